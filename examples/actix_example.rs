@@ -1,4 +1,5 @@
 use actix_web::{web, App, HttpServer, Responder};
+use metrix::exporters::prometheus;
 use metrix::middleware::actix_middleware::MetricsMiddleware;
 use metrix::registry::Registry;
 use std::sync::Arc;
@@ -11,19 +12,12 @@ async fn index() -> impl Responder {
 async fn main() -> std::io::Result<()> {
     let registry = Arc::new(Registry::new());
 
-    // Start Prometheus exporter
-    let exporter_registry = Arc::clone(&registry);
-    tokio::spawn(async move {
-        metrix::exporters::prometheus::PrometheusExporter::new(exporter_registry)
-            .start("127.0.0.1:9100")
-            .await
-            .unwrap();
-    });
-
     HttpServer::new(move || {
         App::new()
             .wrap(MetricsMiddleware::new(registry.clone()))
             .route("/", web::get().to(index))
+            // Expose the metrics on the application server itself
+            .service(prometheus::actix_resource("/metrics", registry.clone()))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -1,71 +1,46 @@
 // examples/axum_example.rs
 
-use axum::{
-    extract::State,
-    http::Request,
-    middleware::{self, Next},
-    response::IntoResponse,
-    routing::get,
-    serve, Router,
+use axum::{routing::get, serve, Router};
+use metrix::{
+    exporters::{json_exporter, prometheus::PrometheusExporter},
+    middleware::tower::MetricsLayer,
+    registry::Registry,
 };
-use metrix::{exporters::prometheus::PrometheusExporter, registry::Registry};
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio::task;
 
 #[tokio::main]
 async fn main() {
     let registry = Arc::new(Registry::new());
 
-    // Start Prometheus exporter in a separate task
-    let exporter_registry = Arc::clone(&registry);
-    task::spawn(async move {
-        PrometheusExporter::new(exporter_registry)
-            .start("127.0.0.1:9100")
-            .await
-            .unwrap();
-    });
+    // Start Prometheus exporter on its own port
+    let exporter = PrometheusExporter::new(Arc::clone(&registry))
+        .start("127.0.0.1:9100")
+        .await
+        .unwrap();
 
     // Build the Axum application
     let app = Router::new()
         .route("/", get(root_handler))
-        .layer(middleware::from_fn_with_state(
-            registry.clone(),
-            metrics_middleware,
-        ))
-        .with_state(registry.clone());
+        // Also expose the JSON metrics on the application server itself
+        .route("/metrics.json", json_exporter::handler(registry.clone()))
+        // Record request metrics, labelled by route template
+        .layer(MetricsLayer::new(registry.clone()));
 
     let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
     println!("Server running at http://127.0.0.1:3000");
 
-    serve(listener, app).await.unwrap();
+    serve(listener, app)
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .unwrap();
+
+    // Let in-flight scrapes finish before exiting
+    exporter.shutdown().await.unwrap();
 }
 
 async fn root_handler() -> &'static str {
     "Hello, World!"
 }
-
-use axum::body::Body;
-
-async fn metrics_middleware(
-    State(registry): State<Arc<Registry>>,
-    req: Request<Body>,
-    next: Next,
-) -> impl IntoResponse {
-    let method = req.method().to_string();
-    let path = req.uri().path().to_string();
-
-    // Proceed to the next handler
-    let response = next.run(req).await;
-
-    // Record metrics using your Registry
-    let mut labels = std::collections::HashMap::new();
-    labels.insert("method".to_string(), method);
-    labels.insert("path".to_string(), path);
-
-    // Increment request counter
-    let counter = registry.register_counter("http_requests_total", labels);
-    counter.increment();
-
-    response
-}
@@ -0,0 +1,523 @@
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::registry::Registry;
+use crate::snapshot::MetricValue;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time::{interval, timeout};
+use tokio_util::sync::CancellationToken;
+
+/// The number of datapoints sent per pickle message.
+const PICKLE_BATCH_SIZE: usize = 500;
+
+/// The Carbon receiver protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphiteProtocol {
+    /// `path value timestamp` lines, usually on port 2003.
+    Plaintext,
+    /// Length-prefixed pickled lists of datapoints, usually on port 2004.
+    Pickle,
+}
+
+/// How labels are mapped onto Graphite metric paths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphiteLabels {
+    /// Appended as `.name.value` path segments, in label name order.
+    Path,
+    /// Appended as Graphite 1.1 `;name=value` tags.
+    Tags,
+}
+
+/// Periodically reports the registry to Graphite over TCP.
+///
+/// Counters, gauges, info and state set series are reported as their
+/// value; state sets with one sub-metric per state. Meters are expanded into
+/// `.count` and `.rate_{mean,1m,5m,15m}`, and histograms and timers into
+/// `.count`, `.mean` and one `.pNN` per configured percentile. Summaries
+/// are expanded the same way, with their own quantiles. Timers are
+/// reported in seconds, like the other exporters.
+///
+/// The connection is re-established when a write fails.
+pub struct GraphiteExporter {
+    registry: Arc<Registry>,
+    addr: String,
+    interval: Duration,
+    protocol: GraphiteProtocol,
+    labels: GraphiteLabels,
+    prefix: Option<String>,
+    percentiles: Vec<f64>,
+    timeout: Duration,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl GraphiteExporter {
+    /// Creates an exporter reporting to the Carbon receiver at `addr`,
+    /// e.g. `graphite:2003`, every interval.
+    pub fn new(registry: Arc<Registry>, addr: String, interval: Duration) -> Self {
+        GraphiteExporter {
+            registry,
+            addr,
+            interval,
+            protocol: GraphiteProtocol::Plaintext,
+            labels: GraphiteLabels::Path,
+            prefix: None,
+            percentiles: vec![50.0, 99.0],
+            timeout: Duration::from_secs(10),
+            stream: Mutex::new(None),
+        }
+    }
+
+    /// Sets the receiver protocol. Defaults to
+    /// [`GraphiteProtocol::Plaintext`].
+    pub fn with_protocol(mut self, protocol: GraphiteProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets how labels are mapped. Defaults to [`GraphiteLabels::Path`].
+    pub fn with_labels(mut self, labels: GraphiteLabels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Prepends `prefix.` to every metric path.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.trim_end_matches('.').to_string());
+        self
+    }
+
+    /// Sets the percentiles histograms and timers are expanded into, e.g.
+    /// `99.9` for a `.p99_9` sub-metric. Defaults to 50 and 99.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a percentile is not between 0 and 100.
+    pub fn with_percentiles(mut self, percentiles: &[f64]) -> Self {
+        assert!(
+            percentiles.iter().all(|p| (0.0..=100.0).contains(p)),
+            "percentiles must be between 0 and 100"
+        );
+        self.percentiles = percentiles.to_vec();
+        self
+    }
+
+    /// Sets the timeout of connecting and of writing a report. Defaults to
+    /// 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Reports the metrics every interval until the returned handle is shut
+    /// down, then reports one last time. Failed periodic reports are logged
+    /// with `tracing`; the result of the final report is returned by the
+    /// handle.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> ExporterHandle {
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = interval(self.interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancelled.cancelled() => break,
+                }
+                if let Err(e) = self.report().await {
+                    tracing::warn!(error = %e, addr = %self.addr, "failed to report metrics to Graphite");
+                }
+            }
+
+            self.report().await.map_err(ExporterError::from)
+        });
+
+        ExporterHandle::new(token, task, None)
+    }
+
+    /// Reports the current values once.
+    pub async fn report(&self) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let datapoints = self.collect();
+        if datapoints.is_empty() {
+            return Ok(());
+        }
+        let payload = match self.protocol {
+            GraphiteProtocol::Plaintext => encode_plaintext(&datapoints, timestamp),
+            GraphiteProtocol::Pickle => encode_pickle(&datapoints, timestamp),
+        };
+
+        let mut stream = self.stream.lock().await;
+        let reused = stream.is_some();
+        match self.write(&mut stream, &payload).await {
+            // A connection that was idle since the previous report may have
+            // been closed by the receiver, so retry once on a new one.
+            Err(_) if reused => self.write(&mut stream, &payload).await,
+            result => result,
+        }
+    }
+
+    async fn write(&self, stream: &mut Option<TcpStream>, payload: &[u8]) -> io::Result<()> {
+        if stream.as_ref().is_some_and(|stream| !is_open(stream)) {
+            *stream = None;
+        }
+        let result = timeout(self.timeout, async {
+            if stream.is_none() {
+                *stream = Some(TcpStream::connect(&self.addr).await?);
+            }
+            let connected = stream.as_mut().unwrap();
+            connected.write_all(payload).await?;
+            connected.flush().await
+        })
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+
+        if result.is_err() {
+            *stream = None;
+        }
+        result
+    }
+
+    /// Gets the `(path, value)` datapoints of a registry snapshot.
+    fn collect(&self) -> Vec<(String, f64)> {
+        let mut datapoints = Vec::new();
+
+        for family in self.registry.snapshot() {
+            for series in &family.series {
+                let labels = &series.labels;
+                let mut push = |suffix: Option<&str>, extra: Option<(&str, &str)>, value: f64| {
+                    if value.is_finite() {
+                        datapoints.push((self.path(&family.name, suffix, labels, extra), value));
+                    }
+                };
+
+                match &series.value {
+                    MetricValue::Counter(total) => push(None, None, *total as f64),
+                    MetricValue::Gauge(value) => push(None, None, *value),
+                    MetricValue::Meter(meter) => {
+                        push(Some("count"), None, meter.count as f64);
+                        for (window, rate) in meter.rates() {
+                            push(Some(&format!("rate_{}", window)), None, rate);
+                        }
+                    }
+                    MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+                        for (suffix, value) in self.summarize(histogram) {
+                            push(Some(&suffix), None, value);
+                        }
+                    }
+                    MetricValue::NativeHistogram(native) => {
+                        for (suffix, value) in self.summarize(&native.to_classic()) {
+                            push(Some(&suffix), None, value);
+                        }
+                    }
+                    MetricValue::Summary(summary) => {
+                        push(Some("count"), None, summary.count as f64);
+                        if summary.count > 0 {
+                            push(Some("mean"), None, summary.sum / summary.count as f64);
+                        }
+                        for (quantile, value) in &summary.quantiles {
+                            push(Some(&percentile_suffix(quantile * 100.0)), None, *value);
+                        }
+                    }
+                    MetricValue::Info => push(None, None, 1.0),
+                    MetricValue::StateSet(states) => {
+                        for (state, enabled) in states {
+                            let value = if *enabled { 1.0 } else { 0.0 };
+                            push(None, Some((&family.name, state)), value);
+                        }
+                    }
+                }
+            }
+        }
+
+        datapoints
+    }
+
+    /// Expands a histogram into its count, mean and percentile sub-metrics.
+    /// Only the count is reported while the histogram is empty.
+    fn summarize(&self, histogram: &HistogramSnapshot) -> Vec<(String, f64)> {
+        let mut values = vec![("count".to_string(), histogram.count as f64)];
+        if histogram.count == 0 {
+            return values;
+        }
+        values.push(("mean".to_string(), histogram.sum / histogram.count as f64));
+        for percentile in &self.percentiles {
+            if let Some(value) = histogram.percentile(*percentile) {
+                values.push((percentile_suffix(*percentile), value));
+            }
+        }
+        values
+    }
+
+    fn path(
+        &self,
+        name: &str,
+        suffix: Option<&str>,
+        labels: &[(String, String)],
+        extra: Option<(&str, &str)>,
+    ) -> String {
+        let mut path = match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, sanitize(name)),
+            None => sanitize(name),
+        };
+        let labels = labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(extra);
+
+        match self.labels {
+            GraphiteLabels::Path => {
+                for (label_name, label_value) in labels {
+                    path.push('.');
+                    path.push_str(&sanitize(label_name));
+                    path.push('.');
+                    path.push_str(&sanitize(label_value));
+                }
+                if let Some(suffix) = suffix {
+                    path.push('.');
+                    path.push_str(suffix);
+                }
+            }
+            GraphiteLabels::Tags => {
+                if let Some(suffix) = suffix {
+                    path.push('.');
+                    path.push_str(suffix);
+                }
+                // Graphite rejects tags with empty values.
+                for (label_name, label_value) in labels.filter(|(_, value)| !value.is_empty()) {
+                    path.push(';');
+                    path.push_str(&label_name.replace([';', '!', '^', '=', ' ', '\n'], "_"));
+                    path.push('=');
+                    path.push_str(sanitize_tag_value(label_value).trim_start_matches('~'));
+                }
+            }
+        }
+        path
+    }
+}
+
+/// Checks whether the receiver has not closed the connection. Writes to a
+/// closed connection only fail after the data is lost, so this is checked
+/// before reporting; Carbon never sends data, so a readable stream is at
+/// its end.
+fn is_open(stream: &TcpStream) -> bool {
+    let mut buf = [0; 1];
+    matches!(stream.try_read(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+}
+
+fn encode_plaintext(datapoints: &[(String, f64)], timestamp: i64) -> Vec<u8> {
+    let mut payload = String::new();
+    for (path, value) in datapoints {
+        payload.push_str(&format!("{} {} {}\n", path, value, timestamp));
+    }
+    payload.into_bytes()
+}
+
+/// Encodes the datapoints as pickle protocol 2 lists of
+/// `(path, (timestamp, value))` tuples, each prefixed by its length as a
+/// 32-bit big-endian integer.
+fn encode_pickle(datapoints: &[(String, f64)], timestamp: i64) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for chunk in datapoints.chunks(PICKLE_BATCH_SIZE) {
+        // PROTO 2, EMPTY_LIST, MARK
+        let mut pickle = vec![0x80, 0x02, b']', b'('];
+        for (path, value) in chunk {
+            // BINUNICODE
+            pickle.push(b'X');
+            pickle.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            pickle.extend_from_slice(path.as_bytes());
+            // LONG1, as the timestamp overflows BININT in 2038
+            pickle.extend_from_slice(&[0x8a, 8]);
+            pickle.extend_from_slice(&timestamp.to_le_bytes());
+            // BINFLOAT, then TUPLE2 twice
+            pickle.push(b'G');
+            pickle.extend_from_slice(&value.to_be_bytes());
+            pickle.extend_from_slice(&[0x86, 0x86]);
+        }
+        // APPENDS, STOP
+        pickle.extend_from_slice(b"e.");
+
+        payload.extend_from_slice(&(pickle.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&pickle);
+    }
+    payload
+}
+
+/// Names the sub-metric of a percentile, e.g. `p99_9` for 99.9.
+fn percentile_suffix(percentile: f64) -> String {
+    // Rounded, so that quantiles such as 0.57 do not turn into 56.99999.
+    let percentile = (percentile * 1e6).round() / 1e6;
+    format!("p{}", percentile).replace('.', "_")
+}
+
+/// Replaces the characters that are not safe in a path segment.
+fn sanitize(segment: &str) -> String {
+    segment
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | ':' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Replaces the characters that delimit tags or lines.
+fn sanitize_tag_value(value: &str) -> String {
+    value.replace([';', ' ', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn exporter(registry: Arc<Registry>) -> GraphiteExporter {
+        GraphiteExporter::new(registry, String::new(), Duration::from_secs(60))
+    }
+
+    #[test]
+    fn maps_labels_onto_paths() {
+        let registry = Arc::new(Registry::new());
+        let requests = registry.counter_family("requests_total", &["path", "zone"]);
+        requests
+            .with_label_values(&["/a b", "eu;1"])
+            .increment_by(2);
+        requests.with_label_values(&["/c", ""]).increment();
+
+        let exporter = exporter(registry.clone()).with_prefix("app.");
+        assert_eq!(
+            exporter.collect(),
+            [
+                ("app.requests_total.path._a_b.zone.eu_1".to_string(), 2.0),
+                ("app.requests_total.path._c.zone.".to_string(), 1.0),
+            ]
+        );
+
+        let exporter = exporter.with_labels(GraphiteLabels::Tags);
+        assert_eq!(
+            exporter.collect(),
+            [
+                ("app.requests_total;path=/a_b;zone=eu_1".to_string(), 2.0),
+                ("app.requests_total;path=/c".to_string(), 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_histograms_and_state_sets() {
+        let registry = Arc::new(Registry::new());
+        let histogram = registry
+            .histogram_family("size", &[])
+            .with_label_values(&[]);
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        registry
+            .state_set_family("mode", &[], &["on", "off"])
+            .with_label_values(&[])
+            .set_exclusive("on");
+
+        let datapoints = exporter(registry)
+            .with_labels(GraphiteLabels::Tags)
+            .with_percentiles(&[99.9])
+            .collect();
+        let paths: Vec<&str> = datapoints.iter().map(|(path, _)| path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "mode;mode=on",
+                "mode;mode=off",
+                "size.count",
+                "size.mean",
+                "size.p99_9"
+            ]
+        );
+        assert_eq!(datapoints[0].1, 1.0);
+        assert_eq!(datapoints[1].1, 0.0);
+        assert_eq!(datapoints[2].1, 2.0);
+        assert_eq!(datapoints[3].1, 2.0);
+    }
+
+    #[test]
+    fn encodes_plaintext_lines() {
+        let datapoints = [("a.b".to_string(), 1.5), ("c".to_string(), -2.0)];
+        assert_eq!(
+            encode_plaintext(&datapoints, 1_700_000_000),
+            b"a.b 1.5 1700000000\nc -2 1700000000\n"
+        );
+    }
+
+    #[test]
+    fn encodes_pickled_datapoints() {
+        let datapoints = [("a.b.c".to_string(), 1.5)];
+        let mut expected = vec![0x80, 0x02, b']', b'(', b'X', 5, 0, 0, 0];
+        expected.extend_from_slice(b"a.b.c");
+        expected.extend_from_slice(&[0x8a, 8]);
+        expected.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        expected.push(b'G');
+        expected.extend_from_slice(&1.5f64.to_be_bytes());
+        expected.extend_from_slice(&[0x86, 0x86, b'e', b'.']);
+
+        let payload = encode_pickle(&datapoints, 1_700_000_000);
+        assert_eq!(payload[..4], (expected.len() as u32).to_be_bytes());
+        assert_eq!(payload[4..], expected);
+    }
+
+    #[test]
+    fn batches_pickled_datapoints() {
+        let datapoints: Vec<(String, f64)> = (0..PICKLE_BATCH_SIZE + 1)
+            .map(|i| ("m".to_string(), i as f64))
+            .collect();
+        let payload = encode_pickle(&datapoints, 0);
+
+        let mut messages = Vec::new();
+        let mut rest = &payload[..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            messages.push(&rest[4..4 + len]);
+            rest = &rest[4 + len..];
+        }
+        // Each datapoint takes 1 + 4 + 1 bytes for the path, 10 for the
+        // timestamp, 9 for the value and 2 for the tuples.
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].len(), 4 + PICKLE_BATCH_SIZE * 27 + 2);
+        assert_eq!(messages[1].len(), 4 + 27 + 2);
+        assert!(messages.iter().all(|message| message.ends_with(b"e.")));
+    }
+
+    #[test]
+    fn names_percentiles() {
+        assert_eq!(percentile_suffix(99.0), "p99");
+        assert_eq!(percentile_suffix(99.9), "p99_9");
+        assert_eq!(percentile_suffix(0.57 * 100.0), "p57");
+    }
+
+    #[tokio::test]
+    async fn reports_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let registry = Arc::new(Registry::new());
+        registry
+            .gauge_family("temperature", &[])
+            .with_label_values(&[])
+            .set(21.5);
+        let exporter = GraphiteExporter::new(registry, addr, Duration::from_secs(60));
+
+        exporter.report().await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0; 256];
+        let len = stream.read(&mut buf).await.unwrap();
+        let line = std::str::from_utf8(&buf[..len]).unwrap();
+        let parts: Vec<&str> = line.trim_end().split(' ').collect();
+        assert_eq!(parts[..2], ["temperature", "21.5"]);
+        assert!(parts[2].parse::<i64>().unwrap() > 1_700_000_000);
+    }
+}
@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// The error type of a finished exporter task.
+pub type ExporterError = Box<dyn Error + Send + Sync>;
+
+/// A handle to a running exporter.
+///
+/// Dropping the handle leaves the exporter running; call
+/// [`ExporterHandle::shutdown`] or cancel its token to stop it.
+pub struct ExporterHandle {
+    token: CancellationToken,
+    task: JoinHandle<Result<(), ExporterError>>,
+    local_addr: Option<SocketAddr>,
+}
+
+impl ExporterHandle {
+    pub(crate) fn new(
+        token: CancellationToken,
+        task: JoinHandle<Result<(), ExporterError>>,
+        local_addr: Option<SocketAddr>,
+    ) -> Self {
+        ExporterHandle {
+            token,
+            task,
+            local_addr,
+        }
+    }
+
+    /// Gets the address an HTTP exporter is listening on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// Gets the token that stops the exporter when cancelled, e.g. to tie it
+    /// to the application's own shutdown.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Stops the exporter and waits for it to finish: HTTP exporters drain
+    /// in-flight scrapes, push exporters push one last time.
+    pub async fn shutdown(self) -> Result<(), ExporterError> {
+        self.token.cancel();
+        self.wait().await
+    }
+
+    /// Waits for the exporter to finish without stopping it.
+    pub async fn wait(self) -> Result<(), ExporterError> {
+        self.task.await?
+    }
+}
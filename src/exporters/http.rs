@@ -0,0 +1,104 @@
+use reqwest::{RequestBuilder, StatusCode};
+use std::fmt;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// An error sending metrics to an HTTP endpoint.
+#[derive(Debug)]
+pub enum HttpExportError {
+    /// The endpoint URL could not be built.
+    Url(String),
+    /// The request failed, e.g. on connect or timeout.
+    Http(reqwest::Error),
+    /// The endpoint answered with a non-success status.
+    Status { status: StatusCode, body: String },
+}
+
+impl fmt::Display for HttpExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpExportError::Url(url) => write!(f, "invalid URL: {}", url),
+            HttpExportError::Http(e) => write!(f, "request failed: {}", e),
+            HttpExportError::Status { status, body } => {
+                write!(f, "endpoint returned {}: {}", status, body.trim())
+            }
+        }
+    }
+}
+
+impl std::error::Error for HttpExportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HttpExportError::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for HttpExportError {
+    fn from(e: reqwest::Error) -> Self {
+        HttpExportError::Http(e)
+    }
+}
+
+impl HttpExportError {
+    /// Whether retrying the request may succeed.
+    fn is_retryable(&self) -> bool {
+        match self {
+            HttpExportError::Url(_) => false,
+            HttpExportError::Http(_) => true,
+            HttpExportError::Status { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+        }
+    }
+}
+
+/// How often a failed request is retried, and the delay before the first
+/// retry, which doubles on each further retry.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Retry {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// Sends the request built by `build` until it succeeds, fails with an
+/// error that is not worth retrying, or the retries are used up. Only
+/// connection errors, timeouts, `429` and `5xx` responses are retried.
+pub(crate) async fn send_with_retries<F>(retry: Retry, build: F) -> Result<(), HttpExportError>
+where
+    F: Fn() -> RequestBuilder,
+{
+    let mut backoff = retry.initial_backoff;
+    let mut attempt = 0;
+    loop {
+        match send(build()).await {
+            Err(e) if e.is_retryable() && attempt < retry.max_retries => {
+                attempt += 1;
+                tracing::debug!(error = %e, attempt, "retrying metrics request");
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn send(request: RequestBuilder) -> Result<(), HttpExportError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(HttpExportError::Status { status, body })
+}
@@ -0,0 +1,434 @@
+//! An exporter writing metrics in the InfluxDB line protocol.
+
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::exporters::http::{send_with_retries, HttpExportError, Retry};
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::registry::Registry;
+use crate::snapshot::{FamilySnapshot, MetricValue, SeriesSnapshot};
+use bytes::Bytes;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Client, Url};
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// The percentile fields written for histograms and timers.
+const PERCENTILES: [(&str, f64); 3] = [("p50", 50.0), ("p90", 90.0), ("p99", 99.0)];
+
+/// Where the lines are written.
+#[derive(Clone, Debug)]
+pub enum InfluxTarget {
+    /// The standard output, e.g. for a log shipper or Telegraf's `execd`.
+    Stdout,
+    /// A file the lines are appended to, created if missing.
+    File(PathBuf),
+    /// The InfluxDB 2 `/api/v2/write` endpoint of the server at `url`.
+    Http {
+        url: String,
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+/// An error writing lines.
+#[derive(Debug)]
+pub enum InfluxError {
+    /// Writing to the standard output or the file failed.
+    Io(io::Error),
+    /// Writing to InfluxDB failed.
+    Http(HttpExportError),
+}
+
+impl fmt::Display for InfluxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfluxError::Io(e) => write!(f, "write failed: {}", e),
+            InfluxError::Http(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for InfluxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InfluxError::Io(e) => Some(e),
+            InfluxError::Http(e) => e.source(),
+        }
+    }
+}
+
+impl From<io::Error> for InfluxError {
+    fn from(e: io::Error) -> Self {
+        InfluxError::Io(e)
+    }
+}
+
+impl From<HttpExportError> for InfluxError {
+    fn from(e: HttpExportError) -> Self {
+        InfluxError::Http(e)
+    }
+}
+
+/// Periodically writes the registry in the InfluxDB line protocol.
+///
+/// Each metric is a measurement and its labels are tags. Counters, gauges
+/// and info series have a `value` field. Meters have `count` and
+/// `rate_{mean,1m,5m,15m}` fields, histograms and timers have `count`,
+/// `sum`, `mean`, `min`, `max`, `p50`, `p90` and `p99` fields, with timers
+/// in seconds, summaries have `count`, `sum` and a field per quantile, e.g.
+/// `0.99`, and state sets have a boolean field per state. All lines of
+/// a write share its timestamp, in nanoseconds.
+pub struct InfluxExporter {
+    registry: Arc<Registry>,
+    target: InfluxTarget,
+    interval: Duration,
+    client: Client,
+    timeout: Duration,
+    retry: Retry,
+}
+
+impl InfluxExporter {
+    /// Creates an exporter writing to `target` every interval.
+    pub fn new(registry: Arc<Registry>, target: InfluxTarget, interval: Duration) -> Self {
+        InfluxExporter {
+            registry,
+            target,
+            interval,
+            client: Client::new(),
+            timeout: Duration::from_secs(10),
+            retry: Retry::default(),
+        }
+    }
+
+    /// Sets the timeout of each HTTP request. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how often a failed HTTP request is retried and the delay before
+    /// the first retry, which doubles on each further retry. Only connection
+    /// errors, timeouts, `429` and `5xx` responses are retried. Defaults to 3
+    /// retries starting at 500 milliseconds.
+    pub fn with_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.retry = Retry {
+            max_retries,
+            initial_backoff,
+        };
+        self
+    }
+
+    /// Writes the metrics every interval until the returned handle is shut
+    /// down, then writes one last time so the final values are not lost.
+    /// Failed periodic writes are logged with `tracing`; the result of the
+    /// final write is returned by the handle.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> ExporterHandle {
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = interval(self.interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancelled.cancelled() => break,
+                }
+                if let Err(e) = self.write().await {
+                    tracing::warn!(error = %e, "failed to write metrics to InfluxDB");
+                }
+            }
+
+            self.write().await.map_err(ExporterError::from)
+        });
+
+        ExporterHandle::new(token, task, None)
+    }
+
+    /// Writes the current metrics once.
+    pub async fn write(&self) -> Result<(), InfluxError> {
+        let lines = render(&self.registry, unix_nanos(SystemTime::now()));
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        match &self.target {
+            InfluxTarget::Stdout => {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(lines.as_bytes()).await?;
+                stdout.flush().await?;
+            }
+            InfluxTarget::File(path) => {
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(lines.as_bytes()).await?;
+                file.flush().await?;
+            }
+            InfluxTarget::Http {
+                url,
+                org,
+                bucket,
+                token,
+            } => {
+                let url = write_url(url, org, bucket)?;
+                let body = Bytes::from(lines);
+                let authorization = format!("Token {}", token);
+                send_with_retries(self.retry, || {
+                    self.client
+                        .post(url.clone())
+                        .timeout(self.timeout)
+                        .header(AUTHORIZATION, &authorization)
+                        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                        .body(body.clone())
+                })
+                .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_url(url: &str, org: &str, bucket: &str) -> Result<Url, HttpExportError> {
+    let base = url.trim_end_matches('/');
+    let mut url = Url::parse(&format!("{}/api/v2/write", base))
+        .map_err(|_| HttpExportError::Url(url.to_string()))?;
+    url.query_pairs_mut()
+        .append_pair("org", org)
+        .append_pair("bucket", bucket)
+        .append_pair("precision", "ns");
+    Ok(url)
+}
+
+/// Renders every series of the registry as a line with the given
+/// timestamp. Fields with non-finite values are left out, as InfluxDB
+/// rejects them, and so are lines left without fields.
+fn render(registry: &Registry, timestamp: u128) -> String {
+    let mut lines = String::new();
+    for family in registry.snapshot() {
+        for series in &family.series {
+            let fields = fields(&series.value);
+            if fields.is_empty() {
+                continue;
+            }
+            lines.push_str(&line(&family, series, &fields, timestamp));
+        }
+    }
+    lines
+}
+
+fn line(
+    family: &FamilySnapshot,
+    series: &SeriesSnapshot,
+    fields: &[(String, String)],
+    timestamp: u128,
+) -> String {
+    let mut line = escape(&family.name, &[',', ' ']);
+
+    // Tags sorted by key are the fastest for InfluxDB to parse, and empty
+    // tag values are not allowed.
+    let mut labels: Vec<_> = series
+        .labels
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+    labels.sort();
+    for (name, value) in labels {
+        line.push(',');
+        line.push_str(&escape(name, &[',', '=', ' ']));
+        line.push('=');
+        line.push_str(&escape(value, &[',', '=', ' ']));
+    }
+
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", escape(key, &[',', '=', ' ']), value))
+        .collect();
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    line.push(' ');
+    line.push_str(&timestamp.to_string());
+    line.push('\n');
+    line
+}
+
+/// Gets the fields of a series value, formatted as line protocol values.
+fn fields(value: &MetricValue) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    match value {
+        MetricValue::Counter(total) => fields.push(("value".to_string(), integer(*total))),
+        MetricValue::Gauge(value) => {
+            if value.is_finite() {
+                fields.push(("value".to_string(), value.to_string()));
+            }
+        }
+        MetricValue::Meter(meter) => {
+            fields.push(("count".to_string(), integer(meter.count)));
+            for (window, rate) in meter.rates() {
+                if rate.is_finite() {
+                    fields.push((format!("rate_{}", window), rate.to_string()));
+                }
+            }
+        }
+        MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+            return histogram_fields(histogram);
+        }
+        MetricValue::NativeHistogram(native) => return histogram_fields(&native.to_classic()),
+        MetricValue::Summary(summary) => {
+            fields.push(("count".to_string(), integer(summary.count)));
+            if summary.sum.is_finite() {
+                fields.push(("sum".to_string(), summary.sum.to_string()));
+            }
+            for (quantile, value) in &summary.quantiles {
+                if value.is_finite() {
+                    fields.push((quantile.to_string(), value.to_string()));
+                }
+            }
+        }
+        MetricValue::Info => fields.push(("value".to_string(), integer(1))),
+        MetricValue::StateSet(states) => {
+            return states
+                .iter()
+                .map(|(state, enabled)| (state.clone(), enabled.to_string()))
+                .collect();
+        }
+    }
+    fields
+}
+
+fn histogram_fields(histogram: &HistogramSnapshot) -> Vec<(String, String)> {
+    let mut fields = vec![("count".to_string(), integer(histogram.count))];
+    if histogram.count == 0 {
+        return fields;
+    }
+    let mut values = vec![
+        ("sum", histogram.sum),
+        ("mean", histogram.sum / histogram.count as f64),
+        ("min", histogram.min),
+        ("max", histogram.max),
+    ];
+    for (key, percentile) in PERCENTILES {
+        if let Some(value) = histogram.percentile(percentile) {
+            values.push((key, value));
+        }
+    }
+    fields.extend(
+        values
+            .into_iter()
+            .filter(|(_, value)| value.is_finite())
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+    );
+    fields
+}
+
+/// Formats a signed 64-bit integer field value, saturating larger counts.
+fn integer(value: u64) -> String {
+    format!("{}i", value.min(i64::MAX as u64))
+}
+
+/// Escapes the given characters with a backslash. Newlines end the line and
+/// cannot be escaped, so they are replaced by spaces, escaped as well if
+/// spaces are special.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        let c = if c == '\n' { ' ' } else { c };
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::histogram::Histogram;
+    use std::collections::HashMap;
+
+    #[test]
+    fn escapes_tag_values() {
+        let special = [',', '=', ' '];
+        assert_eq!(escape("a,b=c d", &special), "a\\,b\\=c\\ d");
+        assert_eq!(escape("line\nbreak", &special), "line\\ break");
+        assert_eq!(escape("a=b\nc", &[',', ' ']), "a=b\\ c");
+    }
+
+    #[test]
+    fn renders_lines() {
+        let registry = Registry::new();
+        registry
+            .counter_family("http requests,total", &["zone", "path", "empty"])
+            .with_label_values(&["eu\nwest", "/a,b=c d", ""])
+            .increment_by(3);
+        registry
+            .gauge_family("temperature", &[])
+            .with_label_values(&[])
+            .set(f64::NAN);
+        registry
+            .state_set_family("mode", &[], &["on", "off"])
+            .with_label_values(&[])
+            .set_exclusive("off");
+
+        assert_eq!(
+            render(&registry, 1_700_000_000_000_000_000),
+            "http\\ requests\\,total,path=/a\\,b\\=c\\ d,zone=eu\\ west value=3i 1700000000000000000\n\
+             mode on=false,off=true 1700000000000000000\n"
+        );
+    }
+
+    #[test]
+    fn writes_histogram_fields() {
+        let histogram = Histogram::new("size", HashMap::new());
+        assert_eq!(
+            histogram_fields(&histogram.snapshot()),
+            [("count".to_string(), "0i".to_string())]
+        );
+
+        histogram.observe(1.0);
+        histogram.observe(3.0);
+        let fields = histogram_fields(&histogram.snapshot());
+        let keys: Vec<&str> = fields.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            ["count", "sum", "mean", "min", "max", "p50", "p90", "p99"]
+        );
+        assert_eq!(
+            fields[..5],
+            [
+                ("count".to_string(), "2i".to_string()),
+                ("sum".to_string(), "4".to_string()),
+                ("mean".to_string(), "2".to_string()),
+                ("min".to_string(), "1".to_string()),
+                ("max".to_string(), "3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn builds_the_write_url() {
+        let url = write_url("http://localhost:8086/", "my org", "metrics").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "http://localhost:8086/api/v2/write?org=my+org&bucket=metrics&precision=ns"
+        );
+        assert!(write_url("not a url", "org", "bucket").is_err());
+    }
+}
@@ -1,26 +1,76 @@
-use crate::metrics::Metric;
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::metrics::histogram::HistogramSnapshot;
 use crate::registry::Registry;
+use crate::snapshot::{FamilySnapshot, MetricValue, SeriesSnapshot};
+use actix_web::{web, HttpResponse};
 use axum::extract::State;
 use axum::response::IntoResponse;
+use axum::routing::{get, MethodRouter};
 use axum::serve;
-use axum::{routing::get, Router};
-use serde_json::json;
+use axum::Router;
+use serde_json::{json, Map, Value};
 use std::sync::Arc;
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+/// The percentiles reported for histograms and timers.
+const PERCENTILES: [(&str, f64); 5] = [
+    ("p50", 50.0),
+    ("p75", 75.0),
+    ("p90", 90.0),
+    ("p95", 95.0),
+    ("p99", 99.0),
+];
+
+/// Serves `/metrics.json` on its own listener until the returned handle is
+/// shut down. To expose metrics on an existing server instead, mount
+/// [`router`], [`handler`] or [`actix_resource`].
 pub async fn start_json_exporter(
     registry: Arc<Registry>,
     addr: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let app = Router::new()
-        .route("/metrics.json", get(metrics_handler))
-        .with_state(registry);
+) -> Result<ExporterHandle, ExporterError> {
+    let app: Router = router(registry, "/metrics.json");
 
     let listener = TcpListener::bind(addr).await?;
-    println!("JSON exporter listening on http://{}", addr);
+    let local_addr = listener.local_addr()?;
+    println!("JSON exporter listening on http://{}", local_addr);
 
-    serve(listener, app).await?;
+    let token = CancellationToken::new();
+    let shutdown = token.clone().cancelled_owned();
+    let task = tokio::spawn(async move {
+        serve(listener, app)
+            .with_graceful_shutdown(shutdown)
+            .await?;
+        Ok(())
+    });
+
+    Ok(ExporterHandle::new(token, task, Some(local_addr)))
+}
 
-    Ok(())
+/// Creates an axum router serving the JSON metrics at `path`, to be merged
+/// into or nested in an application router.
+pub fn router<S>(registry: Arc<Registry>, path: &str) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route(path, handler(registry))
+}
+
+/// Creates an axum `GET` handler serving the JSON metrics, to be mounted
+/// with `Router::route` at any path.
+pub fn handler<S>(registry: Arc<Registry>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    get(metrics_handler).with_state(registry)
+}
+
+/// Creates an actix-web resource serving the JSON metrics at `path`, to be
+/// registered with `App::service`.
+pub fn actix_resource(path: &str, registry: Arc<Registry>) -> actix_web::Resource {
+    web::resource(path)
+        .app_data(web::Data::new(registry))
+        .route(web::get().to(actix_metrics_handler))
 }
 
 async fn metrics_handler(State(registry): State<Arc<Registry>>) -> impl IntoResponse {
@@ -32,26 +82,211 @@ async fn metrics_handler(State(registry): State<Arc<Registry>>) -> impl IntoResp
     )
 }
 
-fn collect_metrics_json(registry: &Arc<Registry>) -> String {
-    let mut metrics = serde_json::Map::new();
+async fn actix_metrics_handler(registry: web::Data<Arc<Registry>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(collect_metrics_json(&registry))
+}
+
+/// Renders every metric family of the registry as JSON:
+///
+/// ```json
+/// {"metrics": [{"name": "...", "type": "counter", "help": "...", "unit": null,
+///               "series": [{"labels": {...}, "value": 1}]}]}
+/// ```
+///
+/// Series of histograms and timers carry `count`, `sum`, `min`, `max`,
+/// `mean`, cumulative `buckets` and estimated `percentiles` instead of a
+/// `value`; timers report seconds. Native histograms carry the same, with
+/// the bounds of their populated buckets, and their `schema`. Summaries
+/// carry `count`, `sum` and `quantiles` keyed by quantile, null while their
+/// window is empty. Meters carry `count` and `rates`, state sets carry
+/// `states`.
+fn collect_metrics_json(registry: &Registry) -> String {
+    let families: Vec<Value> = registry.snapshot().iter().map(family_json).collect();
+    json!({ "metrics": families }).to_string()
+}
+
+fn family_json(family: &FamilySnapshot) -> Value {
+    json!({
+        "name": family.name,
+        "type": family.kind.as_str(),
+        "help": family.help,
+        "unit": family.unit.as_ref().map(|unit| unit.as_str()),
+        "series": family.series.iter().map(series_json).collect::<Vec<_>>(),
+    })
+}
 
-    // Collect counters
-    let counters = registry.counters.read().unwrap();
-    for counter in counters.values() {
-        let name = counter.name();
-        let value = counter.get();
-        metrics.insert(name.to_string(), json!(value));
+fn series_json(series: &SeriesSnapshot) -> Value {
+    let labels: Map<String, Value> = series
+        .labels
+        .iter()
+        .map(|(k, v)| (k.clone(), json!(v)))
+        .collect();
+    let mut object = Map::new();
+    object.insert("labels".to_string(), Value::Object(labels));
+
+    match &series.value {
+        MetricValue::Counter(value) => {
+            object.insert("value".to_string(), json!(value));
+        }
+        MetricValue::Gauge(value) => {
+            object.insert("value".to_string(), json!(value));
+        }
+        MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+            object.extend(histogram_json(histogram));
+        }
+        MetricValue::NativeHistogram(native) => {
+            object.extend(histogram_json(&native.to_classic()));
+            object.insert("schema".to_string(), json!(native.schema));
+        }
+        MetricValue::Summary(summary) => {
+            let quantiles: Map<String, Value> = summary
+                .quantiles
+                .iter()
+                .map(|(quantile, value)| (quantile.to_string(), json!(value)))
+                .collect();
+            object.insert("count".to_string(), json!(summary.count));
+            object.insert("sum".to_string(), json!(summary.sum));
+            object.insert("quantiles".to_string(), Value::Object(quantiles));
+        }
+        MetricValue::Meter(meter) => {
+            let rates: Map<String, Value> = meter
+                .rates()
+                .iter()
+                .map(|(window, rate)| (window.to_string(), json!(rate)))
+                .collect();
+            object.insert("count".to_string(), json!(meter.count));
+            object.insert("rates".to_string(), Value::Object(rates));
+        }
+        MetricValue::Info => {
+            object.insert("value".to_string(), json!(1));
+        }
+        MetricValue::StateSet(states) => {
+            let states: Map<String, Value> = states
+                .iter()
+                .map(|(state, enabled)| (state.clone(), json!(enabled)))
+                .collect();
+            object.insert("states".to_string(), Value::Object(states));
+        }
     }
+    Value::Object(object)
+}
+
+fn histogram_json(histogram: &HistogramSnapshot) -> Map<String, Value> {
+    let mean = if histogram.count > 0 {
+        Some(histogram.sum / histogram.count as f64)
+    } else {
+        None
+    };
+    let buckets: Vec<Value> = histogram
+        .buckets
+        .iter()
+        .map(|(bound, count)| {
+            let le = if bound.is_finite() {
+                json!(bound)
+            } else {
+                json!("+Inf")
+            };
+            json!({ "le": le, "count": count })
+        })
+        .collect();
+    let percentiles: Map<String, Value> = PERCENTILES
+        .iter()
+        .map(|(name, p)| (name.to_string(), json!(histogram.percentile(*p))))
+        .collect();
+
+    let mut object = Map::new();
+    object.insert("count".to_string(), json!(histogram.count));
+    object.insert("sum".to_string(), json!(histogram.sum));
+    object.insert("min".to_string(), json!(histogram.min));
+    object.insert("max".to_string(), json!(histogram.max));
+    object.insert("mean".to_string(), json!(mean));
+    object.insert("buckets".to_string(), json!(buckets));
+    object.insert("percentiles".to_string(), Value::Object(percentiles));
+    object
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Unit;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
 
-    // Collect gauges
-    let gauges = registry.gauges.read().unwrap();
-    for gauge in gauges.values() {
-        let name = gauge.name();
-        let value = gauge.get();
-        metrics.insert(name.to_string(), json!(value));
+    #[tokio::test]
+    async fn stops_serving_on_shutdown() {
+        let registry = Arc::new(Registry::new());
+        registry.register_counter("requests_total", HashMap::new());
+        let handle = start_json_exporter(registry, "127.0.0.1:0").await.unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let body = reqwest::get(format!("http://{}/metrics.json", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["metrics"][0]["name"], "requests_total");
+
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("the exporter stops")
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
     }
 
-    // TODO other:
+    #[test]
+    fn renders_families_with_labels() {
+        let registry = Registry::new();
+        registry
+            .counter_family("requests_total", &["path"])
+            .with_label_values(&["/"])
+            .increment_by(4);
+        let duration = registry.register_histogram_with_buckets(
+            "duration_seconds",
+            HashMap::new(),
+            vec![0.1, 1.0],
+        );
+        registry.describe("duration_seconds", "Duration.", Some(Unit::Seconds));
+        duration.observe(0.5);
+        registry.register_summary("rpc_seconds", HashMap::new());
+
+        let json: Value = serde_json::from_str(&collect_metrics_json(&registry)).unwrap();
+        let metrics = json["metrics"].as_array().unwrap();
+        assert_eq!(metrics.len(), 3);
 
-    serde_json::to_string(&metrics).unwrap()
+        let histogram = &metrics[0];
+        assert_eq!(histogram["type"], "histogram");
+        assert_eq!(histogram["help"], "Duration.");
+        assert_eq!(histogram["unit"], "seconds");
+        let series = &histogram["series"][0];
+        assert_eq!(series["count"], 1);
+        assert_eq!(series["sum"], 0.5);
+        assert_eq!(
+            series["buckets"],
+            json!([
+                { "le": 0.1, "count": 0 },
+                { "le": 1.0, "count": 1 },
+                { "le": "+Inf", "count": 1 },
+            ])
+        );
+
+        assert_eq!(
+            metrics[1],
+            json!({
+                "name": "requests_total",
+                "type": "counter",
+                "help": "",
+                "unit": null,
+                "series": [{ "labels": { "path": "/" }, "value": 4 }],
+            })
+        );
+
+        let summary = &metrics[2]["series"][0];
+        assert_eq!(summary["count"], 0);
+        assert_eq!(summary["quantiles"]["0.99"], Value::Null);
+    }
 }
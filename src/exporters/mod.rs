@@ -1,3 +1,14 @@
+pub mod graphite;
+pub mod handle;
+pub mod http;
+pub mod influx;
 pub mod json_exporter;
+pub mod openmetrics;
+pub mod otlp;
 pub mod prometheus;
 pub mod pushgateway;
+pub mod remote_write;
+pub mod statsd;
+
+#[cfg(test)]
+pub(crate) mod test_server;
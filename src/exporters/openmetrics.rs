@@ -0,0 +1,444 @@
+use crate::exporters::prometheus::{
+    format_labels, format_value, sanitize_label_name, sanitize_metric_name, warn_name_collision,
+};
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::metrics::summary::SummarySnapshot;
+use crate::metrics::{Exemplar, Unit};
+use crate::registry::Registry;
+use crate::snapshot::{FamilySnapshot, MetricKind, MetricValue, SeriesSnapshot};
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The content type of the OpenMetrics text format.
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Renders the registry in the OpenMetrics 1.0 text format.
+pub(crate) fn collect_metrics(registry: &Registry) -> String {
+    let mut output = String::new();
+    let mut written = HashSet::new();
+
+    for family in registry.snapshot() {
+        let name = sanitize_metric_name(&family.name);
+        match family.kind {
+            MetricKind::Counter => {
+                let name = name.strip_suffix("_total").unwrap_or(&name);
+                let header = Header::new(name, "counter", &family);
+                write_family(
+                    &mut output,
+                    &mut written,
+                    header,
+                    &family.series,
+                    |out, series| {
+                        if let MetricValue::Counter(value) = series.value {
+                            write_counter(
+                                out,
+                                name,
+                                series,
+                                value as f64,
+                                series.exemplar.as_ref(),
+                            );
+                        }
+                    },
+                );
+            }
+            MetricKind::Gauge => {
+                let header = Header::new(&name, "gauge", &family);
+                write_family(
+                    &mut output,
+                    &mut written,
+                    header,
+                    &family.series,
+                    |out, series| {
+                        if let MetricValue::Gauge(value) = series.value {
+                            write_sample(out, &name, &series.labels, None, value, None);
+                        }
+                    },
+                );
+            }
+            MetricKind::Histogram | MetricKind::NativeHistogram | MetricKind::Timer => {
+                let header = Header::new(&name, "histogram", &family);
+                write_family(
+                    &mut output,
+                    &mut written,
+                    header,
+                    &family.series,
+                    |out, series| match &series.value {
+                        MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+                            write_histogram(out, &name, series, histogram);
+                        }
+                        MetricValue::NativeHistogram(native) => {
+                            write_histogram(out, &name, series, &native.to_classic());
+                        }
+                        _ => {}
+                    },
+                );
+            }
+            MetricKind::Summary => {
+                let header = Header::new(&name, "summary", &family);
+                write_family(
+                    &mut output,
+                    &mut written,
+                    header,
+                    &family.series,
+                    |out, series| {
+                        if let MetricValue::Summary(summary) = &series.value {
+                            write_summary(out, &name, series, summary);
+                        }
+                    },
+                );
+            }
+            MetricKind::Meter => write_meter(&mut output, &mut written, &name, &family),
+            MetricKind::Info => {
+                let name = name.strip_suffix("_info").unwrap_or(&name);
+                let info_name = format!("{}_info", name);
+                let header = Header::new(name, "info", &family);
+                write_family(
+                    &mut output,
+                    &mut written,
+                    header,
+                    &family.series,
+                    |out, series| {
+                        write_sample(out, &info_name, &series.labels, None, 1.0, None);
+                    },
+                );
+            }
+            MetricKind::StateSet => {
+                let header = Header::new(&name, "stateset", &family);
+                write_family(
+                    &mut output,
+                    &mut written,
+                    header,
+                    &family.series,
+                    |out, series| {
+                        if let MetricValue::StateSet(states) = &series.value {
+                            for (state, enabled) in states {
+                                let label = (name.as_str(), state.clone());
+                                let value = if *enabled { 1.0 } else { 0.0 };
+                                write_sample(out, &name, &series.labels, Some(label), value, None);
+                            }
+                        }
+                    },
+                );
+            }
+        }
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+/// The metadata lines of a family.
+struct Header<'a> {
+    name: &'a str,
+    kind: &'a str,
+    help: &'a str,
+    unit: Option<&'a Unit>,
+}
+
+impl<'a> Header<'a> {
+    fn new(name: &'a str, kind: &'a str, family: &'a FamilySnapshot) -> Self {
+        Header {
+            name,
+            kind,
+            help: &family.help,
+            unit: family.unit.as_ref(),
+        }
+    }
+}
+
+/// Writes the metadata lines and the samples of a family, unless a family
+/// with the same name was already written.
+fn write_family<F>(
+    output: &mut String,
+    written: &mut HashSet<String>,
+    header: Header<'_>,
+    series: &[SeriesSnapshot],
+    write_series: F,
+) where
+    F: Fn(&mut String, &SeriesSnapshot),
+{
+    if !written.insert(header.name.to_string()) {
+        warn_name_collision(header.name);
+        return;
+    }
+    output.push_str(&format!("# TYPE {} {}\n", header.name, header.kind));
+    // A unit may only be declared when the family name ends with it.
+    if let Some(unit) = header.unit {
+        if header.name.ends_with(&format!("_{}", unit)) {
+            output.push_str(&format!("# UNIT {} {}\n", header.name, unit));
+        }
+    }
+    if !header.help.is_empty() {
+        output.push_str(&format!(
+            "# HELP {} {}\n",
+            header.name,
+            escape_help(header.help)
+        ));
+    }
+    for series in series {
+        write_series(output, series);
+    }
+}
+
+fn write_counter(
+    output: &mut String,
+    name: &str,
+    series: &SeriesSnapshot,
+    value: f64,
+    exemplar: Option<&Exemplar>,
+) {
+    let total_name = format!("{}_total", name);
+    write_sample(output, &total_name, &series.labels, None, value, exemplar);
+    write_created(output, name, &series.labels, series.created);
+}
+
+fn write_histogram(
+    output: &mut String,
+    name: &str,
+    series: &SeriesSnapshot,
+    histogram: &HistogramSnapshot,
+) {
+    let labels: Vec<(String, String)> = series
+        .labels
+        .iter()
+        .filter(|(name, _)| sanitize_label_name(name) != "le")
+        .cloned()
+        .collect();
+
+    let bucket_name = format!("{}_bucket", name);
+    for (index, (bound, count)) in histogram.buckets.iter().enumerate() {
+        let le = ("le", format_value(*bound));
+        let exemplar = histogram.exemplars.get(index).and_then(Option::as_ref);
+        write_sample(
+            output,
+            &bucket_name,
+            &labels,
+            Some(le),
+            *count as f64,
+            exemplar,
+        );
+    }
+    let count = histogram.count as f64;
+    write_sample(
+        output,
+        &format!("{}_count", name),
+        &labels,
+        None,
+        count,
+        None,
+    );
+    write_sample(
+        output,
+        &format!("{}_sum", name),
+        &labels,
+        None,
+        histogram.sum,
+        None,
+    );
+    write_created(output, name, &labels, series.created);
+}
+
+fn write_summary(
+    output: &mut String,
+    name: &str,
+    series: &SeriesSnapshot,
+    summary: &SummarySnapshot,
+) {
+    let labels: Vec<(String, String)> = series
+        .labels
+        .iter()
+        .filter(|(name, _)| sanitize_label_name(name) != "quantile")
+        .cloned()
+        .collect();
+
+    for (quantile, value) in &summary.quantiles {
+        let quantile = ("quantile", format_value(*quantile));
+        write_sample(output, name, &labels, Some(quantile), *value, None);
+    }
+    let count = summary.count as f64;
+    write_sample(
+        output,
+        &format!("{}_count", name),
+        &labels,
+        None,
+        count,
+        None,
+    );
+    write_sample(
+        output,
+        &format!("{}_sum", name),
+        &labels,
+        None,
+        summary.sum,
+        None,
+    );
+    write_created(output, name, &labels, series.created);
+}
+
+/// Writes a meter as a counter and one gauge per rate.
+fn write_meter(
+    output: &mut String,
+    written: &mut HashSet<String>,
+    name: &str,
+    family: &FamilySnapshot,
+) {
+    let base = name.strip_suffix("_total").unwrap_or(name);
+    let header = Header::new(base, "counter", family);
+    write_family(output, written, header, &family.series, |out, series| {
+        if let MetricValue::Meter(meter) = &series.value {
+            write_counter(out, base, series, meter.count as f64, None);
+        }
+    });
+
+    for (index, window) in ["mean", "1m", "5m", "15m"].into_iter().enumerate() {
+        let rate_name = format!("{}_rate_{}", base, window);
+        let rate_help = if family.help.is_empty() {
+            String::new()
+        } else {
+            format!("{} ({} rate per second)", family.help, window)
+        };
+        let header = Header {
+            name: &rate_name,
+            kind: "gauge",
+            help: &rate_help,
+            unit: None,
+        };
+        write_family(output, written, header, &family.series, |out, series| {
+            if let MetricValue::Meter(meter) = &series.value {
+                let rate = meter.rates()[index].1;
+                write_sample(out, &rate_name, &series.labels, None, rate, None);
+            }
+        });
+    }
+}
+
+fn write_created(
+    output: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    created: Option<SystemTime>,
+) {
+    if let Some(created) = created {
+        let created_name = format!("{}_created", name);
+        let timestamp = unix_seconds(created);
+        write_sample(output, &created_name, labels, None, timestamp, None);
+    }
+}
+
+fn write_sample(
+    output: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+    exemplar: Option<&Exemplar>,
+) {
+    output.push_str(name);
+    output.push_str(&format_labels(labels, extra_label));
+    output.push(' ');
+    output.push_str(&format_value(value));
+    if let Some(exemplar) = exemplar {
+        let labels = format_labels(&exemplar.labels, None);
+        output.push_str(&format!(
+            " # {} {} {}",
+            if labels.is_empty() { "{}" } else { &labels },
+            format_value(exemplar.value),
+            unix_seconds(exemplar.timestamp)
+        ));
+    }
+    output.push('\n');
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn escapes_help_quotes() {
+        assert_eq!(escape_help("a\\b\n\"c\""), "a\\\\b\\n\\\"c\\\"");
+    }
+
+    #[test]
+    fn renders_counters_info_and_state_sets() {
+        let registry = Registry::new();
+        registry.describe("jobs_total", "Jobs \"done\".", None);
+        registry
+            .counter_family("jobs_total", &["queue"])
+            .with_label_values(&["a\nb"])
+            .increment_by(2);
+        registry.register_info(
+            "build",
+            HashMap::from([("version".to_string(), "1.0".to_string())]),
+        );
+        registry
+            .state_set_family("mode", &[], &["on", "off"])
+            .with_label_values(&[])
+            .set_exclusive("on");
+
+        let output = collect_metrics(&registry);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[..2],
+            ["# TYPE build info", "build_info{version=\"1.0\"} 1"]
+        );
+        assert_eq!(
+            lines[2..5],
+            [
+                "# TYPE jobs counter",
+                "# HELP jobs Jobs \\\"done\\\".",
+                "jobs_total{queue=\"a\\nb\"} 2",
+            ]
+        );
+        assert!(lines[5].starts_with("jobs_created{queue=\"a\\nb\"} "));
+        assert_eq!(
+            lines[6..],
+            [
+                "# TYPE mode stateset",
+                "mode{mode=\"on\"} 1",
+                "mode{mode=\"off\"} 0",
+                "# EOF",
+            ]
+        );
+    }
+
+    #[test]
+    fn declares_units_matching_the_name() {
+        let registry = Registry::new();
+        registry.describe("queue_bytes", "", Some(Unit::Bytes));
+        registry.register_gauge("queue_bytes", HashMap::new());
+        registry.describe("queue_size", "", Some(Unit::Bytes));
+        registry.register_gauge("queue_size", HashMap::new());
+
+        let output = collect_metrics(&registry);
+        assert!(output.contains("# TYPE queue_bytes gauge\n# UNIT queue_bytes bytes\n"));
+        assert!(!output.contains("# UNIT queue_size"));
+    }
+
+    #[test]
+    fn drops_reserved_labels_from_summaries() {
+        let registry = Registry::new();
+        let summary = registry.register_summary(
+            "rpc_seconds",
+            HashMap::from([("quantile".to_string(), "x".to_string())]),
+        );
+        summary.observe(1.0);
+
+        let output = collect_metrics(&registry);
+        assert!(output.contains("rpc_seconds{quantile=\"0.5\"} 1\n"));
+        assert!(output.contains("rpc_seconds_count 1\n"));
+        assert!(output.contains("\nrpc_seconds_created "));
+    }
+}
@@ -0,0 +1,242 @@
+//! The OTLP/JSON encoding of the protocol messages: field names in
+//! lowerCamelCase, 64-bit integers as strings and trace and span IDs as hex.
+
+use super::proto::*;
+use serde_json::{json, Map, Value};
+
+pub(crate) fn encode(request: &ExportMetricsServiceRequest) -> Value {
+    json!({
+        "resourceMetrics": request.resource_metrics.iter().map(resource_metrics).collect::<Vec<_>>(),
+    })
+}
+
+fn resource_metrics(resource_metrics: &ResourceMetrics) -> Value {
+    json!({
+        "resource": {
+            "attributes": resource_metrics
+                .resource
+                .as_ref()
+                .map(|resource| attributes(&resource.attributes))
+                .unwrap_or_default(),
+        },
+        "scopeMetrics": resource_metrics.scope_metrics.iter().map(scope_metrics).collect::<Vec<_>>(),
+    })
+}
+
+fn scope_metrics(scope_metrics: &ScopeMetrics) -> Value {
+    let scope = scope_metrics.scope.clone().unwrap_or_default();
+    json!({
+        "scope": { "name": scope.name, "version": scope.version },
+        "metrics": scope_metrics.metrics.iter().map(metric).collect::<Vec<_>>(),
+    })
+}
+
+fn metric(metric: &Metric) -> Value {
+    let mut object = Map::new();
+    object.insert("name".to_string(), json!(metric.name));
+    object.insert("description".to_string(), json!(metric.description));
+    object.insert("unit".to_string(), json!(metric.unit));
+    match &metric.data {
+        Some(Data::Gauge(gauge)) => {
+            object.insert(
+                "gauge".to_string(),
+                json!({ "dataPoints": number_data_points(&gauge.data_points) }),
+            );
+        }
+        Some(Data::Sum(sum)) => {
+            object.insert(
+                "sum".to_string(),
+                json!({
+                    "dataPoints": number_data_points(&sum.data_points),
+                    "aggregationTemporality": sum.aggregation_temporality,
+                    "isMonotonic": sum.is_monotonic,
+                }),
+            );
+        }
+        Some(Data::Histogram(histogram)) => {
+            object.insert(
+                "histogram".to_string(),
+                json!({
+                    "dataPoints": histogram.data_points.iter().map(histogram_data_point).collect::<Vec<_>>(),
+                    "aggregationTemporality": histogram.aggregation_temporality,
+                }),
+            );
+        }
+        Some(Data::ExponentialHistogram(histogram)) => {
+            object.insert(
+                "exponentialHistogram".to_string(),
+                json!({
+                    "dataPoints": histogram.data_points.iter().map(exponential_histogram_data_point).collect::<Vec<_>>(),
+                    "aggregationTemporality": histogram.aggregation_temporality,
+                }),
+            );
+        }
+        Some(Data::Summary(summary)) => {
+            object.insert(
+                "summary".to_string(),
+                json!({
+                    "dataPoints": summary.data_points.iter().map(summary_data_point).collect::<Vec<_>>(),
+                }),
+            );
+        }
+        None => {}
+    }
+    Value::Object(object)
+}
+
+fn number_data_points(data_points: &[NumberDataPoint]) -> Vec<Value> {
+    data_points
+        .iter()
+        .map(|point| {
+            let mut object = Map::new();
+            object.insert("attributes".to_string(), attributes(&point.attributes));
+            object.insert(
+                "startTimeUnixNano".to_string(),
+                json!(point.start_time_unix_nano.to_string()),
+            );
+            object.insert(
+                "timeUnixNano".to_string(),
+                json!(point.time_unix_nano.to_string()),
+            );
+            match point.value {
+                Some(NumberValue::AsDouble(value)) => {
+                    object.insert("asDouble".to_string(), json!(value));
+                }
+                Some(NumberValue::AsInt(value)) => {
+                    object.insert("asInt".to_string(), json!(value.to_string()));
+                }
+                None => {}
+            }
+            object.insert("exemplars".to_string(), exemplars(&point.exemplars));
+            Value::Object(object)
+        })
+        .collect()
+}
+
+fn histogram_data_point(point: &HistogramDataPoint) -> Value {
+    let mut object = Map::new();
+    object.insert("attributes".to_string(), attributes(&point.attributes));
+    object.insert(
+        "startTimeUnixNano".to_string(),
+        json!(point.start_time_unix_nano.to_string()),
+    );
+    object.insert(
+        "timeUnixNano".to_string(),
+        json!(point.time_unix_nano.to_string()),
+    );
+    object.insert("count".to_string(), json!(point.count.to_string()));
+    if let Some(sum) = point.sum {
+        object.insert("sum".to_string(), json!(sum));
+    }
+    object.insert(
+        "bucketCounts".to_string(),
+        json!(point
+            .bucket_counts
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()),
+    );
+    object.insert("explicitBounds".to_string(), json!(point.explicit_bounds));
+    object.insert("exemplars".to_string(), exemplars(&point.exemplars));
+    if let Some(min) = point.min {
+        object.insert("min".to_string(), json!(min));
+    }
+    if let Some(max) = point.max {
+        object.insert("max".to_string(), json!(max));
+    }
+    Value::Object(object)
+}
+
+fn exponential_histogram_data_point(point: &ExponentialHistogramDataPoint) -> Value {
+    let buckets = |buckets: &Option<Buckets>| {
+        let buckets = buckets.clone().unwrap_or_default();
+        json!({
+            "offset": buckets.offset,
+            "bucketCounts": buckets.bucket_counts.iter().map(u64::to_string).collect::<Vec<_>>(),
+        })
+    };
+
+    let mut object = Map::new();
+    object.insert("attributes".to_string(), attributes(&point.attributes));
+    object.insert(
+        "startTimeUnixNano".to_string(),
+        json!(point.start_time_unix_nano.to_string()),
+    );
+    object.insert(
+        "timeUnixNano".to_string(),
+        json!(point.time_unix_nano.to_string()),
+    );
+    object.insert("count".to_string(), json!(point.count.to_string()));
+    if let Some(sum) = point.sum {
+        object.insert("sum".to_string(), json!(sum));
+    }
+    object.insert("scale".to_string(), json!(point.scale));
+    object.insert("zeroCount".to_string(), json!(point.zero_count.to_string()));
+    object.insert("positive".to_string(), buckets(&point.positive));
+    object.insert("negative".to_string(), buckets(&point.negative));
+    object.insert("exemplars".to_string(), exemplars(&point.exemplars));
+    if let Some(min) = point.min {
+        object.insert("min".to_string(), json!(min));
+    }
+    if let Some(max) = point.max {
+        object.insert("max".to_string(), json!(max));
+    }
+    object.insert("zeroThreshold".to_string(), json!(point.zero_threshold));
+    Value::Object(object)
+}
+
+fn summary_data_point(point: &SummaryDataPoint) -> Value {
+    json!({
+        "attributes": attributes(&point.attributes),
+        "startTimeUnixNano": point.start_time_unix_nano.to_string(),
+        "timeUnixNano": point.time_unix_nano.to_string(),
+        "count": point.count.to_string(),
+        "sum": point.sum,
+        "quantileValues": point
+            .quantile_values
+            .iter()
+            .map(|value| json!({ "quantile": value.quantile, "value": value.value }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+fn exemplars(exemplars: &[Exemplar]) -> Value {
+    exemplars
+        .iter()
+        .map(|exemplar| {
+            let mut object = Map::new();
+            object.insert(
+                "filteredAttributes".to_string(),
+                attributes(&exemplar.filtered_attributes),
+            );
+            object.insert(
+                "timeUnixNano".to_string(),
+                json!(exemplar.time_unix_nano.to_string()),
+            );
+            if let Some(ExemplarValue::AsDouble(value)) = exemplar.value {
+                object.insert("asDouble".to_string(), json!(value));
+            }
+            if !exemplar.span_id.is_empty() {
+                object.insert("spanId".to_string(), json!(hex(&exemplar.span_id)));
+            }
+            if !exemplar.trace_id.is_empty() {
+                object.insert("traceId".to_string(), json!(hex(&exemplar.trace_id)));
+            }
+            Value::Object(object)
+        })
+        .collect()
+}
+
+fn attributes(attributes: &[KeyValue]) -> Value {
+    attributes
+        .iter()
+        .map(|attribute| {
+            let value = attribute.value.clone().unwrap_or_default();
+            json!({ "key": attribute.key, "value": { "stringValue": value.string_value } })
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,1006 @@
+//! An exporter pushing metrics to an OpenTelemetry collector over OTLP/HTTP.
+
+mod json;
+mod proto;
+
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::exporters::http::{send_with_retries, HttpExportError, Retry};
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::metrics::native_histogram::NativeHistogramSnapshot;
+use crate::metrics::{Exemplar, Unit};
+use crate::registry::Registry;
+use crate::snapshot::{FamilySnapshot, MetricValue, SeriesSnapshot};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+use proto::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::Client;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// The encoding of export requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// Binary protobuf, `application/x-protobuf`.
+    HttpProtobuf,
+    /// JSON, `application/json`.
+    HttpJson,
+}
+
+/// Whether sums and histograms are exported as totals since the series was
+/// created, or as changes since the previous export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Temporality {
+    Cumulative,
+    Delta,
+}
+
+/// An error exporting to the collector.
+pub type OtlpError = HttpExportError;
+
+/// Periodically exports the registry to an OpenTelemetry collector as an
+/// OTLP `ExportMetricsServiceRequest`, POSTed to `<endpoint>/v1/metrics`.
+///
+/// Counters are exported as monotonic sums, gauges, info and state set
+/// series as gauges, histograms and timers as explicit-bucket histograms,
+/// native histograms as exponential histograms and summaries as summaries,
+/// which are cumulative whatever the temporality. Meters are exported as a sum of their count and a
+/// `<name>_rate` gauge with a `window` attribute per moving average.
+pub struct OtlpExporter {
+    registry: Arc<Registry>,
+    client: Client,
+    endpoint: String,
+    interval: Duration,
+    protocol: OtlpProtocol,
+    temporality: Temporality,
+    resource: Vec<(String, String)>,
+    headers: HeaderMap,
+    gzip: bool,
+    timeout: Duration,
+    retry: Retry,
+    delta: Mutex<DeltaState>,
+}
+
+/// The values of the previous successful export, to compute deltas from.
+struct DeltaState {
+    last_export: SystemTime,
+    previous: HashMap<SeriesKey, PreviousPoint>,
+}
+
+type SeriesKey = (String, Vec<(String, String)>);
+
+/// The value of a series at the previous export, with the creation time
+/// of the series, which tells when it was reset since.
+struct PreviousPoint {
+    created: Option<SystemTime>,
+    value: Previous,
+}
+
+enum Previous {
+    Count(u64),
+    Histogram(HistogramSnapshot),
+    NativeHistogram(NativeHistogramSnapshot),
+}
+
+impl OtlpExporter {
+    /// Creates an exporter sending to the collector at `endpoint`, e.g.
+    /// `http://localhost:4318`, every interval.
+    pub fn new(registry: Arc<Registry>, endpoint: String, interval: Duration) -> Self {
+        OtlpExporter {
+            registry,
+            client: Client::new(),
+            endpoint,
+            interval,
+            protocol: OtlpProtocol::HttpProtobuf,
+            temporality: Temporality::Cumulative,
+            resource: Vec::new(),
+            headers: HeaderMap::new(),
+            gzip: false,
+            timeout: Duration::from_secs(10),
+            retry: Retry::default(),
+            delta: Mutex::new(DeltaState {
+                last_export: SystemTime::now(),
+                previous: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Sets the encoding of export requests. Defaults to
+    /// [`OtlpProtocol::HttpProtobuf`].
+    pub fn with_protocol(mut self, protocol: OtlpProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the temporality of sums and histograms. Defaults to
+    /// [`Temporality::Cumulative`].
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    /// Adds a resource attribute, e.g. `service.name`. Unless set,
+    /// `service.name` is `unknown_service`.
+    pub fn with_resource_attribute(mut self, key: &str, value: &str) -> Self {
+        self.resource.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds a header sent with every request, e.g. for authentication.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name or value is not a valid HTTP header.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        let name = HeaderName::try_from(name).expect("invalid header name");
+        let value = HeaderValue::try_from(value).expect("invalid header value");
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Compresses requests with gzip.
+    pub fn with_gzip(mut self, gzip: bool) -> Self {
+        self.gzip = gzip;
+        self
+    }
+
+    /// Sets the timeout of each request. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how often a failed request is retried and the delay before the
+    /// first retry, which doubles on each further retry. Only connection
+    /// errors, timeouts, `429` and `5xx` responses are retried. Defaults to 3
+    /// retries starting at 500 milliseconds.
+    pub fn with_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.retry = Retry {
+            max_retries,
+            initial_backoff,
+        };
+        self
+    }
+
+    /// Exports the metrics every interval until the returned handle is shut
+    /// down, then exports one last time so the final values are not lost.
+    /// Failed periodic exports are logged with `tracing`; the result of the
+    /// final export is returned by the handle.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> ExporterHandle {
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = interval(self.interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancelled.cancelled() => break,
+                }
+                if let Err(e) = self.export().await {
+                    tracing::warn!(error = %e, endpoint = %self.endpoint, "failed to export metrics");
+                }
+            }
+
+            self.export().await.map_err(ExporterError::from)
+        });
+
+        ExporterHandle::new(token, task, None)
+    }
+
+    /// Exports the current metrics once. With delta temporality, changes
+    /// are counted from the previous successful export, so those of a failed
+    /// export are sent with the next one.
+    pub async fn export(&self) -> Result<(), OtlpError> {
+        let (request, next) = self.build_request();
+        let (content_type, body) = match self.protocol {
+            OtlpProtocol::HttpProtobuf => ("application/x-protobuf", request.encode_to_vec()),
+            OtlpProtocol::HttpJson => (
+                "application/json",
+                json::encode(&request).to_string().into_bytes(),
+            ),
+        };
+        let body = Bytes::from(if self.gzip { gzip(&body) } else { body });
+
+        let url = self.url();
+        send_with_retries(self.retry, || {
+            let mut request = self
+                .client
+                .post(&url)
+                .timeout(self.timeout)
+                .headers(self.headers.clone())
+                .header(CONTENT_TYPE, content_type);
+            if self.gzip {
+                request = request.header(CONTENT_ENCODING, "gzip");
+            }
+            request.body(body.clone())
+        })
+        .await?;
+
+        *self.delta.lock().unwrap() = next;
+        Ok(())
+    }
+
+    fn url(&self) -> String {
+        let endpoint = self.endpoint.trim_end_matches('/');
+        if endpoint.ends_with("/v1/metrics") {
+            endpoint.to_string()
+        } else {
+            format!("{}/v1/metrics", endpoint)
+        }
+    }
+
+    /// Builds the request for the current metrics, and the delta state to
+    /// keep once it has been sent.
+    fn build_request(&self) -> (ExportMetricsServiceRequest, DeltaState) {
+        let now = SystemTime::now();
+        let delta = self.delta.lock().unwrap();
+        let mut converter = Converter {
+            temporality: self.temporality,
+            now,
+            start: delta.last_export,
+            previous: &delta.previous,
+            next: HashMap::new(),
+        };
+        let metrics = self
+            .registry
+            .snapshot()
+            .iter()
+            .flat_map(|family| converter.convert(family))
+            .collect();
+        let next = DeltaState {
+            last_export: now,
+            previous: converter.next,
+        };
+
+        let mut attributes = self.resource.clone();
+        if !attributes.iter().any(|(key, _)| key == "service.name") {
+            attributes.push(("service.name".to_string(), "unknown_service".to_string()));
+        }
+        let request = ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: key_values(&attributes),
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: env!("CARGO_PKG_NAME").to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    metrics,
+                }],
+            }],
+        };
+        (request, next)
+    }
+}
+
+/// Converts registry snapshots into OTLP metrics, recording the values that
+/// the next delta export starts from.
+struct Converter<'a> {
+    temporality: Temporality,
+    now: SystemTime,
+    start: SystemTime,
+    previous: &'a HashMap<SeriesKey, PreviousPoint>,
+    next: HashMap<SeriesKey, PreviousPoint>,
+}
+
+impl Converter<'_> {
+    fn convert(&mut self, family: &FamilySnapshot) -> Vec<Metric> {
+        let metric = |name: &str, data: Data| Metric {
+            name: name.to_string(),
+            description: family.help.clone(),
+            unit: family.unit.as_ref().map(ucum_unit).unwrap_or_default(),
+            data: Some(data),
+        };
+
+        let mut numbers = Vec::new();
+        let mut sums = Vec::new();
+        let mut histograms = Vec::new();
+        let mut exponential_histograms = Vec::new();
+        let mut summaries = Vec::new();
+        let mut rates = Vec::new();
+        for series in &family.series {
+            let attributes = key_values(&series.labels);
+            match &series.value {
+                MetricValue::Counter(value) => {
+                    sums.push(self.sum_point(&family.name, series, *value));
+                }
+                MetricValue::Gauge(value) => {
+                    numbers.push(self.number_point(attributes, NumberValue::AsDouble(*value)));
+                }
+                MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+                    histograms.push(self.histogram_point(&family.name, series, histogram));
+                }
+                MetricValue::NativeHistogram(native) => {
+                    exponential_histograms.push(self.exponential_histogram_point(
+                        &family.name,
+                        series,
+                        native,
+                    ));
+                }
+                MetricValue::Summary(summary) => {
+                    summaries.push(SummaryDataPoint {
+                        attributes,
+                        start_time_unix_nano: unix_nanos(self.created(series)),
+                        time_unix_nano: unix_nanos(self.now),
+                        count: summary.count,
+                        sum: summary.sum,
+                        // Quantiles are NaN while the window is empty.
+                        quantile_values: summary
+                            .quantiles
+                            .iter()
+                            .filter(|(_, value)| !value.is_nan())
+                            .map(|(quantile, value)| ValueAtQuantile {
+                                quantile: *quantile,
+                                value: *value,
+                            })
+                            .collect(),
+                    });
+                }
+                MetricValue::Meter(meter) => {
+                    sums.push(self.sum_point(&family.name, series, meter.count));
+                    for (window, rate) in meter.rates() {
+                        let mut attributes = attributes.clone();
+                        attributes
+                            .extend(key_values(&[("window".to_string(), window.to_string())]));
+                        rates.push(self.number_point(attributes, NumberValue::AsDouble(rate)));
+                    }
+                }
+                MetricValue::Info => {
+                    numbers.push(self.number_point(attributes, NumberValue::AsInt(1)));
+                }
+                MetricValue::StateSet(states) => {
+                    for (state, enabled) in states {
+                        let mut attributes = attributes.clone();
+                        attributes.extend(key_values(&[(family.name.clone(), state.clone())]));
+                        numbers.push(
+                            self.number_point(attributes, NumberValue::AsInt(*enabled as i64)),
+                        );
+                    }
+                }
+            }
+        }
+
+        let temporality = match self.temporality {
+            Temporality::Cumulative => AggregationTemporality::Cumulative,
+            Temporality::Delta => AggregationTemporality::Delta,
+        } as i32;
+        let mut metrics = Vec::new();
+        if !numbers.is_empty() {
+            metrics.push(metric(
+                &family.name,
+                Data::Gauge(Gauge {
+                    data_points: numbers,
+                }),
+            ));
+        }
+        if !sums.is_empty() {
+            metrics.push(metric(
+                &family.name,
+                Data::Sum(Sum {
+                    data_points: sums,
+                    aggregation_temporality: temporality,
+                    is_monotonic: true,
+                }),
+            ));
+        }
+        if !histograms.is_empty() {
+            metrics.push(metric(
+                &family.name,
+                Data::Histogram(Histogram {
+                    data_points: histograms,
+                    aggregation_temporality: temporality,
+                }),
+            ));
+        }
+        if !exponential_histograms.is_empty() {
+            metrics.push(metric(
+                &family.name,
+                Data::ExponentialHistogram(ExponentialHistogram {
+                    data_points: exponential_histograms,
+                    aggregation_temporality: temporality,
+                }),
+            ));
+        }
+        if !summaries.is_empty() {
+            metrics.push(metric(
+                &family.name,
+                Data::Summary(Summary {
+                    data_points: summaries,
+                }),
+            ));
+        }
+        if !rates.is_empty() {
+            let mut rate = metric(
+                &format!("{}_rate", family.name),
+                Data::Gauge(Gauge { data_points: rates }),
+            );
+            rate.unit = "1/s".to_string();
+            metrics.push(rate);
+        }
+        metrics
+    }
+
+    fn number_point(&self, attributes: Vec<KeyValue>, value: NumberValue) -> NumberDataPoint {
+        NumberDataPoint {
+            attributes,
+            start_time_unix_nano: 0,
+            time_unix_nano: unix_nanos(self.now),
+            value: Some(value),
+            exemplars: Vec::new(),
+        }
+    }
+
+    fn sum_point(&mut self, name: &str, series: &SeriesSnapshot, total: u64) -> NumberDataPoint {
+        let key = (name.to_string(), series.labels.clone());
+        let (start, value) = match self.temporality {
+            Temporality::Cumulative => (self.created(series), total),
+            Temporality::Delta => {
+                let previous = match self.previous(&key, series) {
+                    Some(Previous::Count(previous)) => Some(*previous),
+                    _ => None,
+                };
+                let start = self.delta_start(series, previous.is_some());
+                self.remember(key, series, Previous::Count(total));
+                (start, total.saturating_sub(previous.unwrap_or(0)))
+            }
+        };
+        NumberDataPoint {
+            attributes: key_values(&series.labels),
+            start_time_unix_nano: unix_nanos(start),
+            time_unix_nano: unix_nanos(self.now),
+            value: Some(NumberValue::AsInt(value as i64)),
+            exemplars: series.exemplar.iter().map(exemplar).collect(),
+        }
+    }
+
+    fn histogram_point(
+        &mut self,
+        name: &str,
+        series: &SeriesSnapshot,
+        histogram: &HistogramSnapshot,
+    ) -> HistogramDataPoint {
+        let mut counts = bucket_counts(histogram);
+        let mut count = histogram.count;
+        let mut sum = histogram.sum;
+        // Minimum and maximum are only known since creation.
+        let mut min_max = (histogram.count > 0).then_some((histogram.min, histogram.max));
+        let mut start = self.created(series);
+
+        if self.temporality == Temporality::Delta {
+            let key = (name.to_string(), series.labels.clone());
+            let previous = match self.previous(&key, series) {
+                // Buckets can only be subtracted with the same bounds.
+                Some(Previous::Histogram(previous))
+                    if previous.buckets.len() == histogram.buckets.len() =>
+                {
+                    Some(previous)
+                }
+                _ => None,
+            };
+            if let Some(previous) = previous {
+                for (bucket, previous) in counts.iter_mut().zip(bucket_counts(previous)) {
+                    *bucket = bucket.saturating_sub(previous);
+                }
+                count = count.saturating_sub(previous.count);
+                sum -= previous.sum;
+            }
+            start = self.delta_start(series, previous.is_some());
+            self.remember(key, series, Previous::Histogram(histogram.clone()));
+            min_max = None;
+        }
+
+        HistogramDataPoint {
+            attributes: key_values(&series.labels),
+            start_time_unix_nano: unix_nanos(start),
+            time_unix_nano: unix_nanos(self.now),
+            count,
+            sum: Some(sum),
+            bucket_counts: counts,
+            explicit_bounds: histogram
+                .buckets
+                .iter()
+                .map(|(bound, _)| *bound)
+                .filter(|bound| bound.is_finite())
+                .collect(),
+            exemplars: histogram.exemplars.iter().flatten().map(exemplar).collect(),
+            min: min_max.map(|(min, _)| min),
+            max: min_max.map(|(_, max)| max),
+        }
+    }
+
+    fn exponential_histogram_point(
+        &mut self,
+        name: &str,
+        series: &SeriesSnapshot,
+        native: &NativeHistogramSnapshot,
+    ) -> ExponentialHistogramDataPoint {
+        let mut native = native.clone();
+        let mut min_max = (native.count > 0).then_some((native.min, native.max));
+        let mut start = self.created(series);
+
+        if self.temporality == Temporality::Delta {
+            let key = (name.to_string(), series.labels.clone());
+            let current = native.clone();
+            let previous = match self.previous(&key, series) {
+                // The schema of a series only ever gets coarser.
+                Some(Previous::NativeHistogram(previous)) if previous.schema >= native.schema => {
+                    Some(previous.downscale(native.schema))
+                }
+                _ => None,
+            };
+            if let Some(previous) = &previous {
+                native.positive = subtract(&native.positive, &previous.positive);
+                native.negative = subtract(&native.negative, &previous.negative);
+                native.zero_count = native.zero_count.saturating_sub(previous.zero_count);
+                native.sum -= previous.sum;
+            }
+            start = self.delta_start(series, previous.is_some());
+            self.remember(key, series, Previous::NativeHistogram(current));
+            min_max = None;
+        }
+
+        // The count is that of the buckets, which leave out infinite
+        // observations, and so does the sum.
+        let positive = dense_buckets(&native, &native.positive);
+        let negative = dense_buckets(&native, &native.negative);
+        let count = native.zero_count
+            + positive.bucket_counts.iter().sum::<u64>()
+            + negative.bucket_counts.iter().sum::<u64>();
+
+        ExponentialHistogramDataPoint {
+            attributes: key_values(&series.labels),
+            start_time_unix_nano: unix_nanos(start),
+            time_unix_nano: unix_nanos(self.now),
+            count,
+            sum: native.sum.is_finite().then_some(native.sum),
+            scale: native.schema,
+            zero_count: native.zero_count,
+            positive: Some(positive),
+            negative: Some(negative),
+            exemplars: Vec::new(),
+            min: min_max.map(|(min, _)| min),
+            max: min_max.map(|(_, max)| max),
+            zero_threshold: native.zero_threshold,
+        }
+    }
+
+    fn created(&self, series: &SeriesSnapshot) -> SystemTime {
+        series.created.unwrap_or(self.start)
+    }
+
+    /// Gets the value of a series at the previous export, unless the series
+    /// was created again since, e.g. after being removed from its family.
+    fn previous(&self, key: &SeriesKey, series: &SeriesSnapshot) -> Option<&Previous> {
+        self.previous
+            .get(key)
+            .filter(|previous| previous.created == series.created)
+            .map(|previous| &previous.value)
+    }
+
+    fn remember(&mut self, key: SeriesKey, series: &SeriesSnapshot, value: Previous) {
+        let created = series.created;
+        self.next.insert(key, PreviousPoint { created, value });
+    }
+
+    /// Gets the start of a delta: the previous export, or the creation of
+    /// the series if it has no value from then.
+    fn delta_start(&self, series: &SeriesSnapshot, has_previous: bool) -> SystemTime {
+        if has_previous {
+            self.start
+        } else {
+            self.created(series)
+        }
+    }
+}
+
+/// Gets the non-cumulative bucket counts, ending with the `+Inf` bucket.
+fn bucket_counts(histogram: &HistogramSnapshot) -> Vec<u64> {
+    let mut below = 0;
+    histogram
+        .buckets
+        .iter()
+        .map(|(_, cumulative)| {
+            let count = cumulative.saturating_sub(below);
+            below = *cumulative;
+            count
+        })
+        .collect()
+}
+
+/// Subtracts previous sparse bucket counts of the same schema, leaving out
+/// buckets that did not change.
+fn subtract(current: &[(i32, u64)], previous: &[(i32, u64)]) -> Vec<(i32, u64)> {
+    let previous: BTreeMap<i32, u64> = previous.iter().copied().collect();
+    current
+        .iter()
+        .map(|(index, count)| {
+            let previous = previous.get(index).copied().unwrap_or(0);
+            (*index, count.saturating_sub(previous))
+        })
+        .filter(|(_, count)| *count > 0)
+        .collect()
+}
+
+/// Converts sparse native buckets into dense OTLP buckets. OTLP bucket `i`
+/// is native bucket `i + 1`, both holding `(base^i, base^(i+1)]`. Buckets
+/// with infinite bounds, holding infinite observations, are left out, as
+/// OTLP buckets cannot hold them.
+fn dense_buckets(native: &NativeHistogramSnapshot, sparse: &[(i32, u64)]) -> Buckets {
+    let mut sparse = sparse
+        .iter()
+        .filter(|(index, _)| native.upper_bound(*index).is_finite());
+    let Some((first, count)) = sparse.next() else {
+        return Buckets::default();
+    };
+    let mut bucket_counts = vec![*count];
+    for (index, count) in sparse {
+        bucket_counts.resize((index - first) as usize, 0);
+        bucket_counts.push(*count);
+    }
+    Buckets {
+        offset: first - 1,
+        bucket_counts,
+    }
+}
+
+/// Converts an exemplar, taking `trace_id` and `span_id` labels holding hex
+/// IDs as the exemplar's trace and span.
+fn exemplar(exemplar: &Exemplar) -> proto::Exemplar {
+    let mut trace_id = Vec::new();
+    let mut span_id = Vec::new();
+    let mut filtered = Vec::new();
+    for (key, value) in &exemplar.labels {
+        match (key.as_str(), decode_hex(value)) {
+            ("trace_id", Some(id)) if id.len() == 16 => trace_id = id,
+            ("span_id", Some(id)) if id.len() == 8 => span_id = id,
+            _ => filtered.push((key.clone(), value.clone())),
+        }
+    }
+    proto::Exemplar {
+        filtered_attributes: key_values(&filtered),
+        time_unix_nano: unix_nanos(exemplar.timestamp),
+        value: Some(ExemplarValue::AsDouble(exemplar.value)),
+        span_id,
+        trace_id,
+    }
+}
+
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+    if !value.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn key_values(pairs: &[(String, String)]) -> Vec<KeyValue> {
+    pairs
+        .iter()
+        .map(|(key, value)| KeyValue {
+            key: key.clone(),
+            value: Some(AnyValue {
+                string_value: value.clone(),
+            }),
+        })
+        .collect()
+}
+
+/// Converts a unit to its UCUM code, as OpenTelemetry expects.
+fn ucum_unit(unit: &Unit) -> String {
+    match unit {
+        Unit::Seconds => "s",
+        Unit::Milliseconds => "ms",
+        Unit::Microseconds => "us",
+        Unit::Nanoseconds => "ns",
+        Unit::Bytes => "By",
+        Unit::Ratio | Unit::Count => "1",
+        Unit::Percent => "%",
+        Unit::Other(unit) => unit,
+    }
+    .to_string()
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(body)
+        .expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporters::test_server::{Received, TestServer};
+    use flate2::read::GzDecoder;
+    use serde_json::Value;
+    use std::io::Read;
+
+    fn exporter(server: &TestServer, registry: Arc<Registry>) -> OtlpExporter {
+        OtlpExporter::new(registry, server.url.clone(), Duration::from_secs(3600))
+            .with_retries(0, Duration::from_millis(1))
+    }
+
+    fn decode(received: &Received) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest::decode(received.body.clone()).unwrap()
+    }
+
+    /// Gets the data of the only metric of a request.
+    fn data(request: &ExportMetricsServiceRequest) -> &Data {
+        let metrics = &request.resource_metrics[0].scope_metrics[0].metrics;
+        assert_eq!(metrics.len(), 1);
+        metrics[0].data.as_ref().unwrap()
+    }
+
+    fn sum_values(received: &Received) -> Vec<i64> {
+        match data(&decode(received)) {
+            Data::Sum(sum) => sum
+                .data_points
+                .iter()
+                .map(|point| match point.value {
+                    Some(NumberValue::AsInt(value)) => value,
+                    _ => panic!("expected an integer"),
+                })
+                .collect(),
+            _ => panic!("expected a sum"),
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_cumulative_sums_as_protobuf() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        let counter = registry.counter_family("jobs_total", &["queue"]);
+        let exporter = exporter(&server, registry.clone())
+            .with_resource_attribute("service.name", "worker")
+            .with_header("x-api-key", "secret");
+
+        counter.with_label_values(&["a"]).increment_by(5);
+        exporter.export().await.unwrap();
+        counter.with_label_values(&["a"]).increment_by(2);
+        exporter.export().await.unwrap();
+
+        let received = server.received();
+        assert_eq!(received[0].path, "/v1/metrics");
+        assert_eq!(
+            received[0].header("content-type"),
+            Some("application/x-protobuf")
+        );
+        assert_eq!(received[0].header("x-api-key"), Some("secret"));
+        assert_eq!(received[0].header("content-encoding"), None);
+        assert_eq!(sum_values(&received[0]), [5]);
+        assert_eq!(sum_values(&received[1]), [7]);
+
+        let request = decode(&received[0]);
+        let resource = request.resource_metrics[0].resource.as_ref().unwrap();
+        assert_eq!(
+            resource.attributes,
+            key_values(&[("service.name".into(), "worker".into())])
+        );
+        let metric = &request.resource_metrics[0].scope_metrics[0].metrics[0];
+        assert_eq!(metric.name, "jobs_total");
+        let Data::Sum(sum) = data(&request) else {
+            unreachable!()
+        };
+        assert!(sum.is_monotonic);
+        assert_eq!(
+            sum.aggregation_temporality,
+            AggregationTemporality::Cumulative as i32
+        );
+        assert_eq!(
+            sum.data_points[0].attributes,
+            key_values(&[("queue".into(), "a".into())])
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_deltas_and_keeps_failed_ones() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        let counter = registry.register_counter("jobs_total", HashMap::new());
+        let exporter = exporter(&server, registry).with_temporality(Temporality::Delta);
+
+        counter.increment_by(5);
+        exporter.export().await.unwrap();
+        counter.increment_by(2);
+        server.respond_with(&[503]);
+        assert!(exporter.export().await.is_err());
+        counter.increment_by(1);
+        exporter.export().await.unwrap();
+
+        let received = server.received();
+        assert_eq!(sum_values(&received[0]), [5]);
+        assert_eq!(sum_values(&received[1]), [2]);
+        // The failed delta is sent with the next export.
+        assert_eq!(sum_values(&received[2]), [3]);
+
+        let last = decode(&received[2]);
+        let Data::Sum(sum) = data(&last) else {
+            unreachable!()
+        };
+        assert_eq!(
+            sum.aggregation_temporality,
+            AggregationTemporality::Delta as i32
+        );
+        let first = match data(&decode(&received[0])) {
+            Data::Sum(sum) => sum.data_points[0].time_unix_nano,
+            _ => unreachable!(),
+        };
+        assert_eq!(sum.data_points[0].start_time_unix_nano, first);
+    }
+
+    #[tokio::test]
+    async fn exports_delta_histograms() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        let histogram =
+            registry.register_histogram_with_buckets("latency", HashMap::new(), vec![1.0, 2.0]);
+        let exporter = exporter(&server, registry).with_temporality(Temporality::Delta);
+
+        histogram.observe(0.5);
+        histogram.observe(1.5);
+        exporter.export().await.unwrap();
+        histogram.observe(3.0);
+        exporter.export().await.unwrap();
+
+        let points: Vec<HistogramDataPoint> = server
+            .received()
+            .iter()
+            .map(|received| match data(&decode(received)) {
+                Data::Histogram(histogram) => histogram.data_points[0].clone(),
+                _ => panic!("expected a histogram"),
+            })
+            .collect();
+        assert_eq!(points[0].explicit_bounds, [1.0, 2.0]);
+        assert_eq!(points[0].bucket_counts, [1, 1, 0]);
+        assert_eq!((points[0].count, points[0].sum), (2, Some(2.0)));
+        assert_eq!(points[1].bucket_counts, [0, 0, 1]);
+        assert_eq!((points[1].count, points[1].sum), (1, Some(3.0)));
+        assert_eq!(points[1].min, None);
+    }
+
+    #[tokio::test]
+    async fn restarts_deltas_of_recreated_series() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        let counter = registry.counter_family("jobs_total", &["queue"]);
+        let exporter = exporter(&server, registry).with_temporality(Temporality::Delta);
+
+        counter.with_label_values(&["a"]).increment_by(5);
+        exporter.export().await.unwrap();
+        counter.remove_label_values(&["a"]);
+        let recreated = counter.with_label_values(&["a"]);
+        recreated.increment_by(7);
+        exporter.export().await.unwrap();
+
+        let received = server.received();
+        // Counted from the creation of the new series, not from 5.
+        assert_eq!(sum_values(&received[1]), [7]);
+        let request = decode(&received[1]);
+        let Data::Sum(sum) = data(&request) else {
+            unreachable!()
+        };
+        assert_eq!(
+            sum.data_points[0].start_time_unix_nano,
+            unix_nanos(recreated.created())
+        );
+    }
+
+    #[tokio::test]
+    async fn exports_delta_exponential_histograms() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        let native = registry.register_native_histogram("latency", HashMap::new());
+        let exporter = exporter(&server, registry).with_temporality(Temporality::Delta);
+
+        native.observe(1.5);
+        native.observe(0.0);
+        exporter.export().await.unwrap();
+        native.observe(1.5);
+        native.observe(3.0);
+        native.observe(f64::INFINITY);
+        exporter.export().await.unwrap();
+
+        let points: Vec<ExponentialHistogramDataPoint> = server
+            .received()
+            .iter()
+            .map(|received| match data(&decode(received)) {
+                Data::ExponentialHistogram(histogram) => histogram.data_points[0].clone(),
+                _ => panic!("expected an exponential histogram"),
+            })
+            .collect();
+        assert_eq!(points[0].scale, 3);
+        assert_eq!((points[0].count, points[0].zero_count), (2, 1));
+        let positive = points[0].positive.as_ref().unwrap();
+        // 1.5 is in native bucket 5, (2^(4/8), 2^(5/8)], OTLP bucket 4.
+        assert_eq!(
+            (positive.offset, &positive.bucket_counts[..]),
+            (4, &[1][..])
+        );
+        assert_eq!(points[0].sum, Some(1.5));
+
+        // The infinite observation is in neither the buckets nor the count.
+        assert_eq!((points[1].count, points[1].zero_count), (2, 0));
+        let positive = points[1].positive.as_ref().unwrap();
+        assert_eq!(positive.offset, 4);
+        assert_eq!(positive.bucket_counts.iter().sum::<u64>(), 2);
+        assert_eq!(positive.bucket_counts[0], 1);
+        assert_eq!(points[1].sum, None);
+    }
+
+    #[tokio::test]
+    async fn exports_gzipped_json() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        registry
+            .register_gauge("temperature", HashMap::new())
+            .set(21.5);
+        exporter(&server, registry)
+            .with_protocol(OtlpProtocol::HttpJson)
+            .with_gzip(true)
+            .export()
+            .await
+            .unwrap();
+
+        let received = &server.received()[0];
+        assert_eq!(received.header("content-type"), Some("application/json"));
+        assert_eq!(received.header("content-encoding"), Some("gzip"));
+        let mut body = String::new();
+        GzDecoder::new(&received.body[..])
+            .read_to_string(&mut body)
+            .unwrap();
+        let json: Value = serde_json::from_str(&body).unwrap();
+
+        let resource = &json["resourceMetrics"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0],
+            serde_json::json!({
+                "key": "service.name",
+                "value": { "stringValue": "unknown_service" },
+            })
+        );
+        let metric = &resource["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(metric["name"], "temperature");
+        assert_eq!(metric["gauge"]["dataPoints"][0]["asDouble"], 21.5);
+        assert!(metric["gauge"]["dataPoints"][0]["timeUnixNano"].is_string());
+    }
+
+    #[test]
+    fn appends_the_metrics_path_once() {
+        let registry = Arc::new(Registry::new());
+        let url = |endpoint: &str| {
+            OtlpExporter::new(
+                registry.clone(),
+                endpoint.to_string(),
+                Duration::from_secs(1),
+            )
+            .url()
+        };
+        assert_eq!(
+            url("http://collector:4318"),
+            "http://collector:4318/v1/metrics"
+        );
+        assert_eq!(
+            url("http://collector:4318/v1/metrics/"),
+            "http://collector:4318/v1/metrics"
+        );
+    }
+}
@@ -1,8 +1,24 @@
-use crate::metrics::Metric;
+mod proto;
+
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::exporters::openmetrics;
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::metrics::native_histogram::NativeHistogramSnapshot;
+use crate::metrics::summary::SummarySnapshot;
+use crate::metrics::Exemplar;
 use crate::registry::Registry;
-use axum::{extract::State, response::IntoResponse, routing::get, serve, Router};
+use crate::snapshot::{FamilySnapshot, MetricKind, MetricValue, SeriesSnapshot};
+use actix_web::{web, HttpRequest, HttpResponse};
+use axum::http::header::{ACCEPT, CONTENT_TYPE as CONTENT_TYPE_HEADER};
+use axum::http::HeaderMap;
+use axum::routing::{get, MethodRouter};
+use axum::{extract::State, response::IntoResponse, serve, Router};
+use prost::Message;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 
 pub struct PrometheusExporter {
     registry: Arc<Registry>,
@@ -13,69 +29,1117 @@ impl PrometheusExporter {
         PrometheusExporter { registry }
     }
 
-    pub async fn start(self, addr: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let registry = Arc::clone(&self.registry);
-
-        let app = Router::new()
-            .route("/metrics", get(metrics_handler))
-            .with_state(registry);
+    /// Serves `/metrics` on its own listener until the returned handle is
+    /// shut down. To expose metrics on an existing server instead, mount
+    /// [`router`], [`handler`] or [`actix_resource`].
+    pub async fn start(self, addr: &str) -> Result<ExporterHandle, ExporterError> {
+        let app: Router = router(Arc::clone(&self.registry), "/metrics");
 
         let listener = TcpListener::bind(addr).await?;
-        println!("Prometheus exporter listening on http://{}", addr);
+        let local_addr = listener.local_addr()?;
+        println!("Prometheus exporter listening on http://{}", local_addr);
+
+        let token = CancellationToken::new();
+        let shutdown = token.clone().cancelled_owned();
+        let task = tokio::spawn(async move {
+            serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+            Ok(())
+        });
+
+        Ok(ExporterHandle::new(token, task, Some(local_addr)))
+    }
+}
+
+/// The content type of the Prometheus text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The content type of the Prometheus delimited protobuf format.
+pub const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// An exposition format the metrics handler can answer with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// The Prometheus text format, version 0.0.4.
+    Text,
+    /// The OpenMetrics 1.0 text format.
+    OpenMetrics,
+    /// Length-delimited `io.prometheus.client.MetricFamily` protobuf
+    /// messages.
+    Protobuf,
+}
 
-        serve(listener, app).await?;
+impl Format {
+    /// Picks a format from the value of an `Accept` header: the supported
+    /// media range with the highest quality wins, the earliest listed on a
+    /// tie. Media types and parameter names are case-insensitive. Falls
+    /// back to the text format.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        let mut best = (Format::Text, 0.0);
+        for range in accept.unwrap_or_default().split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut version = None;
+            let mut proto = None;
+            let mut encoding = None;
+            for param in parts {
+                let Some((name, value)) = param.split_once('=') else {
+                    continue;
+                };
+                let value = value.trim().trim_matches('"');
+                match name.trim().to_ascii_lowercase().as_str() {
+                    "q" => quality = value.parse().unwrap_or(0.0),
+                    "version" => version = Some(value),
+                    "proto" => proto = Some(value),
+                    "encoding" => encoding = Some(value),
+                    _ => {}
+                }
+            }
+            let format = match (media_type.as_str(), version) {
+                ("application/openmetrics-text", None | Some("1.0.0") | Some("0.0.1")) => {
+                    Format::OpenMetrics
+                }
+                ("application/vnd.google.protobuf", _)
+                    if proto == Some("io.prometheus.client.MetricFamily")
+                        && encoding == Some("delimited") =>
+                {
+                    Format::Protobuf
+                }
+                ("text/plain" | "text/*" | "*/*", _) => Format::Text,
+                _ => continue,
+            };
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
 
-        Ok(())
+    /// Gets the content type of the format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Text => CONTENT_TYPE,
+            Format::OpenMetrics => openmetrics::CONTENT_TYPE,
+            Format::Protobuf => PROTOBUF_CONTENT_TYPE,
+        }
     }
+
+    /// Renders the registry in this format.
+    pub fn render(&self, registry: &Registry) -> Vec<u8> {
+        match self {
+            Format::Text => collect_metrics(registry).into_bytes(),
+            Format::OpenMetrics => openmetrics::collect_metrics(registry).into_bytes(),
+            Format::Protobuf => collect_metrics_protobuf(registry),
+        }
+    }
+}
+
+/// Creates an axum router serving the metrics at `path`, to be merged into
+/// or nested in an application router.
+pub fn router<S>(registry: Arc<Registry>, path: &str) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new().route(path, handler(registry))
+}
+
+/// Creates an axum `GET` handler serving the metrics, to be mounted with
+/// `Router::route` at any path.
+pub fn handler<S>(registry: Arc<Registry>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    get(metrics_handler).with_state(registry)
+}
+
+/// Creates an actix-web resource serving the metrics at `path`, to be
+/// registered with `App::service`.
+pub fn actix_resource(path: &str, registry: Arc<Registry>) -> actix_web::Resource {
+    web::resource(path)
+        .app_data(web::Data::new(registry))
+        .route(web::get().to(actix_metrics_handler))
 }
 
-async fn metrics_handler(State(registry): State<Arc<Registry>>) -> impl IntoResponse {
-    let metrics = collect_metrics(&registry);
+async fn metrics_handler(
+    State(registry): State<Arc<Registry>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let accept = headers.get(ACCEPT).and_then(|value| value.to_str().ok());
+    let format = Format::from_accept(accept);
     (
         axum::http::StatusCode::OK,
-        [("Content-Type", "text/plain; version=0.0.4")],
-        metrics,
+        [(CONTENT_TYPE_HEADER, format.content_type())],
+        format.render(&registry),
     )
 }
 
-pub(crate) fn collect_metrics(registry: &Arc<Registry>) -> String {
+async fn actix_metrics_handler(
+    req: HttpRequest,
+    registry: web::Data<Arc<Registry>>,
+) -> HttpResponse {
+    let accept = req
+        .headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok());
+    let format = Format::from_accept(accept);
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(format.render(&registry))
+}
+
+/// Renders the registry in the Prometheus text format.
+pub(crate) fn collect_metrics(registry: &Registry) -> String {
     let mut output = String::new();
+    let mut written = HashSet::new();
+
+    for family in registry.snapshot() {
+        let name = sanitize_metric_name(&family.name);
+        let series = &family.series;
+        match family.kind {
+            MetricKind::Counter => {
+                let header = (name.as_str(), family.help.as_str(), "counter");
+                write_family(&mut output, &mut written, header, series, |out, series| {
+                    if let MetricValue::Counter(value) = series.value {
+                        write_sample(out, &name, &series.labels, None, value as f64);
+                    }
+                });
+            }
+            MetricKind::Gauge => {
+                let header = (name.as_str(), family.help.as_str(), "gauge");
+                write_family(&mut output, &mut written, header, series, |out, series| {
+                    if let MetricValue::Gauge(value) = series.value {
+                        write_sample(out, &name, &series.labels, None, value);
+                    }
+                });
+            }
+            MetricKind::Histogram | MetricKind::NativeHistogram | MetricKind::Timer => {
+                let header = (name.as_str(), family.help.as_str(), "histogram");
+                write_family(&mut output, &mut written, header, series, |out, series| {
+                    match &series.value {
+                        MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+                            write_histogram(out, &name, &series.labels, histogram);
+                        }
+                        // The text format has no native histograms.
+                        MetricValue::NativeHistogram(native) => {
+                            write_histogram(out, &name, &series.labels, &native.to_classic());
+                        }
+                        _ => {}
+                    }
+                });
+            }
+            MetricKind::Summary => {
+                let header = (name.as_str(), family.help.as_str(), "summary");
+                write_family(&mut output, &mut written, header, series, |out, series| {
+                    if let MetricValue::Summary(summary) = &series.value {
+                        write_summary(out, &name, &series.labels, summary);
+                    }
+                });
+            }
+            MetricKind::Meter => write_meter(&mut output, &mut written, &name, &family),
+            MetricKind::Info => {
+                let info_name = format!("{}_info", name.strip_suffix("_info").unwrap_or(&name));
+                let header = (info_name.as_str(), family.help.as_str(), "gauge");
+                write_family(&mut output, &mut written, header, series, |out, series| {
+                    write_sample(out, &info_name, &series.labels, None, 1.0);
+                });
+            }
+            MetricKind::StateSet => {
+                let header = (name.as_str(), family.help.as_str(), "gauge");
+                write_family(&mut output, &mut written, header, series, |out, series| {
+                    if let MetricValue::StateSet(states) = &series.value {
+                        for (state, enabled) in states {
+                            let label = (name.as_str(), state.clone());
+                            let value = if *enabled { 1.0 } else { 0.0 };
+                            write_sample(out, &name, &series.labels, Some(label), value);
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    output
+}
+
+/// Writes the `# HELP` and `# TYPE` lines and the samples of a family,
+/// unless a family with the same name was already written.
+fn write_family<F>(
+    output: &mut String,
+    written: &mut HashSet<String>,
+    (name, help, kind): (&str, &str, &str),
+    series: &[SeriesSnapshot],
+    write_series: F,
+) where
+    F: Fn(&mut String, &SeriesSnapshot),
+{
+    if !written.insert(name.to_string()) {
+        warn_name_collision(name);
+        return;
+    }
+    if !help.is_empty() {
+        output.push_str(&format!("# HELP {} {}\n", name, escape_help(help)));
+    }
+    output.push_str(&format!("# TYPE {} {}\n", name, kind));
+    for series in series {
+        write_series(output, series);
+    }
+}
 
-    // Collect counters
-    let counters = registry.counters.read().unwrap();
-    for counter in counters.values() {
-        let name = sanitize_metric_name(&counter.name());
-        let value = counter.get();
-        let labels = format_labels(&counter.labels());
-        output.push_str(&format!("{}{} {}\n", name, labels, value));
+/// Warns that a family was left out because an earlier family has the same
+/// exposed name, e.g. `a.b` and `a_b`, or a counter and a histogram
+/// registered under the same name.
+pub(crate) fn warn_name_collision(name: &str) {
+    tracing::warn!(
+        name,
+        "metric family skipped: another family is exposed under the same name"
+    );
+}
+
+fn write_histogram(
+    output: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    histogram: &HistogramSnapshot,
+) {
+    // `le` is reserved for the bucket bound, so drop any label with that name
+    // from every sample of the series to keep them consistent.
+    let labels: Vec<(String, String)> = labels
+        .iter()
+        .filter(|(name, _)| sanitize_label_name(name) != "le")
+        .cloned()
+        .collect();
+    let labels = labels.as_slice();
+    let bucket_name = format!("{}_bucket", name);
+    for (bound, count) in &histogram.buckets {
+        let le = ("le", format_value(*bound));
+        write_sample(output, &bucket_name, labels, Some(le), *count as f64);
     }
+    write_sample(
+        output,
+        &format!("{}_sum", name),
+        labels,
+        None,
+        histogram.sum,
+    );
+    let count = histogram.count as f64;
+    write_sample(output, &format!("{}_count", name), labels, None, count);
+}
 
-    // Collect gauges
-    let gauges = registry.gauges.read().unwrap();
-    for gauge in gauges.values() {
-        let name = sanitize_metric_name(&gauge.name());
-        let value = gauge.get();
-        let labels = format_labels(&gauge.labels());
-        output.push_str(&format!("{}{} {}\n", name, labels, value));
+fn write_summary(
+    output: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    summary: &SummarySnapshot,
+) {
+    // `quantile` is reserved like `le` for histograms.
+    let labels: Vec<(String, String)> = labels
+        .iter()
+        .filter(|(name, _)| sanitize_label_name(name) != "quantile")
+        .cloned()
+        .collect();
+    let labels = labels.as_slice();
+    for (quantile, value) in &summary.quantiles {
+        let quantile = ("quantile", format_value(*quantile));
+        write_sample(output, name, labels, Some(quantile), *value);
     }
+    write_sample(output, &format!("{}_sum", name), labels, None, summary.sum);
+    let count = summary.count as f64;
+    write_sample(output, &format!("{}_count", name), labels, None, count);
+}
+
+/// Writes a meter as a `_total` counter and one gauge per rate.
+fn write_meter(
+    output: &mut String,
+    written: &mut HashSet<String>,
+    name: &str,
+    family: &FamilySnapshot,
+) {
+    let (help, series) = (family.help.as_str(), family.series.as_slice());
+    let base = name.strip_suffix("_total").unwrap_or(name);
 
-    // Histograms, meters, timers can be added similarly.
+    let total_name = format!("{}_total", base);
+    let header = (total_name.as_str(), help, "counter");
+    write_family(output, written, header, series, |out, series| {
+        if let MetricValue::Meter(meter) = &series.value {
+            write_sample(out, &total_name, &series.labels, None, meter.count as f64);
+        }
+    });
+
+    for (index, window) in ["mean", "1m", "5m", "15m"].into_iter().enumerate() {
+        let rate_name = format!("{}_rate_{}", base, window);
+        let rate_help = if help.is_empty() {
+            String::new()
+        } else {
+            format!("{} ({} rate per second)", help, window)
+        };
+        let header = (rate_name.as_str(), rate_help.as_str(), "gauge");
+        write_family(output, written, header, series, |out, series| {
+            if let MetricValue::Meter(meter) = &series.value {
+                write_sample(
+                    out,
+                    &rate_name,
+                    &series.labels,
+                    None,
+                    meter.rates()[index].1,
+                );
+            }
+        });
+    }
+}
+
+fn write_sample(
+    output: &mut String,
+    name: &str,
+    labels: &[(String, String)],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+) {
+    output.push_str(&format!(
+        "{}{} {}\n",
+        name,
+        format_labels(labels, extra_label),
+        format_value(value)
+    ));
+}
+
+/// Renders the registry as length-delimited protobuf `MetricFamily`
+/// messages, with the same family names and labels as the text format.
+pub(crate) fn collect_metrics_protobuf(registry: &Registry) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut written = HashSet::new();
+
+    for family in registry.snapshot() {
+        for metric_family in protobuf_families(&family) {
+            if !written.insert(metric_family.name.clone()) {
+                warn_name_collision(&metric_family.name);
+                continue;
+            }
+            metric_family
+                .encode_length_delimited(&mut output)
+                .expect("a Vec has unbounded capacity");
+        }
+    }
 
     output
 }
 
-fn format_labels(labels: &std::collections::HashMap<String, String>) -> String {
-    if labels.is_empty() {
-        "".to_string()
+fn protobuf_families(family: &FamilySnapshot) -> Vec<proto::MetricFamily> {
+    let name = sanitize_metric_name(&family.name);
+    let unit = family.unit.as_ref().map(|unit| unit.as_str().to_string());
+    let metric_family =
+        |name: &str, help: &str, kind: proto::MetricType, metric| proto::MetricFamily {
+            name: name.to_string(),
+            help: help.to_string(),
+            r#type: kind as i32,
+            metric,
+            unit: unit.clone().unwrap_or_default(),
+        };
+    let series = &family.series;
+
+    match family.kind {
+        MetricKind::Counter => {
+            let metrics = series
+                .iter()
+                .filter_map(|series| match series.value {
+                    MetricValue::Counter(value) => Some(counter_metric(series, value as f64)),
+                    _ => None,
+                })
+                .collect();
+            vec![metric_family(
+                &name,
+                &family.help,
+                proto::MetricType::Counter,
+                metrics,
+            )]
+        }
+        MetricKind::Gauge => {
+            let metrics = series
+                .iter()
+                .filter_map(|series| match series.value {
+                    MetricValue::Gauge(value) => Some(gauge_metric(&series.labels, None, value)),
+                    _ => None,
+                })
+                .collect();
+            vec![metric_family(
+                &name,
+                &family.help,
+                proto::MetricType::Gauge,
+                metrics,
+            )]
+        }
+        MetricKind::Histogram | MetricKind::NativeHistogram | MetricKind::Timer => {
+            let metrics = series
+                .iter()
+                .filter_map(|series| match &series.value {
+                    MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+                        Some(histogram_metric(series, histogram))
+                    }
+                    MetricValue::NativeHistogram(native) => {
+                        Some(native_histogram_metric(series, native))
+                    }
+                    _ => None,
+                })
+                .collect();
+            vec![metric_family(
+                &name,
+                &family.help,
+                proto::MetricType::Histogram,
+                metrics,
+            )]
+        }
+        MetricKind::Summary => {
+            let metrics = series
+                .iter()
+                .filter_map(|series| match &series.value {
+                    MetricValue::Summary(summary) => Some(summary_metric(series, summary)),
+                    _ => None,
+                })
+                .collect();
+            vec![metric_family(
+                &name,
+                &family.help,
+                proto::MetricType::Summary,
+                metrics,
+            )]
+        }
+        MetricKind::Meter => {
+            let base = name.strip_suffix("_total").unwrap_or(&name);
+            let meters: Vec<_> = series
+                .iter()
+                .filter_map(|series| match &series.value {
+                    MetricValue::Meter(meter) => Some((series, meter)),
+                    _ => None,
+                })
+                .collect();
+
+            let totals = meters
+                .iter()
+                .map(|(series, meter)| counter_metric(series, meter.count as f64))
+                .collect();
+            let total_name = format!("{}_total", base);
+            let mut families = vec![metric_family(
+                &total_name,
+                &family.help,
+                proto::MetricType::Counter,
+                totals,
+            )];
+            for (index, window) in ["mean", "1m", "5m", "15m"].into_iter().enumerate() {
+                let rates = meters
+                    .iter()
+                    .map(|(series, meter)| {
+                        gauge_metric(&series.labels, None, meter.rates()[index].1)
+                    })
+                    .collect();
+                let rate_help = if family.help.is_empty() {
+                    String::new()
+                } else {
+                    format!("{} ({} rate per second)", family.help, window)
+                };
+                let mut rate_family = metric_family(
+                    &format!("{}_rate_{}", base, window),
+                    &rate_help,
+                    proto::MetricType::Gauge,
+                    rates,
+                );
+                rate_family.unit.clear();
+                families.push(rate_family);
+            }
+            families
+        }
+        MetricKind::Info => {
+            let info_name = format!("{}_info", name.strip_suffix("_info").unwrap_or(&name));
+            let metrics = series
+                .iter()
+                .map(|series| gauge_metric(&series.labels, None, 1.0))
+                .collect();
+            vec![metric_family(
+                &info_name,
+                &family.help,
+                proto::MetricType::Gauge,
+                metrics,
+            )]
+        }
+        MetricKind::StateSet => {
+            let mut metrics = Vec::new();
+            for series in series {
+                if let MetricValue::StateSet(states) = &series.value {
+                    for (state, enabled) in states {
+                        let label = Some((name.as_str(), state.clone()));
+                        let value = if *enabled { 1.0 } else { 0.0 };
+                        metrics.push(gauge_metric(&series.labels, label, value));
+                    }
+                }
+            }
+            vec![metric_family(
+                &name,
+                &family.help,
+                proto::MetricType::Gauge,
+                metrics,
+            )]
+        }
+    }
+}
+
+fn counter_metric(series: &SeriesSnapshot, value: f64) -> proto::Metric {
+    proto::Metric {
+        label: label_pairs(&series.labels, None),
+        counter: Some(proto::Counter {
+            value,
+            exemplar: series.exemplar.as_ref().map(exemplar),
+            created_timestamp: series.created.map(timestamp),
+        }),
+        ..Default::default()
+    }
+}
+
+fn gauge_metric(
+    labels: &[(String, String)],
+    extra_label: Option<(&str, String)>,
+    value: f64,
+) -> proto::Metric {
+    proto::Metric {
+        label: label_pairs(labels, extra_label),
+        gauge: Some(proto::Gauge { value }),
+        ..Default::default()
+    }
+}
+
+/// Converts a histogram, leaving out the `+Inf` bucket unless it carries an
+/// exemplar, as the count implies it.
+fn histogram_metric(series: &SeriesSnapshot, histogram: &HistogramSnapshot) -> proto::Metric {
+    let labels: Vec<(String, String)> = series
+        .labels
+        .iter()
+        .filter(|(name, _)| sanitize_label_name(name) != "le")
+        .cloned()
+        .collect();
+    let bucket = histogram
+        .buckets
+        .iter()
+        .zip(&histogram.exemplars)
+        .filter(|((bound, _), bucket_exemplar)| bound.is_finite() || bucket_exemplar.is_some())
+        .map(|((bound, count), bucket_exemplar)| proto::Bucket {
+            cumulative_count: *count,
+            upper_bound: *bound,
+            exemplar: bucket_exemplar.as_ref().map(exemplar),
+        })
+        .collect();
+
+    proto::Metric {
+        label: label_pairs(&labels, None),
+        histogram: Some(proto::Histogram {
+            sample_count: histogram.count,
+            sample_sum: histogram.sum,
+            bucket,
+            created_timestamp: series.created.map(timestamp),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn summary_metric(series: &SeriesSnapshot, summary: &SummarySnapshot) -> proto::Metric {
+    let labels: Vec<(String, String)> = series
+        .labels
+        .iter()
+        .filter(|(name, _)| sanitize_label_name(name) != "quantile")
+        .cloned()
+        .collect();
+    proto::Metric {
+        label: label_pairs(&labels, None),
+        summary: Some(proto::Summary {
+            sample_count: summary.count,
+            sample_sum: summary.sum,
+            quantile: summary
+                .quantiles
+                .iter()
+                .map(|(quantile, value)| proto::Quantile {
+                    quantile: *quantile,
+                    value: *value,
+                })
+                .collect(),
+            created_timestamp: series.created.map(timestamp),
+        }),
+        ..Default::default()
+    }
+}
+
+fn native_histogram_metric(
+    series: &SeriesSnapshot,
+    native: &NativeHistogramSnapshot,
+) -> proto::Metric {
+    let (negative_span, negative_delta) = bucket_spans(&native.negative);
+    let (mut positive_span, positive_delta) = bucket_spans(&native.positive);
+    // Without buckets, a no-op span tells a native histogram apart from a
+    // classic one.
+    if positive_span.is_empty() && negative_span.is_empty() {
+        positive_span.push(proto::BucketSpan {
+            offset: 0,
+            length: 0,
+        });
+    }
+
+    proto::Metric {
+        label: label_pairs(&series.labels, None),
+        histogram: Some(proto::Histogram {
+            sample_count: native.count,
+            sample_sum: native.sum,
+            bucket: Vec::new(),
+            created_timestamp: series.created.map(timestamp),
+            schema: native.schema,
+            zero_threshold: native.zero_threshold,
+            zero_count: native.zero_count,
+            negative_span,
+            negative_delta,
+            positive_span,
+            positive_delta,
+        }),
+        ..Default::default()
+    }
+}
+
+/// Encodes sparse `(index, count)` buckets as spans of consecutive indices
+/// and the differences between the counts of neighbouring buckets.
+fn bucket_spans(buckets: &[(i32, u64)]) -> (Vec<proto::BucketSpan>, Vec<i64>) {
+    let mut spans: Vec<proto::BucketSpan> = Vec::new();
+    let mut deltas = Vec::with_capacity(buckets.len());
+    let mut previous: Option<(i32, i64)> = None;
+    for (index, count) in buckets {
+        let count = *count as i64;
+        match previous {
+            Some((previous_index, _)) if index - previous_index == 1 => {
+                spans.last_mut().unwrap().length += 1;
+            }
+            Some((previous_index, _)) => spans.push(proto::BucketSpan {
+                offset: index - previous_index - 1,
+                length: 1,
+            }),
+            None => spans.push(proto::BucketSpan {
+                offset: *index,
+                length: 1,
+            }),
+        }
+        deltas.push(count - previous.map_or(0, |(_, previous_count)| previous_count));
+        previous = Some((*index, count));
+    }
+    (spans, deltas)
+}
+
+fn label_pairs(
+    labels: &[(String, String)],
+    extra_label: Option<(&str, String)>,
+) -> Vec<proto::LabelPair> {
+    sorted_labels(labels, &extra_label)
+        .into_iter()
+        .map(|(name, value)| proto::LabelPair {
+            name,
+            value: value.to_string(),
+        })
+        .collect()
+}
+
+fn exemplar(exemplar: &Exemplar) -> proto::Exemplar {
+    proto::Exemplar {
+        label: label_pairs(&exemplar.labels, None),
+        value: exemplar.value,
+        timestamp: Some(timestamp(exemplar.timestamp)),
+    }
+}
+
+fn timestamp(time: SystemTime) -> proto::Timestamp {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    proto::Timestamp {
+        seconds: since_epoch.as_secs() as i64,
+        nanos: since_epoch.subsec_nanos() as i32,
+    }
+}
+
+/// Formats labels as `{a="1",b="2"}`, sorted by name. Label names are
+/// sanitized, and when two labels end up with the same name only the first
+/// is kept; the extra label always wins.
+pub(crate) fn format_labels(
+    labels: &[(String, String)],
+    extra_label: Option<(&str, String)>,
+) -> String {
+    let pairs = sorted_labels(labels, &extra_label);
+    if pairs.is_empty() {
+        return "".to_string();
+    }
+    let label_pairs: Vec<String> = pairs
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(v)))
+        .collect();
+    format!("{{{}}}", label_pairs.join(","))
+}
+
+/// Gets the labels of a sample sorted by name, with sanitized names, keeping
+/// only the first of labels with the same name and letting the extra label
+/// win.
+fn sorted_labels<'a>(
+    labels: &'a [(String, String)],
+    extra_label: &'a Option<(&str, String)>,
+) -> Vec<(String, &'a str)> {
+    let mut pairs: Vec<(String, &str)> = Vec::with_capacity(labels.len() + 1);
+    if let Some((name, value)) = extra_label {
+        pairs.push((name.to_string(), value.as_str()));
+    }
+    for (name, value) in labels {
+        let name = sanitize_label_name(name);
+        if !pairs.iter().any(|(existing, _)| *existing == name) {
+            pairs.push((name, value.as_str()));
+        }
+    }
+    pairs.sort();
+    pairs
+}
+
+pub(crate) fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
     } else {
-        let label_pairs: Vec<String> = labels
+        value.to_string()
+    }
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+pub(crate) fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Makes a metric name match `[a-zA-Z_:][a-zA-Z0-9_:]*` by replacing every
+/// other character with `_`.
+pub(crate) fn sanitize_metric_name(name: &str) -> String {
+    sanitize_name(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Makes a label name match `[a-zA-Z_][a-zA-Z0-9_]*` by replacing every
+/// other character with `_`.
+pub(crate) fn sanitize_label_name(name: &str) -> String {
+    sanitize_name(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sanitize_name(name: &str, is_valid: impl Fn(char) -> bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if is_valid(c) { c } else { '_' })
+        .collect();
+    if sanitized.is_empty() || sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Unit;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn escapes_help_and_label_values() {
+        assert_eq!(escape_help("a\\b\nc \"d\""), "a\\\\b\\nc \"d\"");
+        assert_eq!(escape_label_value("a\\b\nc \"d\""), "a\\\\b\\nc \\\"d\\\"");
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(
+            sanitize_metric_name("http.requests:total"),
+            "http_requests:total"
+        );
+        assert_eq!(sanitize_metric_name("1st"), "_1st");
+        assert_eq!(sanitize_label_name("a:b-c"), "a_b_c");
+        assert_eq!(sanitize_label_name(""), "_");
+    }
+
+    #[tokio::test]
+    async fn stops_serving_on_shutdown() {
+        let registry = Arc::new(Registry::new());
+        registry.register_counter("requests_total", HashMap::new());
+        let handle = PrometheusExporter::new(registry)
+            .start("127.0.0.1:0")
+            .await
+            .unwrap();
+        let addr = handle.local_addr().unwrap();
+
+        let body = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(body.contains("requests_total 0\n"));
+
+        tokio::time::timeout(Duration::from_secs(5), handle.shutdown())
+            .await
+            .expect("the exporter stops")
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[test]
+    fn picks_the_format_with_the_highest_quality() {
+        // The default of Prometheus.
+        let accept = "application/openmetrics-text;version=1.0.0,\
+                      application/openmetrics-text;version=0.0.1;q=0.75,\
+                      text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(Format::from_accept(Some(accept)), Format::OpenMetrics);
+        // The default of Prometheus with the text format preferred.
+        let accept = "text/plain;version=0.0.4;q=1.0,\
+                      application/openmetrics-text;version=1.0.0;q=0.5";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+
+        let accept = "text/plain;q=0.5, application/openmetrics-text;q=0.9";
+        assert_eq!(Format::from_accept(Some(accept)), Format::OpenMetrics);
+        // The earliest listed wins a tie.
+        let accept = "text/plain;q=0.5, application/openmetrics-text;q=0.5";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+    }
+
+    #[test]
+    fn parses_accept_parameters() {
+        let accept = "application/openmetrics-text;version=1.0.0";
+        assert_eq!(Format::from_accept(Some(accept)), Format::OpenMetrics);
+        let accept = "Application/OpenMetrics-Text; Version=\"1.0.0\"; Q=0.5";
+        assert_eq!(Format::from_accept(Some(accept)), Format::OpenMetrics);
+        // Unknown versions are not supported.
+        let accept = "application/openmetrics-text;version=2.0.0";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+        // A quality of 0 means not acceptable.
+        let accept = "application/openmetrics-text;q=0, text/plain;q=0.1";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+        let accept = "application/openmetrics-text;Q=0";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+    }
+
+    #[test]
+    fn picks_delimited_protobuf() {
+        let accept = "application/vnd.google.protobuf;\
+                      proto=io.prometheus.client.MetricFamily;encoding=delimited";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Protobuf);
+        // The default of Prometheus with native histograms enabled.
+        let accept = "application/vnd.google.protobuf;\
+                      proto=io.prometheus.client.MetricFamily;encoding=delimited,\
+                      application/openmetrics-text;version=1.0.0;q=0.8,\
+                      application/openmetrics-text;version=0.0.1;q=0.75,\
+                      text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Protobuf);
+
+        // Other messages and encodings are not supported.
+        let accept = "application/vnd.google.protobuf";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+        let accept = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily";
+        assert_eq!(Format::from_accept(Some(accept)), Format::Text);
+        let accept = "application/vnd.google.protobuf;\
+                      proto=io.prometheus.client.MetricFamily;encoding=text,\
+                      application/openmetrics-text;q=0.5";
+        assert_eq!(Format::from_accept(Some(accept)), Format::OpenMetrics);
+    }
+
+    #[test]
+    fn falls_back_to_the_text_format() {
+        assert_eq!(Format::from_accept(None), Format::Text);
+        assert_eq!(Format::from_accept(Some("")), Format::Text);
+        assert_eq!(Format::from_accept(Some("*/*")), Format::Text);
+        assert_eq!(Format::from_accept(Some("application/json")), Format::Text);
+    }
+
+    #[test]
+    fn formats_special_values() {
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_value(0.25), "0.25");
+    }
+
+    #[test]
+    fn renders_described_families() {
+        let registry = Registry::new();
+        registry.describe("requests_total", "Requests\nserved.", None);
+        let requests = registry.counter_family("requests_total", &["path"]);
+        requests.with_label_values(&["/a\"b"]).increment_by(3);
+        registry.describe("duration_seconds", "Duration.", Some(Unit::Seconds));
+        let duration = registry.register_histogram("duration_seconds", HashMap::new());
+        duration.observe(0.3);
+
+        let output = collect_metrics(&registry);
+        assert!(output.starts_with(
+            "# HELP duration_seconds Duration.\n\
+             # TYPE duration_seconds histogram\n\
+             duration_seconds_bucket{le=\"0.005\"} 0\n"
+        ));
+        assert!(output.contains("duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(output.contains("duration_seconds_count 1\n"));
+        assert!(output.ends_with(
+            "# HELP requests_total Requests\\nserved.\n\
+             # TYPE requests_total counter\n\
+             requests_total{path=\"/a\\\"b\"} 3\n"
+        ));
+    }
+
+    #[test]
+    fn renders_meters_and_timers() {
+        let registry = Registry::new();
+        registry
+            .meter_family("jobs", &["queue"])
+            .with_label_values(&["a"])
+            .mark_n(3);
+        let timer = registry
+            .timer_family_with_buckets("latency_seconds", &[], vec![0.1, 1.0])
+            .with_label_values(&[]);
+        timer.observe_duration(Duration::from_millis(250));
+        timer.observe_duration(Duration::from_secs(2));
+
+        let output = collect_metrics(&registry);
+        assert!(output.starts_with(
+            "# TYPE jobs_total counter\n\
+             jobs_total{queue=\"a\"} 3\n\
+             # TYPE jobs_rate_mean gauge\n\
+             jobs_rate_mean{queue=\"a\"} "
+        ));
+        // The moving averages start after the first tick.
+        assert!(output.contains(
+            "# TYPE jobs_rate_1m gauge\n\
+             jobs_rate_1m{queue=\"a\"} 0\n\
+             # TYPE jobs_rate_5m gauge\n\
+             jobs_rate_5m{queue=\"a\"} 0\n\
+             # TYPE jobs_rate_15m gauge\n\
+             jobs_rate_15m{queue=\"a\"} 0\n"
+        ));
+        // Timers are histograms in seconds.
+        assert!(output.ends_with(
+            "# TYPE latency_seconds histogram\n\
+             latency_seconds_bucket{le=\"0.1\"} 0\n\
+             latency_seconds_bucket{le=\"1\"} 1\n\
+             latency_seconds_bucket{le=\"+Inf\"} 2\n\
+             latency_seconds_sum 2.25\n\
+             latency_seconds_count 2\n"
+        ));
+    }
+
+    #[test]
+    fn skips_colliding_families() {
+        let registry = Registry::new();
+        registry.register_counter("a.b", HashMap::new()).increment();
+        registry.register_gauge("a_b", HashMap::new()).set(2.0);
+
+        let output = collect_metrics(&registry);
+        assert_eq!(output.matches("# TYPE a_b").count(), 1);
+        assert_eq!(output, "# TYPE a_b counter\na_b 1\n");
+    }
+
+    /// Splits length-delimited protobuf output into its families.
+    fn decode_families(mut output: &[u8]) -> Vec<proto::MetricFamily> {
+        let mut families = Vec::new();
+        while !output.is_empty() {
+            families.push(proto::MetricFamily::decode_length_delimited(&mut output).unwrap());
+        }
+        families
+    }
+
+    #[test]
+    fn frames_protobuf_families() {
+        let registry = Registry::new();
+        registry
+            .counter_family("requests_total", &["path", "method"])
+            .with_label_values(&["/a", "GET"])
+            .increment_by(3);
+        let duration = registry
+            .histogram_family_with_buckets("duration_seconds", &[], vec![0.5])
+            .with_label_values(&[]);
+        duration.observe(0.25);
+        duration.observe(1.0);
+        registry.register_meter("jobs", HashMap::new()).mark_n(2);
+
+        let families = decode_families(&collect_metrics_protobuf(&registry));
+        let names: Vec<&str> = families.iter().map(|family| family.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "duration_seconds",
+                "jobs_total",
+                "jobs_rate_mean",
+                "jobs_rate_1m",
+                "jobs_rate_5m",
+                "jobs_rate_15m",
+                "requests_total",
+            ]
+        );
+
+        let histogram = families[0].metric[0].histogram.as_ref().unwrap();
+        assert_eq!(families[0].r#type, proto::MetricType::Histogram as i32);
+        assert_eq!(histogram.sample_count, 2);
+        assert_eq!(histogram.sample_sum, 1.25);
+        // The `+Inf` bucket is implied by the count.
+        assert_eq!(histogram.bucket.len(), 1);
+        assert_eq!(histogram.bucket[0].upper_bound, 0.5);
+        assert_eq!(histogram.bucket[0].cumulative_count, 1);
+        assert!(histogram.created_timestamp.is_some());
+
+        assert_eq!(families[1].r#type, proto::MetricType::Counter as i32);
+        assert_eq!(families[1].metric[0].counter.as_ref().unwrap().value, 2.0);
+        assert_eq!(families[2].r#type, proto::MetricType::Gauge as i32);
+
+        let requests = &families[6].metric[0];
+        let labels: Vec<(&str, &str)> = requests
+            .label
             .iter()
-            .map(|(k, v)| format!("{}=\"{}\"", k, v))
+            .map(|label| (label.name.as_str(), label.value.as_str()))
             .collect();
-        format!("{{{}}}", label_pairs.join(","))
+        assert_eq!(labels, [("method", "GET"), ("path", "/a")]);
+        assert_eq!(requests.counter.as_ref().unwrap().value, 3.0);
     }
-}
 
-fn sanitize_metric_name(name: &str) -> String {
-    name.replace('.', "_").replace('-', "_")
+    #[test]
+    fn encodes_native_histogram_spans() {
+        let (spans, deltas) = bucket_spans(&[(-2, 1), (-1, 3), (2, 2), (3, 2)]);
+        let spans: Vec<(i32, u32)> = spans
+            .iter()
+            .map(|span| (span.offset, span.length))
+            .collect();
+        assert_eq!(spans, [(-2, 2), (2, 2)]);
+        assert_eq!(deltas, [1, 2, -1, 0]);
+
+        let registry = Registry::new();
+        let native = registry
+            .native_histogram_family("latency", &[])
+            .with_label_values(&[]);
+        let families = decode_families(&collect_metrics_protobuf(&registry));
+        let histogram = families[0].metric[0].histogram.as_ref().unwrap();
+        // An empty native histogram has a no-op span.
+        assert_eq!(histogram.positive_span.len(), 1);
+        assert_eq!(histogram.positive_span[0].length, 0);
+
+        native.observe(1.5);
+        native.observe(-1.5);
+        native.observe(0.0);
+        let families = decode_families(&collect_metrics_protobuf(&registry));
+        let histogram = families[0].metric[0].histogram.as_ref().unwrap();
+        assert_eq!(histogram.sample_count, 3);
+        assert_eq!(histogram.zero_count, 1);
+        assert_eq!(histogram.positive_delta, [1]);
+        assert_eq!(histogram.negative_delta, [1]);
+        assert!(histogram.bucket.is_empty());
+    }
 }
@@ -1,8 +1,34 @@
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::exporters::http::{send_with_retries, HttpExportError, Retry};
+use crate::exporters::prometheus::CONTENT_TYPE;
 use crate::registry::Registry;
-use reqwest::Client;
+use base64::engine::general_purpose::URL_SAFE;
+use base64::Engine;
+use reqwest::header::CONTENT_TYPE as CONTENT_TYPE_HEADER;
+use reqwest::{Client, Method, RequestBuilder, Url};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// How pushed metrics replace the ones already in the group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushMethod {
+    /// `POST`: replaces only metrics with the same names as the pushed ones.
+    Post,
+    /// `PUT`: replaces all metrics in the group.
+    Put,
+}
+
+/// Credentials sent with every request.
+#[derive(Clone, Debug)]
+pub enum PushAuth {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// An error pushing to or deleting from the Pushgateway.
+pub type PushError = HttpExportError;
 
 pub struct PushgatewayExporter {
     registry: Arc<Registry>,
@@ -10,6 +36,12 @@ pub struct PushgatewayExporter {
     push_url: String,
     job: String,
     interval: Duration,
+    grouping_key: Vec<(String, String)>,
+    method: PushMethod,
+    delete_on_shutdown: bool,
+    auth: Option<PushAuth>,
+    timeout: Duration,
+    retry: Retry,
 }
 
 impl PushgatewayExporter {
@@ -20,35 +52,305 @@ impl PushgatewayExporter {
             push_url,
             job,
             interval,
+            grouping_key: Vec::new(),
+            method: PushMethod::Post,
+            delete_on_shutdown: false,
+            auth: None,
+            timeout: Duration::from_secs(10),
+            retry: Retry::default(),
         }
     }
 
-    pub async fn start(self) {
-        let mut interval = interval(self.interval);
+    /// Adds a grouping key label, e.g. `instance`, to the group pushed to.
+    pub fn with_grouping_label(mut self, name: &str, value: &str) -> Self {
+        self.grouping_key
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Sets how pushed metrics replace the ones already in the group.
+    /// Defaults to [`PushMethod::Post`].
+    pub fn with_method(mut self, method: PushMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    /// Deletes the group on shutdown instead of pushing one last time, so a
+    /// stopped service leaves no stale metrics behind.
+    pub fn with_delete_on_shutdown(mut self, delete_on_shutdown: bool) -> Self {
+        self.delete_on_shutdown = delete_on_shutdown;
+        self
+    }
+
+    /// Sends HTTP basic auth credentials with every request.
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some(PushAuth::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    /// Sends a bearer token with every request.
+    pub fn with_bearer_auth(mut self, token: &str) -> Self {
+        self.auth = Some(PushAuth::Bearer(token.to_string()));
+        self
+    }
+
+    /// Sets the timeout of each request. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how often a failed request is retried and the delay before the
+    /// first retry, which doubles on each further retry. Only connection
+    /// errors, timeouts, `429` and `5xx` responses are retried. Defaults to 3
+    /// retries starting at 500 milliseconds.
+    pub fn with_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.retry = Retry {
+            max_retries,
+            initial_backoff,
+        };
+        self
+    }
+
+    /// Pushes the metrics every interval until the returned handle is shut
+    /// down, then pushes one last time so the final values are not lost, or
+    /// deletes the group if configured to. Failed periodic pushes are logged
+    /// with `tracing`; the result of the final request is returned by the
+    /// handle.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> ExporterHandle {
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = interval(self.interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancelled.cancelled() => break,
+                }
+                if let Err(e) = self.push().await {
+                    tracing::warn!(error = %e, job = %self.job, "failed to push metrics");
+                }
+            }
+
+            let result = if self.delete_on_shutdown {
+                self.delete().await
+            } else {
+                self.push().await
+            };
+            result.map_err(ExporterError::from)
+        });
+
+        ExporterHandle::new(token, task, None)
+    }
+
+    /// Pushes the current metrics to the group once.
+    pub async fn push(&self) -> Result<(), PushError> {
+        let metrics = crate::exporters::prometheus::collect_metrics(&self.registry);
+        let method = match self.method {
+            PushMethod::Post => Method::POST,
+            PushMethod::Put => Method::PUT,
+        };
+        self.send(method, Some(metrics)).await
+    }
+
+    /// Deletes all metrics of the group.
+    pub async fn delete(&self) -> Result<(), PushError> {
+        self.send(Method::DELETE, None).await
+    }
+
+    async fn send(&self, method: Method, body: Option<String>) -> Result<(), PushError> {
+        let url = self.group_url()?;
+        send_with_retries(self.retry, || {
+            let request = self
+                .client
+                .request(method.clone(), url.clone())
+                .timeout(self.timeout);
+            let request = self.authorize(request);
+            match &body {
+                Some(body) => request
+                    .header(CONTENT_TYPE_HEADER, CONTENT_TYPE)
+                    .body(body.clone()),
+                None => request,
+            }
+        })
+        .await
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth {
+            Some(PushAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(PushAuth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
 
-        loop {
-            interval.tick().await;
-            if let Err(e) = self.push_metrics().await {
-                eprintln!("Error pushing metrics: {}", e);
+    /// Builds `<push_url>/metrics/job/<job>{/<label>/<value>}`, encoding
+    /// values that cannot be a plain path segment as
+    /// `<label>@base64/<base64url value>`.
+    fn group_url(&self) -> Result<Url, PushError> {
+        let mut url =
+            Url::parse(&self.push_url).map_err(|_| PushError::Url(self.push_url.clone()))?;
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| PushError::Url(self.push_url.clone()))?;
+            segments.pop_if_empty().push("metrics");
+            let job = std::iter::once(("job", self.job.as_str()));
+            let labels = self
+                .grouping_key
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()));
+            for (name, value) in job.chain(labels) {
+                if value.is_empty() || value.contains('/') {
+                    segments.push(&format!("{}@base64", name));
+                    segments.push(&encode_base64(value));
+                } else {
+                    segments.push(name).push(value);
+                }
             }
         }
+        Ok(url)
     }
+}
 
-    async fn push_metrics(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let metrics = self.collect_metrics();
+/// Encodes a grouping key value as base64url. An empty value is encoded as
+/// a single `=`, as the Pushgateway requires.
+fn encode_base64(value: &str) -> String {
+    if value.is_empty() {
+        "=".to_string()
+    } else {
+        URL_SAFE.encode(value)
+    }
+}
 
-        let url = format!("{}/metrics/job/{}", self.push_url, self.job);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporters::test_server::TestServer;
+    use std::collections::HashMap;
+
+    fn exporter(server: &TestServer, registry: Arc<Registry>) -> PushgatewayExporter {
+        PushgatewayExporter::new(
+            registry,
+            format!("{}/", server.url),
+            "batch".to_string(),
+            Duration::from_secs(3600),
+        )
+        .with_retries(2, Duration::from_millis(1))
+    }
 
-        let response = self.client.post(&url).body(metrics).send().await?;
+    #[test]
+    fn encodes_grouping_key() {
+        let exporter = PushgatewayExporter::new(
+            Arc::new(Registry::new()),
+            "http://localhost:9091/base".to_string(),
+            "a/b".to_string(),
+            Duration::from_secs(1),
+        )
+        .with_grouping_label("instance", "host:1")
+        .with_grouping_label("path", "/var/tmp")
+        .with_grouping_label("empty", "");
 
-        if !response.status().is_success() {
-            eprintln!("Failed to push metrics: {}", response.status());
+        assert_eq!(
+            exporter.group_url().unwrap().as_str(),
+            "http://localhost:9091/base/metrics/job@base64/YS9i/instance/host:1\
+             /path@base64/L3Zhci90bXA=/empty@base64/="
+        );
+    }
+
+    #[tokio::test]
+    async fn pushes_with_post_or_put() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        registry
+            .register_counter("jobs_total", HashMap::new())
+            .increment();
+
+        exporter(&server, registry.clone()).push().await.unwrap();
+        exporter(&server, registry)
+            .with_method(PushMethod::Put)
+            .push()
+            .await
+            .unwrap();
+
+        let received = server.received();
+        assert_eq!(received[0].method, Method::POST);
+        assert_eq!(received[1].method, Method::PUT);
+        for request in &received {
+            assert_eq!(request.path, "/metrics/job/batch");
+            assert_eq!(request.header("content-type"), Some(CONTENT_TYPE));
+            assert_eq!(request.body, "# TYPE jobs_total counter\njobs_total 1\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn deletes_on_shutdown() {
+        let server = TestServer::start().await;
+        let handle = exporter(&server, Arc::new(Registry::new()))
+            .with_grouping_label("instance", "a")
+            .with_delete_on_shutdown(true)
+            .start();
+        // The first tick pushes right away.
+        while server.received().is_empty() {
+            tokio::task::yield_now().await;
         }
+        handle.shutdown().await.unwrap();
 
-        Ok(())
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].method, Method::POST);
+        assert_eq!(received[1].method, Method::DELETE);
+        assert_eq!(received[1].path, "/metrics/job/batch/instance/a");
+        assert!(received[1].body.is_empty());
     }
 
-    fn collect_metrics(&self) -> String {
-        crate::exporters::prometheus::collect_metrics(&self.registry)
+    #[tokio::test]
+    async fn sends_auth_headers() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        exporter(&server, registry.clone())
+            .with_basic_auth("user", "secret")
+            .push()
+            .await
+            .unwrap();
+        exporter(&server, registry)
+            .with_bearer_auth("token")
+            .delete()
+            .await
+            .unwrap();
+
+        let received = server.received();
+        assert_eq!(
+            received[0].header("authorization"),
+            Some("Basic dXNlcjpzZWNyZXQ=")
+        );
+        assert_eq!(received[1].header("authorization"), Some("Bearer token"));
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_only() {
+        let server = TestServer::start().await;
+        let exporter = exporter(&server, Arc::new(Registry::new()));
+
+        server.respond_with(&[503, 500]);
+        exporter.push().await.unwrap();
+        assert_eq!(server.received().len(), 3);
+
+        server.respond_with(&[400]);
+        let error = exporter.push().await.unwrap_err();
+        assert!(matches!(error, PushError::Status { status, .. } if status == 400));
+        assert_eq!(server.received().len(), 4);
+
+        server.respond_with(&[502, 502, 502]);
+        assert!(exporter.push().await.is_err());
+        assert_eq!(server.received().len(), 7);
     }
 }
@@ -0,0 +1,618 @@
+//! An exporter pushing metrics with the Prometheus remote-write 1.0
+//! protocol, for hosts Prometheus cannot scrape.
+
+mod proto;
+
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::exporters::http::{send_with_retries, HttpExportError, Retry};
+use crate::exporters::prometheus::{format_value, sanitize_label_name, sanitize_metric_name};
+use crate::exporters::pushgateway::PushAuth;
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::metrics::Exemplar as MetricExemplar;
+use crate::registry::Registry;
+use crate::snapshot::{FamilySnapshot, MetricValue, SeriesSnapshot};
+use bytes::Bytes;
+use prost::Message;
+use proto::*;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Client, RequestBuilder};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// An error sending a write request.
+pub type RemoteWriteError = HttpExportError;
+
+/// Periodically pushes the registry to a Prometheus remote-write 1.0
+/// receiver, such as Prometheus with `--web.enable-remote-write-receiver`,
+/// Mimir, Thanos or VictoriaMetrics.
+///
+/// Each collection takes one timestamped sample of every series, named as
+/// on the Prometheus text endpoint, e.g. `_bucket`, `_sum` and `_count`
+/// series for histograms. Samples are queued on one of several shards by a
+/// hash of their series, so the samples of a series are always sent in
+/// order, and each shard sends its queue in batches. Failed batches are
+/// retried with backoff; when a shard's queue is full, new samples are
+/// dropped.
+pub struct RemoteWriteExporter {
+    registry: Arc<Registry>,
+    url: String,
+    interval: Duration,
+    client: Client,
+    auth: Option<PushAuth>,
+    labels: Vec<(String, String)>,
+    shards: usize,
+    queue_capacity: usize,
+    max_samples_per_send: usize,
+    timeout: Duration,
+    retry: Retry,
+}
+
+impl RemoteWriteExporter {
+    /// Creates an exporter pushing to the receiver at `url`, e.g.
+    /// `http://localhost:9090/api/v1/write`, every interval.
+    pub fn new(registry: Arc<Registry>, url: String, interval: Duration) -> Self {
+        RemoteWriteExporter {
+            registry,
+            url,
+            interval,
+            client: Client::new(),
+            auth: None,
+            labels: Vec::new(),
+            shards: 1,
+            queue_capacity: 10_000,
+            max_samples_per_send: 2_000,
+            timeout: Duration::from_secs(30),
+            retry: Retry::default(),
+        }
+    }
+
+    /// Adds a label, e.g. `instance`, to every series that does not have a
+    /// label with that name.
+    pub fn with_label(mut self, name: &str, value: &str) -> Self {
+        self.labels
+            .push((sanitize_label_name(name), value.to_string()));
+        self
+    }
+
+    /// Sends HTTP basic auth credentials with every request.
+    pub fn with_basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.auth = Some(PushAuth::Basic {
+            username: username.to_string(),
+            password: password.to_string(),
+        });
+        self
+    }
+
+    /// Sends a bearer token with every request.
+    pub fn with_bearer_auth(mut self, token: &str) -> Self {
+        self.auth = Some(PushAuth::Bearer(token.to_string()));
+        self
+    }
+
+    /// Sets the number of shards sending concurrently. Defaults to 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is 0.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "at least one shard is required");
+        self.shards = shards;
+        self
+    }
+
+    /// Sets the number of samples each shard queues before dropping new
+    /// ones. Defaults to 10000.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "the queue capacity must not be 0");
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Sets the maximum number of samples sent in one request. Defaults to
+    /// 2000.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_samples` is 0.
+    pub fn with_max_samples_per_send(mut self, max_samples: usize) -> Self {
+        assert!(max_samples > 0, "the batch size must not be 0");
+        self.max_samples_per_send = max_samples;
+        self
+    }
+
+    /// Sets the timeout of each request. Defaults to 30 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how often a failed request is retried and the delay before the
+    /// first retry, which doubles on each further retry. Only connection
+    /// errors, timeouts, `429` and `5xx` responses are retried. Defaults to 3
+    /// retries starting at 500 milliseconds.
+    pub fn with_retries(mut self, max_retries: u32, initial_backoff: Duration) -> Self {
+        self.retry = Retry {
+            max_retries,
+            initial_backoff,
+        };
+        self
+    }
+
+    /// Collects the metrics every interval until the returned handle is shut
+    /// down, then collects one last time and waits for the queues to be
+    /// sent. Failed requests are logged with `tracing`; the handle returns
+    /// the error of a shard whose last request failed.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> ExporterHandle {
+        let exporter = Arc::new(self);
+        let (queues, shards): (Vec<_>, Vec<_>) = (0..exporter.shards)
+            .map(|_| {
+                let (queue, samples) = mpsc::channel(exporter.queue_capacity);
+                (queue, tokio::spawn(exporter.clone().run_shard(samples)))
+            })
+            .unzip();
+
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = interval(exporter.interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancelled.cancelled() => break,
+                }
+                exporter.enqueue(&queues);
+            }
+
+            exporter.enqueue(&queues);
+            // Closing the queues stops the shards once they are sent.
+            drop(queues);
+            finish(shards).await.map_err(ExporterError::from)
+        });
+
+        ExporterHandle::new(token, task, None)
+    }
+
+    /// Sends the current metrics once, bypassing the queues, e.g. at the end
+    /// of a batch job.
+    pub async fn write(&self) -> Result<(), RemoteWriteError> {
+        let mut shards = vec![Vec::new(); self.shards];
+        for series in self.collect() {
+            shards[self.shard(&series)].push(series);
+        }
+        let sends = shards.into_iter().map(|series| async move {
+            for batch in series.chunks(self.max_samples_per_send) {
+                self.send(batch.to_vec()).await?;
+            }
+            Ok(())
+        });
+        futures::future::join_all(sends).await.into_iter().collect()
+    }
+
+    fn enqueue(&self, queues: &[mpsc::Sender<TimeSeries>]) {
+        let mut dropped = 0;
+        for series in self.collect() {
+            let shard = self.shard(&series);
+            if let Err(TrySendError::Full(_)) = queues[shard].try_send(series) {
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            tracing::warn!(dropped, url = %self.url, "remote-write queue full, dropped samples");
+        }
+    }
+
+    async fn run_shard(
+        self: Arc<Self>,
+        mut samples: mpsc::Receiver<TimeSeries>,
+    ) -> Result<(), RemoteWriteError> {
+        let mut result = Ok(());
+        let mut batch = Vec::with_capacity(self.max_samples_per_send);
+        while samples
+            .recv_many(&mut batch, self.max_samples_per_send)
+            .await
+            > 0
+        {
+            result = self.send(std::mem::take(&mut batch)).await;
+            if let Err(e) = &result {
+                tracing::warn!(error = %e, url = %self.url, "failed to send remote-write batch");
+            }
+        }
+        result
+    }
+
+    async fn send(&self, timeseries: Vec<TimeSeries>) -> Result<(), RemoteWriteError> {
+        let body = WriteRequest { timeseries }.encode_to_vec();
+        let body = Bytes::from(
+            snap::raw::Encoder::new()
+                .compress_vec(&body)
+                .expect("snappy compression of a buffer cannot fail"),
+        );
+        send_with_retries(self.retry, || {
+            let request = self
+                .client
+                .post(&self.url)
+                .timeout(self.timeout)
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .header(CONTENT_ENCODING, "snappy")
+                .header(USER_AGENT, concat!("metrix/", env!("CARGO_PKG_VERSION")))
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(body.clone());
+            self.authorize(request)
+        })
+        .await
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.auth {
+            Some(PushAuth::Basic { username, password }) => {
+                request.basic_auth(username, Some(password))
+            }
+            Some(PushAuth::Bearer(token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn shard(&self, series: &TimeSeries) -> usize {
+        let mut hasher = DefaultHasher::new();
+        series.labels.hash(&mut hasher);
+        (hasher.finish() % self.shards as u64) as usize
+    }
+
+    /// Converts a registry snapshot into one series per sample, timestamped
+    /// now.
+    fn collect(&self) -> Vec<TimeSeries> {
+        let timestamp = unix_millis(SystemTime::now());
+        let mut collector = Collector {
+            labels: &self.labels,
+            timestamp,
+            series: Vec::new(),
+        };
+        for family in self.registry.snapshot() {
+            collector.family(&family);
+        }
+        collector.series
+    }
+}
+
+/// Waits for the shards to send their queues, returning the last error.
+async fn finish(
+    shards: Vec<JoinHandle<Result<(), RemoteWriteError>>>,
+) -> Result<(), RemoteWriteError> {
+    let mut result = Ok(());
+    for shard in shards {
+        match shard.await {
+            Ok(Err(e)) => result = Err(e),
+            Err(e) => tracing::error!(error = %e, "remote-write shard panicked"),
+            Ok(Ok(())) => {}
+        }
+    }
+    result
+}
+
+/// Builds the series of a snapshot, named and labelled like the samples of
+/// the Prometheus text format.
+struct Collector<'a> {
+    labels: &'a [(String, String)],
+    timestamp: i64,
+    series: Vec<TimeSeries>,
+}
+
+impl Collector<'_> {
+    fn family(&mut self, family: &FamilySnapshot) {
+        let name = sanitize_metric_name(&family.name);
+        for series in &family.series {
+            match &series.value {
+                MetricValue::Counter(total) => {
+                    self.sample(&name, &series.labels, None, *total as f64)
+                }
+                MetricValue::Gauge(value) => self.sample(&name, &series.labels, None, *value),
+                MetricValue::Histogram(histogram) | MetricValue::Timer(histogram) => {
+                    self.histogram(&name, series, histogram);
+                }
+                // Remote-write 1.0 has no native histograms.
+                MetricValue::NativeHistogram(native) => {
+                    self.histogram(&name, series, &native.to_classic());
+                }
+                MetricValue::Summary(summary) => {
+                    let labels: Vec<(String, String)> = series
+                        .labels
+                        .iter()
+                        .filter(|(name, _)| sanitize_label_name(name) != "quantile")
+                        .cloned()
+                        .collect();
+                    for (quantile, value) in &summary.quantiles {
+                        let quantile = ("quantile", format_value(*quantile));
+                        self.sample(&name, &labels, Some(quantile), *value);
+                    }
+                    self.sample(&format!("{}_sum", name), &labels, None, summary.sum);
+                    let count = summary.count as f64;
+                    self.sample(&format!("{}_count", name), &labels, None, count);
+                }
+                MetricValue::Meter(meter) => {
+                    let base = name.strip_suffix("_total").unwrap_or(&name);
+                    let count = meter.count as f64;
+                    self.sample(&format!("{}_total", base), &series.labels, None, count);
+                    for (window, rate) in meter.rates() {
+                        self.sample(
+                            &format!("{}_rate_{}", base, window),
+                            &series.labels,
+                            None,
+                            rate,
+                        );
+                    }
+                }
+                MetricValue::Info => {
+                    let info_name = format!("{}_info", name.strip_suffix("_info").unwrap_or(&name));
+                    self.sample(&info_name, &series.labels, None, 1.0);
+                }
+                MetricValue::StateSet(states) => {
+                    for (state, enabled) in states {
+                        let label = (name.as_str(), state.clone());
+                        let value = if *enabled { 1.0 } else { 0.0 };
+                        self.sample(&name, &series.labels, Some(label), value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn histogram(&mut self, name: &str, series: &SeriesSnapshot, histogram: &HistogramSnapshot) {
+        // `le` is reserved for the bucket bound, so drop any label with that
+        // name from every sample of the series.
+        let labels: Vec<(String, String)> = series
+            .labels
+            .iter()
+            .filter(|(name, _)| sanitize_label_name(name) != "le")
+            .cloned()
+            .collect();
+        let bucket_name = format!("{}_bucket", name);
+        for (index, (bound, count)) in histogram.buckets.iter().enumerate() {
+            let le = ("le", format_value(*bound));
+            self.sample(&bucket_name, &labels, Some(le), *count as f64);
+            if let Some(Some(exemplar)) = histogram.exemplars.get(index) {
+                let last = self.series.last_mut().unwrap();
+                last.exemplars.push(exemplar_proto(exemplar));
+            }
+        }
+        self.sample(&format!("{}_sum", name), &labels, None, histogram.sum);
+        let count = histogram.count as f64;
+        self.sample(&format!("{}_count", name), &labels, None, count);
+    }
+
+    fn sample(
+        &mut self,
+        name: &str,
+        series_labels: &[(String, String)],
+        extra_label: Option<(&str, String)>,
+        value: f64,
+    ) {
+        // The name and the extra label win over series labels, which win
+        // over the exporter's labels.
+        let mut labels = vec![Label {
+            name: "__name__".to_string(),
+            value: name.to_string(),
+        }];
+        if let Some((name, value)) = extra_label {
+            labels.push(Label {
+                name: name.to_string(),
+                value,
+            });
+        }
+        let series_labels = series_labels
+            .iter()
+            .map(|(name, value)| (sanitize_label_name(name), value.clone()));
+        for (name, value) in series_labels.chain(self.labels.iter().cloned()) {
+            // Empty label values are the same as missing labels.
+            if !value.is_empty() && !labels.iter().any(|label| label.name == name) {
+                labels.push(Label { name, value });
+            }
+        }
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+        self.series.push(TimeSeries {
+            labels,
+            samples: vec![Sample {
+                value,
+                timestamp: self.timestamp,
+            }],
+            exemplars: Vec::new(),
+        });
+    }
+}
+
+fn exemplar_proto(exemplar: &MetricExemplar) -> Exemplar {
+    Exemplar {
+        labels: exemplar
+            .labels
+            .iter()
+            .map(|(name, value)| Label {
+                name: sanitize_label_name(name),
+                value: value.clone(),
+            })
+            .collect(),
+        value: exemplar.value,
+        timestamp: unix_millis(exemplar.timestamp),
+    }
+}
+
+fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporters::test_server::{Received, TestServer};
+
+    fn exporter(server: &TestServer, registry: Arc<Registry>) -> RemoteWriteExporter {
+        RemoteWriteExporter::new(
+            registry,
+            format!("{}/api/v1/write", server.url),
+            Duration::from_secs(3600),
+        )
+        .with_retries(2, Duration::from_millis(1))
+    }
+
+    /// Decodes a received write request into its series, as label lists
+    /// with their value.
+    fn decode(received: &Received) -> Vec<(Vec<(String, String)>, f64)> {
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(&received.body)
+            .unwrap();
+        WriteRequest::decode(&body[..])
+            .unwrap()
+            .timeseries
+            .into_iter()
+            .map(|series| {
+                assert_eq!(series.samples.len(), 1);
+                let labels = series
+                    .labels
+                    .into_iter()
+                    .map(|label| (label.name, label.value))
+                    .collect();
+                (labels, series.samples[0].value)
+            })
+            .collect()
+    }
+
+    fn labels(labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_snappy_compressed_series() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        registry
+            .counter_family("requests_total", &["method", "instance", "empty"])
+            .with_label_values(&["GET", "a", ""])
+            .increment_by(2);
+        let histogram = registry
+            .histogram_family_with_buckets("latency", &["le"], vec![0.5])
+            .with_label_values(&["dropped"]);
+        histogram.observe(0.25);
+        histogram.observe(2.0);
+
+        exporter(&server, registry)
+            .with_label("instance", "host:1")
+            .with_label("job", "batch")
+            .write()
+            .await
+            .unwrap();
+
+        let received = server.received();
+        assert_eq!(received.len(), 1);
+        let request = &received[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/api/v1/write");
+        assert_eq!(
+            request.header("content-type"),
+            Some("application/x-protobuf")
+        );
+        assert_eq!(request.header("content-encoding"), Some("snappy"));
+        assert_eq!(
+            request.header("x-prometheus-remote-write-version"),
+            Some("0.1.0")
+        );
+
+        let host = ("instance", "host:1");
+        let job = ("job", "batch");
+        assert_eq!(
+            decode(request),
+            [
+                (
+                    labels(&[("__name__", "latency_bucket"), host, job, ("le", "0.5")]),
+                    1.0
+                ),
+                (
+                    labels(&[("__name__", "latency_bucket"), host, job, ("le", "+Inf")]),
+                    2.0
+                ),
+                (labels(&[("__name__", "latency_sum"), host, job]), 2.25),
+                (labels(&[("__name__", "latency_count"), host, job]), 2.0),
+                (
+                    labels(&[
+                        ("__name__", "requests_total"),
+                        ("instance", "a"),
+                        job,
+                        ("method", "GET"),
+                    ]),
+                    2.0
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_failed_requests() {
+        let server = TestServer::start().await;
+        server.respond_with(&[503, 400]);
+        let registry = Arc::new(Registry::new());
+        registry
+            .gauge_family("temperature", &[])
+            .with_label_values(&[])
+            .set(21.5);
+
+        let result = exporter(&server, registry).write().await;
+        assert!(matches!(
+            result,
+            Err(HttpExportError::Status { status, .. }) if status == 400
+        ));
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].body, received[1].body);
+        assert_eq!(
+            decode(&received[1]),
+            [(labels(&[("__name__", "temperature")]), 21.5)]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_the_queues_on_shutdown() {
+        let server = TestServer::start().await;
+        let registry = Arc::new(Registry::new());
+        let counter = registry
+            .counter_family("events_total", &[])
+            .with_label_values(&[]);
+        counter.increment();
+
+        let handle = exporter(&server, registry)
+            .with_shards(2)
+            .with_max_samples_per_send(1)
+            .start();
+        // The first tick collects right away.
+        while server.received().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        handle.shutdown().await.unwrap();
+
+        // The first tick and the final collection both take a sample.
+        let received = server.received();
+        assert_eq!(received.len(), 2);
+        for request in &received {
+            assert_eq!(
+                decode(request),
+                [(labels(&[("__name__", "events_total")]), 1.0)]
+            );
+        }
+    }
+}
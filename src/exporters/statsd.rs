@@ -0,0 +1,641 @@
+use crate::exporters::handle::{ExporterError, ExporterHandle};
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::registry::Registry;
+use crate::snapshot::{MetricValue, SeriesSnapshot};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+use tokio::time::interval;
+use tokio_util::sync::CancellationToken;
+
+/// The dialect of the StatsD protocol.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatsdFlavor {
+    /// Plain StatsD, without tags: label values are appended to the metric
+    /// name as dotted segments, ordered by label name.
+    Statsd,
+    /// DogStatsD, with labels sent as `|#name:value` tags.
+    DogStatsd,
+}
+
+/// The StatsD type histograms are sent as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramType {
+    /// `|h`, aggregated by the agent.
+    Histogram,
+    /// `|d`, a DogStatsD distribution, aggregated server-side.
+    Distribution,
+}
+
+/// Periodically flushes the registry to a StatsD or DogStatsD agent over
+/// UDP or, for addresses of the form `unix:///path/to/socket`, a Unix
+/// datagram socket.
+///
+/// Counters and meter counts are sent as the change since the previous
+/// flush (`|c`) and gauges as their value (`|g`); info and state set series
+/// are sent as gauges. Timers (`|ms`) and histograms (`|h` or `|d`) are
+/// sent per bucket that received observations since the previous flush: one
+/// value in the bucket's range, with a sample rate of one over the number
+/// of observations so that the agent counts each of them. Summaries cannot
+/// be aggregated by the agent, so their quantiles are sent as gauges with a
+/// `quantile` label, and their count as a `_count` counter.
+///
+/// With a sample rate below 1, see [`StatsdExporter::with_sample_rate`],
+/// counter and histogram lines are only sent with that probability.
+pub struct StatsdExporter {
+    registry: Arc<Registry>,
+    addr: String,
+    interval: Duration,
+    flavor: StatsdFlavor,
+    histogram_type: HistogramType,
+    prefix: Option<String>,
+    max_packet_size: Option<usize>,
+    sample_rate: f64,
+    socket: tokio::sync::Mutex<Option<Socket>>,
+    previous: Mutex<HashMap<SeriesKey, Previous>>,
+}
+
+type SeriesKey = (String, Vec<(String, String)>);
+
+enum Previous {
+    Count(u64),
+    Histogram(HistogramSnapshot),
+}
+
+impl StatsdExporter {
+    /// Creates an exporter sending to the agent at `addr`, e.g.
+    /// `127.0.0.1:8125` or `unix:///var/run/datadog/dsd.socket`, every
+    /// interval.
+    pub fn new(registry: Arc<Registry>, addr: String, interval: Duration) -> Self {
+        StatsdExporter {
+            registry,
+            addr,
+            interval,
+            flavor: StatsdFlavor::DogStatsd,
+            histogram_type: HistogramType::Histogram,
+            prefix: None,
+            max_packet_size: None,
+            sample_rate: 1.0,
+            socket: tokio::sync::Mutex::new(None),
+            previous: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets the protocol dialect. Defaults to [`StatsdFlavor::DogStatsd`].
+    pub fn with_flavor(mut self, flavor: StatsdFlavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    /// Sets the type histograms are sent as. Defaults to
+    /// [`HistogramType::Histogram`].
+    pub fn with_histogram_type(mut self, histogram_type: HistogramType) -> Self {
+        self.histogram_type = histogram_type;
+        self
+    }
+
+    /// Prepends `prefix.` to every metric name.
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.trim_end_matches('.').to_string());
+        self
+    }
+
+    /// Sets the maximum size of a datagram, into which as many lines as fit
+    /// are batched. Defaults to 1432 bytes over UDP, to fit a common MTU,
+    /// and 8192 bytes over a Unix socket.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = Some(max_packet_size);
+        self
+    }
+
+    /// Sends each counter, timer and histogram line with the given
+    /// probability, tagged with the rate (`|@0.1`) so that the agent scales
+    /// the sent values up, to reduce the traffic to the agent. Gauges are
+    /// always sent. Defaults to 1, sending every line.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the rate is not above 0 and at most 1.
+    pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+        assert!(
+            sample_rate > 0.0 && sample_rate <= 1.0,
+            "the sample rate must be above 0 and at most 1"
+        );
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    /// Flushes the metrics every interval until the returned handle is shut
+    /// down, then flushes one last time so the final changes are not lost.
+    /// Failed periodic flushes are logged with `tracing`; the result of the
+    /// final flush is returned by the handle.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn start(self) -> ExporterHandle {
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = interval(self.interval);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancelled.cancelled() => break,
+                }
+                if let Err(e) = self.flush().await {
+                    tracing::warn!(error = %e, addr = %self.addr, "failed to flush metrics to StatsD");
+                }
+            }
+
+            self.flush().await.map_err(ExporterError::from)
+        });
+
+        ExporterHandle::new(token, task, None)
+    }
+
+    /// Sends the changes since the previous flush once. Changes are counted
+    /// from the previous successful flush, so those of a failed flush are
+    /// sent with the next one.
+    pub async fn flush(&self) -> io::Result<()> {
+        let (lines, next) = self.collect_lines();
+        if !lines.is_empty() {
+            self.send(&lines).await?;
+        }
+        *self.previous.lock().unwrap() = next;
+        Ok(())
+    }
+
+    async fn send(&self, lines: &[String]) -> io::Result<()> {
+        let mut socket = self.socket.lock().await;
+        if socket.is_none() {
+            *socket = Some(Socket::connect(&self.addr).await?);
+        }
+        let connected = socket.as_ref().unwrap();
+        let max_packet_size = self
+            .max_packet_size
+            .unwrap_or_else(|| connected.default_packet_size());
+        for packet in batch(lines, max_packet_size) {
+            if let Err(e) = connected.send(packet.as_bytes()).await {
+                // Reconnect on the next flush, e.g. after the agent restarted.
+                *socket = None;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the lines of this flush from a registry snapshot, and the
+    /// values the next deltas are computed from once they have been sent.
+    fn collect_lines(&self) -> (Vec<String>, HashMap<SeriesKey, Previous>) {
+        let previous = self.previous.lock().unwrap();
+        let mut next = HashMap::new();
+        let mut lines = Vec::new();
+
+        for family in self.registry.snapshot() {
+            for series in &family.series {
+                let key = (family.name.clone(), series.labels.clone());
+                match &series.value {
+                    MetricValue::Counter(total) => {
+                        let delta = count_delta(previous.get(&key), *total);
+                        next.insert(key, Previous::Count(*total));
+                        self.push_count(&mut lines, &family.name, series, delta);
+                    }
+                    MetricValue::Meter(meter) => {
+                        let delta = count_delta(previous.get(&key), meter.count);
+                        next.insert(key, Previous::Count(meter.count));
+                        self.push_count(&mut lines, &family.name, series, delta);
+                    }
+                    MetricValue::Gauge(value) => {
+                        self.push_gauge(&mut lines, &family.name, series, &[], *value);
+                    }
+                    MetricValue::Histogram(histogram) => {
+                        self.push_histogram(
+                            &mut lines,
+                            &family.name,
+                            series,
+                            previous.get(&key),
+                            histogram,
+                            self.histogram_kind(),
+                            1.0,
+                        );
+                        next.insert(key, Previous::Histogram(histogram.clone()));
+                    }
+                    MetricValue::NativeHistogram(native) => {
+                        let histogram = native.to_classic();
+                        self.push_histogram(
+                            &mut lines,
+                            &family.name,
+                            series,
+                            previous.get(&key),
+                            &histogram,
+                            self.histogram_kind(),
+                            1.0,
+                        );
+                        next.insert(key, Previous::Histogram(histogram));
+                    }
+                    MetricValue::Timer(histogram) => {
+                        // Timers are recorded in seconds; StatsD expects milliseconds.
+                        self.push_histogram(
+                            &mut lines,
+                            &family.name,
+                            series,
+                            previous.get(&key),
+                            histogram,
+                            "ms",
+                            1000.0,
+                        );
+                        next.insert(key, Previous::Histogram(histogram.clone()));
+                    }
+                    MetricValue::Summary(summary) => {
+                        let delta = count_delta(previous.get(&key), summary.count);
+                        next.insert(key, Previous::Count(summary.count));
+                        let name = format!("{}_count", family.name);
+                        self.push_count(&mut lines, &name, series, delta);
+                        for (quantile, value) in &summary.quantiles {
+                            let extra = [("quantile".to_string(), quantile.to_string())];
+                            self.push_gauge(&mut lines, &family.name, series, &extra, *value);
+                        }
+                    }
+                    MetricValue::Info => {
+                        self.push_gauge(&mut lines, &family.name, series, &[], 1.0);
+                    }
+                    MetricValue::StateSet(states) => {
+                        for (state, enabled) in states {
+                            let extra = [(family.name.clone(), state.clone())];
+                            let value = if *enabled { 1.0 } else { 0.0 };
+                            self.push_gauge(&mut lines, &family.name, series, &extra, value);
+                        }
+                    }
+                }
+            }
+        }
+
+        (lines, next)
+    }
+
+    fn histogram_kind(&self) -> &'static str {
+        match self.histogram_type {
+            HistogramType::Histogram => "h",
+            HistogramType::Distribution => "d",
+        }
+    }
+
+    /// Pushes a counter line for the change of a total, unless it is zero
+    /// or the line is left out by sampling.
+    fn push_count(&self, lines: &mut Vec<String>, name: &str, series: &SeriesSnapshot, delta: u64) {
+        if delta > 0 && sampled(self.sample_rate) {
+            let sample_rate = (self.sample_rate < 1.0).then_some(self.sample_rate);
+            lines.push(self.line(name, series, &[], &delta.to_string(), "c", sample_rate));
+        }
+    }
+
+    fn push_gauge(
+        &self,
+        lines: &mut Vec<String>,
+        name: &str,
+        series: &SeriesSnapshot,
+        extra_labels: &[(String, String)],
+        value: f64,
+    ) {
+        if !value.is_finite() {
+            return;
+        }
+        // Plain StatsD reads a signed value as a change to the gauge, so a
+        // negative gauge has to be reset to zero first.
+        if value < 0.0 && self.flavor == StatsdFlavor::Statsd {
+            lines.push(self.line(name, series, extra_labels, "0", "g", None));
+        }
+        lines.push(self.line(name, series, extra_labels, &value.to_string(), "g", None));
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push_histogram(
+        &self,
+        lines: &mut Vec<String>,
+        name: &str,
+        series: &SeriesSnapshot,
+        previous: Option<&Previous>,
+        histogram: &HistogramSnapshot,
+        kind: &str,
+        scale: f64,
+    ) {
+        // A count lower than before means the series was reset.
+        let previous = match previous {
+            Some(Previous::Histogram(previous)) if previous.count <= histogram.count => {
+                Some(previous)
+            }
+            _ => None,
+        };
+        // The buckets of native histograms change between flushes, so the
+        // previous cumulative count at a bound is the one at the highest
+        // previous bound not above it.
+        let previous_cumulative = |bound: f64| {
+            previous
+                .and_then(|previous| {
+                    previous
+                        .buckets
+                        .iter()
+                        .take_while(|(previous_bound, _)| *previous_bound <= bound)
+                        .last()
+                })
+                .map_or(0, |(_, cumulative)| *cumulative)
+        };
+
+        let mut lower = f64::NEG_INFINITY;
+        let mut below = 0;
+        for (upper, cumulative) in &histogram.buckets {
+            let new = cumulative.saturating_sub(previous_cumulative(*upper));
+            let observed = new.saturating_sub(below);
+            below = below.max(new);
+            if observed > 0 && sampled(self.sample_rate) {
+                let value = bucket_value(lower, *upper, histogram.min, histogram.max) * scale;
+                let sample_rate = self.sample_rate / observed as f64;
+                let sample_rate = (sample_rate < 1.0).then_some(sample_rate);
+                lines.push(self.line(name, series, &[], &value.to_string(), kind, sample_rate));
+            }
+            lower = *upper;
+        }
+    }
+
+    fn line(
+        &self,
+        name: &str,
+        series: &SeriesSnapshot,
+        extra_labels: &[(String, String)],
+        value: &str,
+        kind: &str,
+        sample_rate: Option<f64>,
+    ) -> String {
+        let labels = series.labels.iter().chain(extra_labels);
+        let mut line = match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, sanitize(name)),
+            None => sanitize(name),
+        };
+        if self.flavor == StatsdFlavor::Statsd {
+            for (_, label_value) in labels.clone() {
+                line.push('.');
+                line.push_str(&sanitize(label_value));
+            }
+        }
+        line.push_str(&format!(":{}|{}", value, kind));
+        if let Some(sample_rate) = sample_rate {
+            line.push_str(&format!("|@{}", sample_rate));
+        }
+        if self.flavor == StatsdFlavor::DogStatsd {
+            let tags: Vec<String> = labels
+                .map(|(label_name, label_value)| {
+                    format!("{}:{}", sanitize_tag(label_name), sanitize_tag(label_value))
+                })
+                .collect();
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        line
+    }
+}
+
+/// A connected datagram socket.
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl Socket {
+    async fn connect(addr: &str) -> io::Result<Socket> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix("unix://") {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(path)?;
+            return Ok(Socket::Unix(socket));
+        }
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(addr).await?;
+        Ok(Socket::Udp(socket))
+    }
+
+    async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            Socket::Udp(socket) => socket.send(packet).await?,
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(packet).await?,
+        };
+        Ok(())
+    }
+
+    fn default_packet_size(&self) -> usize {
+        match self {
+            Socket::Udp(_) => 1432,
+            #[cfg(unix)]
+            Socket::Unix(_) => 8192,
+        }
+    }
+}
+
+/// Joins lines with newlines into packets of at most `max_packet_size`
+/// bytes. A line longer than that is sent in a packet of its own.
+fn batch(lines: &[String], max_packet_size: usize) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_packet_size {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// Gets the change of a total since the previous flush. A total lower than
+/// before means the series was reset.
+fn count_delta(previous: Option<&Previous>, total: u64) -> u64 {
+    match previous {
+        Some(Previous::Count(previous)) if *previous <= total => total - previous,
+        _ => total,
+    }
+}
+
+/// Returns whether a line is sent, with the probability `sample_rate`.
+fn sampled(sample_rate: f64) -> bool {
+    if sample_rate >= 1.0 {
+        return true;
+    }
+    // Every `RandomState` is seeded differently, which is random enough to
+    // sample with and needs no extra dependency.
+    let random = RandomState::new().build_hasher().finish();
+    (random as f64 / u64::MAX as f64) < sample_rate
+}
+
+/// Gets the value standing for the observations of a bucket: the middle of
+/// its range, narrowed to the observed minimum and maximum.
+fn bucket_value(lower: f64, upper: f64, min: f64, max: f64) -> f64 {
+    let lower = lower.max(min);
+    let upper = upper.min(max);
+    if lower > upper {
+        // Only possible with concurrent updates; the range is then unknown.
+        return upper;
+    }
+    lower + (upper - lower) / 2.0
+}
+
+/// Replaces the characters that delimit the parts of a line.
+fn sanitize(name: &str) -> String {
+    name.replace([':', '|', '@', '\n'], "_")
+}
+
+/// Replaces the characters that delimit tags.
+fn sanitize_tag(tag: &str) -> String {
+    tag.replace([',', '|', '#', '\n'], "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn agent() -> (UdpSocket, String) {
+        let agent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = agent.local_addr().unwrap().to_string();
+        (agent, addr)
+    }
+
+    async fn receive(agent: &UdpSocket) -> String {
+        let mut buf = [0; 2048];
+        let len = agent.recv(&mut buf).await.unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_counter_deltas_gauges_and_timers() {
+        let registry = Arc::new(Registry::new());
+        let requests = registry.counter_family("requests_total", &["method"]);
+        let gauge = registry
+            .gauge_family("temperature", &[])
+            .with_label_values(&[]);
+        let timer = registry.timer_family("latency", &[]).with_label_values(&[]);
+        let (agent, addr) = agent().await;
+        let exporter =
+            StatsdExporter::new(registry, addr, Duration::from_secs(60)).with_prefix("app.");
+
+        requests.with_label_values(&["GET"]).increment_by(3);
+        gauge.set(-1.5);
+        exporter.flush().await.unwrap();
+        assert_eq!(
+            receive(&agent).await,
+            "app.requests_total:3|c|#method:GET\napp.temperature:-1.5|g"
+        );
+
+        requests.with_label_values(&["GET"]).increment_by(2);
+        timer.observe_duration(Duration::from_millis(20));
+        exporter.flush().await.unwrap();
+        let packet = receive(&agent).await;
+        let mut lines: Vec<&str> = packet.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "app.latency:20|ms",
+                "app.requests_total:2|c|#method:GET",
+                "app.temperature:-1.5|g",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn formats_plain_statsd_lines() {
+        let registry = Arc::new(Registry::new());
+        let gauge = registry.gauge_family("free_bytes", &["disk", "host"]);
+        let (agent, addr) = agent().await;
+        let exporter = StatsdExporter::new(registry, addr, Duration::from_secs(60))
+            .with_flavor(StatsdFlavor::Statsd);
+
+        gauge.with_label_values(&["sd|a", "h1"]).set(-2.0);
+        exporter.flush().await.unwrap();
+        assert_eq!(
+            receive(&agent).await,
+            "free_bytes.sd_a.h1:0|g\nfree_bytes.sd_a.h1:-2|g"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_sample_rates() {
+        let registry = Arc::new(Registry::new());
+        let counter = registry
+            .counter_family("events_total", &[])
+            .with_label_values(&[]);
+        let histogram = registry
+            .histogram_family("size", &[])
+            .with_label_values(&[]);
+        let (agent, addr) = agent().await;
+        // A rate just below 1 keeps every line in practice, so the
+        // annotations can be checked.
+        let exporter = StatsdExporter::new(registry, addr, Duration::from_secs(60))
+            .with_histogram_type(HistogramType::Distribution)
+            .with_sample_rate(0.999_999_999);
+
+        counter.increment();
+        histogram.observe(0.5);
+        histogram.observe(0.5);
+        exporter.flush().await.unwrap();
+        assert_eq!(
+            receive(&agent).await,
+            "events_total:1|c|@0.999999999\nsize:0.5|d|@0.4999999995"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "the sample rate must be above 0 and at most 1")]
+    fn rejects_invalid_sample_rates() {
+        let registry = Arc::new(Registry::new());
+        let _ = StatsdExporter::new(registry, String::new(), Duration::from_secs(60))
+            .with_sample_rate(0.0);
+    }
+
+    #[test]
+    fn batches_lines_into_packets() {
+        let lines = ["a:1|c", "b:2|c", "c:3|c"].map(String::from);
+        assert_eq!(batch(&lines, 11), ["a:1|c\nb:2|c", "c:3|c"]);
+        assert_eq!(batch(&lines, 3), ["a:1|c", "b:2|c", "c:3|c"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn keeps_deltas_of_failed_flushes() {
+        let dir = std::env::temp_dir().join(format!("metrix-statsd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("agent.socket");
+        let _ = std::fs::remove_file(&path);
+
+        let registry = Arc::new(Registry::new());
+        let counter = registry
+            .counter_family("events_total", &[])
+            .with_label_values(&[]);
+        let addr = format!("unix://{}", path.display());
+        let exporter = StatsdExporter::new(registry, addr, Duration::from_secs(60));
+
+        counter.increment_by(2);
+        assert!(exporter.flush().await.is_err());
+
+        let agent = UnixDatagram::bind(&path).unwrap();
+        counter.increment();
+        exporter.flush().await.unwrap();
+        let mut buf = [0; 2048];
+        let len = agent.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"events_total:3|c");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! A local HTTP server recording the requests the push exporters send, for
+//! their tests.
+
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, Method, StatusCode, Uri};
+use axum::Router;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+/// A request received by the [`TestServer`].
+#[derive(Clone, Debug)]
+pub(crate) struct Received {
+    pub method: Method,
+    /// The path as sent, still percent-encoded.
+    pub path: String,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl Received {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+}
+
+#[derive(Clone, Default)]
+struct Shared {
+    received: Arc<Mutex<Vec<Received>>>,
+    statuses: Arc<Mutex<VecDeque<StatusCode>>>,
+}
+
+/// Answers every request with `200 OK`, or with the statuses queued by
+/// [`TestServer::respond_with`] first.
+pub(crate) struct TestServer {
+    pub url: String,
+    shared: Shared,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        let shared = Shared::default();
+        let app = Router::new().fallback(record).with_state(shared.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        TestServer { url, shared }
+    }
+
+    /// Answers the next requests with the given statuses, in order.
+    pub fn respond_with(&self, statuses: &[u16]) {
+        self.shared.statuses.lock().unwrap().extend(
+            statuses
+                .iter()
+                .map(|status| StatusCode::from_u16(*status).unwrap()),
+        );
+    }
+
+    /// Gets the requests received so far.
+    pub fn received(&self) -> Vec<Received> {
+        self.shared.received.lock().unwrap().clone()
+    }
+}
+
+async fn record(
+    State(shared): State<Shared>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    shared.received.lock().unwrap().push(Received {
+        method,
+        path: uri.path().to_string(),
+        headers,
+        body,
+    });
+    shared
+        .statuses
+        .lock()
+        .unwrap()
+        .pop_front()
+        .unwrap_or(StatusCode::OK)
+}
@@ -2,6 +2,8 @@ pub mod exporters;
 pub mod macros;
 pub mod metrics;
 pub mod middleware;
+pub mod recorder;
 pub mod registry;
+pub mod snapshot;
 pub mod tracing_integration;
 pub mod utils;
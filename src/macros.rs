@@ -5,6 +5,11 @@
 /// # Examples
 ///
 /// ```
+/// # use metrix::registry::Registry;
+/// # use metrix::{metrics_counter};
+/// # use std::collections::HashMap;
+/// # let registry = Registry::new();
+/// # let labels = HashMap::new();
 /// metrics_counter!(registry, "requests_total", labels);
 /// ```
 #[macro_export]
@@ -24,6 +29,12 @@ macro_rules! metrics_counter {
 /// # Examples
 ///
 /// ```
+/// # use metrix::registry::Registry;
+/// # use metrix::{metrics_gauge};
+/// # use std::collections::HashMap;
+/// # let registry = Registry::new();
+/// # let labels = HashMap::new();
+/// # let value = 1.0;
 /// metrics_gauge!(registry, "memory_usage", labels, value);
 /// ```
 #[macro_export]
@@ -39,6 +50,11 @@ macro_rules! metrics_gauge {
 /// # Examples
 ///
 /// ```
+/// # use metrix::registry::Registry;
+/// # use metrix::{metrics_timer};
+/// # use std::collections::HashMap;
+/// # let registry = Registry::new();
+/// # let labels = HashMap::new();
 /// metrics_timer!(registry, "request_duration_seconds", labels, {
 ///     // Code to measure
 /// });
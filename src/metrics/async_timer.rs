@@ -1,27 +1,33 @@
-use crate::metrics::{Histogram, Meter};
+use crate::metrics::{Histogram, Meter, ObserveDuration};
 use futures::Future;
 use std::sync::Arc;
 use tokio::time::Instant;
 
-pub struct AsyncTimer {
-    histogram: Arc<Histogram>,
+/// Times futures, recording each duration and marking a meter.
+///
+/// Durations are recorded in a histogram by default, or in any other
+/// [`ObserveDuration`] metric, e.g. an
+/// [`HdrHistogram`](crate::metrics::HdrHistogram) for precise high
+/// percentiles.
+pub struct AsyncTimer<R: ?Sized = Histogram> {
+    recorder: Arc<R>,
     meter: Arc<Meter>,
 }
 
-impl AsyncTimer {
-    pub fn new(histogram: Arc<Histogram>, meter: Arc<Meter>) -> Self {
-        AsyncTimer { histogram, meter }
+impl<R: ObserveDuration + ?Sized> AsyncTimer<R> {
+    pub fn new(recorder: Arc<R>, meter: Arc<Meter>) -> Self {
+        AsyncTimer { recorder, meter }
     }
 
-    pub async fn time<F, Fut, R>(&self, f: F) -> R
+    pub async fn time<F, Fut, T>(&self, f: F) -> T
     where
         F: FnOnce() -> Fut,
-        Fut: Future<Output = R>,
+        Fut: Future<Output = T>,
     {
         let start = Instant::now();
         let result = f().await;
         let duration = start.elapsed();
-        self.histogram.observe(duration.as_secs_f64());
+        self.recorder.observe_duration(duration);
         self.meter.mark();
         result
     }
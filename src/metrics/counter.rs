@@ -2,14 +2,18 @@
 
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
-use super::Metric;
+use super::{Exemplar, Metric};
 
 /// A counter metric.
 pub struct Counter {
     name: String,
     labels: HashMap<String, String>,
     value: AtomicU64,
+    created: SystemTime,
+    exemplar: Mutex<Option<Exemplar>>,
 }
 
 impl Counter {
@@ -19,6 +23,8 @@ impl Counter {
             name: name.to_string(),
             labels,
             value: AtomicU64::new(0),
+            created: SystemTime::now(),
+            exemplar: Mutex::new(None),
         }
     }
 
@@ -32,10 +38,33 @@ impl Counter {
         self.value.fetch_add(amount, Ordering::Relaxed);
     }
 
+    /// Raises the counter to `value` if it is lower, e.g. to mirror a total
+    /// kept elsewhere.
+    pub fn absolute(&self, value: u64) {
+        self.value.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// Increments the counter by a specified amount, recording an exemplar
+    /// with the given labels, e.g. a trace ID.
+    pub fn increment_with_exemplar(&self, amount: u64, exemplar_labels: &[(&str, &str)]) {
+        self.increment_by(amount);
+        *self.exemplar.lock().unwrap() = Some(Exemplar::new(exemplar_labels, amount as f64));
+    }
+
     /// Gets the current value of the counter.
     pub fn get(&self) -> u64 {
         self.value.load(Ordering::Relaxed)
     }
+
+    /// Gets the time the counter was created.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Gets the most recently recorded exemplar.
+    pub fn get_exemplar(&self) -> Option<Exemplar> {
+        self.exemplar.lock().unwrap().clone()
+    }
 }
 
 impl Metric for Counter {
@@ -0,0 +1,58 @@
+// src/metrics/descriptor.rs
+
+use std::fmt;
+
+/// The unit a metric is measured in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+    Bytes,
+    Ratio,
+    Percent,
+    Count,
+    /// Any other unit, named by its lowercase plural, e.g. `"requests"`.
+    Other(String),
+}
+
+impl Unit {
+    /// Gets the unit name as used in metric names, e.g. `"seconds"`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Unit::Seconds => "seconds",
+            Unit::Milliseconds => "milliseconds",
+            Unit::Microseconds => "microseconds",
+            Unit::Nanoseconds => "nanoseconds",
+            Unit::Bytes => "bytes",
+            Unit::Ratio => "ratio",
+            Unit::Percent => "percent",
+            Unit::Count => "count",
+            Unit::Other(unit) => unit,
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Describes a metric family: its help text and unit.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Descriptor {
+    pub help: String,
+    pub unit: Option<Unit>,
+}
+
+impl Descriptor {
+    /// Creates a new descriptor.
+    pub fn new(help: &str, unit: Option<Unit>) -> Self {
+        Descriptor {
+            help: help.to_string(),
+            unit,
+        }
+    }
+}
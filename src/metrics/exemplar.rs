@@ -0,0 +1,26 @@
+// src/metrics/exemplar.rs
+
+use std::time::SystemTime;
+
+/// An exemplar: a sample observation annotated with labels such as a trace
+/// ID, linking a metric to an example of what it measured.
+#[derive(Clone, Debug)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp: SystemTime,
+}
+
+impl Exemplar {
+    /// Creates an exemplar for the given value, timestamped now.
+    pub fn new(labels: &[(&str, &str)], value: f64) -> Self {
+        Exemplar {
+            labels: labels
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            value,
+            timestamp: SystemTime::now(),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+// src/metrics/family.rs
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+type NewChild<M> = dyn Fn(&str, HashMap<String, String>) -> M + Send + Sync;
+
+/// A metric family: a single metric name with a fixed set of label names,
+/// holding one child series per distinct combination of label values.
+pub struct MetricFamily<M> {
+    name: String,
+    label_names: Vec<String>,
+    children: RwLock<HashMap<Vec<String>, Arc<M>>>,
+    new_child: Box<NewChild<M>>,
+}
+
+impl<M> MetricFamily<M> {
+    /// Creates a new family. `new_child` builds the series for a new set of
+    /// label values.
+    pub fn new<F>(name: &str, label_names: &[&str], new_child: F) -> Self
+    where
+        F: Fn(&str, HashMap<String, String>) -> M + Send + Sync + 'static,
+    {
+        MetricFamily {
+            name: name.to_string(),
+            label_names: label_names.iter().map(|l| l.to_string()).collect(),
+            children: RwLock::new(HashMap::new()),
+            new_child: Box::new(new_child),
+        }
+    }
+
+    /// Gets the metric name of the family.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the label names of the family, in schema order.
+    pub fn label_names(&self) -> &[String] {
+        &self.label_names
+    }
+
+    /// Retrieves or creates the series for the given label values, which
+    /// must be given in the same order as the family's label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values does not match the number of label
+    /// names.
+    pub fn with_label_values(&self, values: &[&str]) -> Arc<M> {
+        self.check_label_values(values);
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+
+        if let Some(child) = self.children.read().unwrap().get(&key) {
+            return child.clone();
+        }
+
+        let mut children = self.children.write().unwrap();
+        children
+            .entry(key)
+            .or_insert_with_key(|key| {
+                let labels = self
+                    .label_names
+                    .iter()
+                    .cloned()
+                    .zip(key.iter().cloned())
+                    .collect();
+                Arc::new((self.new_child)(&self.name, labels))
+            })
+            .clone()
+    }
+
+    /// Retrieves or creates the series for the given label map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the label names do not match the family's label names.
+    pub fn with_labels(&self, labels: &HashMap<String, String>) -> Arc<M> {
+        assert!(
+            self.has_label_names(labels.keys()),
+            "metric `{}` expects labels {:?}, got {:?}",
+            self.name,
+            self.label_names,
+            labels.keys().collect::<Vec<_>>()
+        );
+        let values: Vec<&str> = self
+            .label_names
+            .iter()
+            .map(|name| labels[name].as_str())
+            .collect();
+        self.with_label_values(&values)
+    }
+
+    /// Removes the series for the given label values, returning it if it
+    /// existed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of values does not match the number of label
+    /// names.
+    pub fn remove_label_values(&self, values: &[&str]) -> Option<Arc<M>> {
+        self.check_label_values(values);
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        self.children.write().unwrap().remove(&key)
+    }
+
+    /// Gets all series of the family, ordered by their label values.
+    pub fn children(&self) -> Vec<Arc<M>> {
+        let children = self.children.read().unwrap();
+        let mut entries: Vec<_> = children.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries
+            .into_iter()
+            .map(|(_, child)| child.clone())
+            .collect()
+    }
+
+    fn check_label_values(&self, values: &[&str]) {
+        assert_eq!(
+            values.len(),
+            self.label_names.len(),
+            "metric `{}` expects label values for {:?}, got {:?}",
+            self.name,
+            self.label_names,
+            values
+        );
+    }
+
+    /// Returns whether the given names are exactly the family's label names,
+    /// in any order.
+    pub(crate) fn has_label_names<'a, I>(&self, names: I) -> bool
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let mut names: Vec<&String> = names.into_iter().collect();
+        names.sort();
+        let mut expected: Vec<&String> = self.label_names.iter().collect();
+        expected.sort();
+        names == expected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Counter, Metric};
+
+    fn family() -> MetricFamily<Counter> {
+        MetricFamily::new("requests_total", &["method", "path"], Counter::new)
+    }
+
+    #[test]
+    fn with_label_values_reuses_series() {
+        let family = family();
+        family.with_label_values(&["GET", "/"]).increment();
+        family.with_label_values(&["GET", "/"]).increment();
+        family.with_label_values(&["POST", "/"]).increment();
+
+        let children = family.children();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].get(), 2);
+        assert_eq!(children[0].labels()["method"], "GET");
+    }
+
+    #[test]
+    fn remove_label_values_removes_series() {
+        let family = family();
+        family.with_label_values(&["GET", "/"]);
+        assert!(family.remove_label_values(&["GET", "/"]).is_some());
+        assert!(family.remove_label_values(&["GET", "/"]).is_none());
+        assert!(family.children().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "expects label values")]
+    fn remove_label_values_checks_length() {
+        family().remove_label_values(&["GET"]);
+    }
+}
@@ -34,6 +34,12 @@ impl Gauge {
         *val += 1.0;
     }
 
+    /// Adds a value, which may be negative, to the gauge.
+    pub fn add(&self, delta: f64) {
+        let mut val = self.value.lock().unwrap();
+        *val += delta;
+    }
+
     /// Decrements the gauge by 1.
     pub fn decrement(&self) {
         let mut val = self.value.lock().unwrap();
@@ -0,0 +1,371 @@
+// src/metrics/hdr_histogram.rs
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::summary::SummarySnapshot;
+use super::{Metric, ObserveDuration};
+
+/// The precision and range of an HDR histogram, and the quantiles it
+/// reports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HdrConfig {
+    /// The number of significant decimal digits values are kept with, from
+    /// 0 to 5: with 3, a value is known to within 0.1%.
+    pub significant_digits: u8,
+    /// The highest trackable duration; longer durations are recorded as
+    /// this value.
+    pub max_value: Duration,
+    /// The quantiles reported for each interval, between 0 and 1.
+    pub quantiles: Vec<f64>,
+    /// The number of histograms concurrent recordings are spread over.
+    /// More stripes mean less contention between recording threads, but
+    /// each takes as much memory as a histogram.
+    pub stripes: usize,
+}
+
+impl Default for HdrConfig {
+    /// 3 significant digits up to one minute, reporting the 50th, 90th,
+    /// 99th and 99.9th percentiles, with 4 stripes.
+    fn default() -> Self {
+        HdrConfig {
+            significant_digits: 3,
+            max_value: Duration::from_secs(60),
+            quantiles: vec![0.5, 0.9, 0.99, 0.999],
+            stripes: 4,
+        }
+    }
+}
+
+/// A histogram of durations backed by an HDR histogram, for precise high
+/// percentiles in constant memory.
+///
+/// Durations are recorded in nanoseconds with the configured number of
+/// significant digits. Threads record into one of the configured number of
+/// stripes, so that recording threads rarely contend; the stripes are
+/// merged whenever the histogram is read.
+///
+/// Each series holds a histogram per stripe, plus two merged ones. A
+/// histogram grows with the longest recorded duration and never shrinks:
+/// with 3 significant digits, from 16 KiB to 216 KiB for durations of a
+/// minute, so up to 1.3 MiB per series with the default configuration.
+/// Each significant digit less divides that by about 7.
+///
+/// It is exported as a summary: every snapshot reports the quantiles of the
+/// durations recorded since the previous snapshot, so each scrape or push
+/// shows recent percentiles, along with the count and sum, in seconds,
+/// since creation. With several exporters, each sees the intervals between
+/// its snapshot and the previous one of any exporter.
+pub struct HdrHistogram {
+    name: String,
+    labels: HashMap<String, String>,
+    max_nanos: u64,
+    quantiles: Vec<f64>,
+    stripes: Vec<Mutex<Stripe>>,
+    merged: Mutex<Merged>,
+    created: SystemTime,
+}
+
+/// The durations recorded by a thread since the stripes were last merged.
+struct Stripe {
+    histogram: hdrhistogram::Histogram<u64>,
+    sum: f64,
+}
+
+struct Merged {
+    total: hdrhistogram::Histogram<u64>,
+    interval: hdrhistogram::Histogram<u64>,
+    sum: f64,
+}
+
+/// Assigns each thread its stripe, round-robin.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Ordering::Relaxed);
+}
+
+impl HdrHistogram {
+    /// Creates a new HDR histogram with the default configuration.
+    pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
+        Self::with_config(name, labels, HdrConfig::default())
+    }
+
+    /// Creates a new HDR histogram with the given configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than 5 significant digits, the highest
+    /// trackable duration is below 2 nanoseconds, a quantile is not between
+    /// 0 and 1, or there are no stripes.
+    pub fn with_config(name: &str, labels: HashMap<String, String>, config: HdrConfig) -> Self {
+        assert!(
+            config.significant_digits <= 5,
+            "at most 5 significant digits are supported"
+        );
+        assert!(
+            config.max_value.as_nanos() >= 2,
+            "the highest trackable duration must be at least 2 nanoseconds"
+        );
+        assert!(
+            config.quantiles.iter().all(|q| (0.0..=1.0).contains(q)),
+            "quantiles must be between 0 and 1"
+        );
+        assert!(config.stripes > 0, "at least one stripe is required");
+
+        // Histograms grow to the range of the recorded values, which are
+        // capped at the highest trackable duration.
+        let histogram = || {
+            hdrhistogram::Histogram::new(config.significant_digits)
+                .expect("the number of significant digits is valid")
+        };
+        let mut quantiles = config.quantiles;
+        quantiles.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        quantiles.dedup();
+        HdrHistogram {
+            name: name.to_string(),
+            labels,
+            max_nanos: config.max_value.as_nanos().min(u64::MAX as u128) as u64,
+            quantiles,
+            stripes: (0..config.stripes)
+                .map(|_| {
+                    Mutex::new(Stripe {
+                        histogram: histogram(),
+                        sum: 0.0,
+                    })
+                })
+                .collect(),
+            merged: Mutex::new(Merged {
+                total: histogram(),
+                interval: histogram(),
+                sum: 0.0,
+            }),
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Records a duration.
+    pub fn observe_duration(&self, duration: Duration) {
+        let nanos = duration.as_nanos().min(self.max_nanos as u128) as u64;
+        let index = STRIPE.with(|stripe| *stripe) % self.stripes.len();
+        let mut stripe = self.stripes[index].lock().unwrap();
+        stripe
+            .histogram
+            .record(nanos)
+            .expect("histograms grow to fit the value");
+        stripe.sum += nanos as f64 / 1e9;
+    }
+
+    /// Starts a timing operation.
+    pub fn start_timer(&self) -> HdrTimerHandle<'_> {
+        HdrTimerHandle {
+            start_time: Instant::now(),
+            histogram: self,
+        }
+    }
+
+    /// Gets the number of recorded durations.
+    pub fn get_count(&self) -> u64 {
+        self.merge().total.len()
+    }
+
+    /// Gets the percentile duration over all recorded durations, or `None`
+    /// if there are none.
+    pub fn get_percentile(&self, percentile: f64) -> Option<Duration> {
+        let merged = self.merge();
+        if merged.total.is_empty() {
+            return None;
+        }
+        let quantile = (percentile / 100.0).clamp(0.0, 1.0);
+        Some(Duration::from_nanos(
+            merged.total.value_at_quantile(quantile),
+        ))
+    }
+
+    /// Gets the time the histogram was created.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Takes a snapshot of the quantiles of the durations recorded since
+    /// the previous snapshot, in seconds, and starts a new interval. The
+    /// value of a quantile is NaN when no durations were recorded in the
+    /// interval.
+    pub fn snapshot(&self) -> SummarySnapshot {
+        let mut merged = self.merge();
+        let interval = &merged.interval;
+        let quantiles = self
+            .quantiles
+            .iter()
+            .map(|quantile| {
+                let value = if interval.is_empty() {
+                    f64::NAN
+                } else {
+                    interval.value_at_quantile(*quantile) as f64 / 1e9
+                };
+                (*quantile, value)
+            })
+            .collect();
+        let snapshot = SummarySnapshot {
+            quantiles,
+            count: merged.total.len(),
+            sum: merged.sum,
+        };
+        merged.interval.reset();
+        snapshot
+    }
+
+    /// Moves the durations recorded by every thread into the merged
+    /// histograms.
+    fn merge(&self) -> std::sync::MutexGuard<'_, Merged> {
+        let mut merged = self.merged.lock().unwrap();
+        for stripe in &self.stripes {
+            let mut stripe = stripe.lock().unwrap();
+            if stripe.histogram.is_empty() {
+                continue;
+            }
+            merged
+                .total
+                .add(&stripe.histogram)
+                .expect("histograms grow to fit the values");
+            merged
+                .interval
+                .add(&stripe.histogram)
+                .expect("histograms grow to fit the values");
+            merged.sum += stripe.sum;
+            stripe.histogram.reset();
+            stripe.sum = 0.0;
+        }
+        merged
+    }
+}
+
+impl Metric for HdrHistogram {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+}
+
+impl ObserveDuration for HdrHistogram {
+    fn observe_duration(&self, duration: Duration) {
+        HdrHistogram::observe_duration(self, duration);
+    }
+}
+
+/// A handle to a timing operation of an HDR histogram.
+pub struct HdrTimerHandle<'a> {
+    start_time: Instant,
+    histogram: &'a HdrHistogram,
+}
+
+impl HdrTimerHandle<'_> {
+    /// Stops the timing operation and records the duration.
+    pub fn stop(self) {
+        self.histogram.observe_duration(self.start_time.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn snapshots_interval_quantiles_then_resets_them() {
+        let config = HdrConfig {
+            quantiles: vec![0.99, 0.5],
+            ..HdrConfig::default()
+        };
+        let histogram = HdrHistogram::with_config("h", HashMap::new(), config);
+        for millis in 1..=100 {
+            histogram.observe_duration(Duration::from_millis(millis));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 100);
+        assert!((snapshot.sum - 5.05).abs() < 1e-9);
+        assert_eq!(snapshot.quantiles.len(), 2);
+        let (quantile, median) = snapshot.quantiles[0];
+        assert_eq!(quantile, 0.5);
+        assert!((median - 0.050).abs() < 0.050 * 1e-3, "{}", median);
+        let (quantile, p99) = snapshot.quantiles[1];
+        assert_eq!(quantile, 0.99);
+        assert!((p99 - 0.099).abs() < 0.099 * 1e-3, "{}", p99);
+
+        // The next interval is empty, while the count and sum remain.
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 100);
+        assert!(snapshot.quantiles.iter().all(|(_, value)| value.is_nan()));
+
+        histogram.observe_duration(Duration::from_secs(1));
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 101);
+        assert!((snapshot.quantiles[0].1 - 1.0).abs() < 1e-3);
+        // Percentiles cover every recorded duration.
+        let p50 = histogram.get_percentile(50.0).unwrap();
+        assert!(p50 > Duration::from_millis(49) && p50 < Duration::from_millis(52));
+    }
+
+    #[test]
+    fn merges_the_stripes_of_every_thread() {
+        let config = HdrConfig {
+            stripes: 2,
+            ..HdrConfig::default()
+        };
+        let histogram = Arc::new(HdrHistogram::with_config("h", HashMap::new(), config));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let histogram = histogram.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        histogram.observe_duration(Duration::from_micros(10));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert_eq!(histogram.stripes.len(), 2);
+        assert_eq!(histogram.get_count(), 4000);
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4000);
+        assert!((snapshot.sum - 0.04).abs() < 1e-9);
+    }
+
+    #[test]
+    fn clamps_durations_to_the_highest_trackable_value() {
+        let config = HdrConfig {
+            max_value: Duration::from_secs(1),
+            ..HdrConfig::default()
+        };
+        let histogram = HdrHistogram::with_config("h", HashMap::new(), config);
+        histogram.observe_duration(Duration::from_secs(3600));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.sum, 1.0);
+        assert!(snapshot
+            .quantiles
+            .iter()
+            .all(|(_, value)| (value - 1.0).abs() < 1e-3));
+        let max = histogram.get_percentile(100.0).unwrap();
+        assert!(max.abs_diff(Duration::from_secs(1)) < Duration::from_millis(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one stripe is required")]
+    fn rejects_zero_stripes() {
+        let config = HdrConfig {
+            stripes: 0,
+            ..HdrConfig::default()
+        };
+        HdrHistogram::with_config("h", HashMap::new(), config);
+    }
+}
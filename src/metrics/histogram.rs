@@ -1,48 +1,168 @@
 // src/metrics/histogram.rs
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
-use super::Metric;
+use super::{Exemplar, Metric, ObserveDuration};
+use crate::utils::atomic::AtomicF64;
+use crate::utils::buckets::DEFAULT_BUCKETS;
 
 /// A histogram metric.
+///
+/// Observations are counted into a fixed set of buckets, so memory use does
+/// not grow with the number of observations. Bucket bounds are inclusive
+/// upper bounds; an implicit `+Inf` bucket catches everything above the last
+/// bound.
 pub struct Histogram {
     name: String,
     labels: HashMap<String, String>,
-    observations: Mutex<Vec<f64>>,
+    bounds: Vec<f64>,
+    buckets: Vec<AtomicU64>,
+    sum: AtomicF64,
+    sum_of_squares: AtomicF64,
+    min: AtomicF64,
+    max: AtomicF64,
+    created: SystemTime,
+    exemplars: Vec<Mutex<Option<Exemplar>>>,
 }
 
 impl Histogram {
-    /// Creates a new histogram.
+    /// Creates a new histogram with the default buckets.
     pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
+        Self::with_buckets(name, labels, DEFAULT_BUCKETS.to_vec())
+    }
+
+    /// Creates a new histogram with the given bucket upper bounds, as built
+    /// by `utils::buckets::exponential_buckets` or `linear_buckets`.
+    ///
+    /// Bounds are sorted and deduplicated; non-finite bounds are dropped.
+    pub fn with_buckets(name: &str, labels: HashMap<String, String>, buckets: Vec<f64>) -> Self {
+        let mut bounds: Vec<f64> = buckets.into_iter().filter(|b| b.is_finite()).collect();
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        bounds.dedup();
+        let bounds_len = bounds.len();
         Histogram {
             name: name.to_string(),
             labels,
-            observations: Mutex::new(Vec::new()),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            sum: AtomicF64::new(0.0),
+            sum_of_squares: AtomicF64::new(0.0),
+            min: AtomicF64::new(f64::INFINITY),
+            max: AtomicF64::new(f64::NEG_INFINITY),
+            created: SystemTime::now(),
+            exemplars: (0..=bounds_len).map(|_| Mutex::new(None)).collect(),
         }
     }
 
-    /// Records an observation.
+    /// Records an observation. NaN observations are ignored.
     pub fn observe(&self, value: f64) {
-        let mut obs = self.observations.lock().unwrap();
-        obs.push(value);
+        self.record(value);
     }
 
-    /// Gets the percentile value.
-    pub fn get_percentile(&self, percentile: f64) -> Option<f64> {
-        let mut obs = self.observations.lock().unwrap();
-        if obs.is_empty() {
+    /// Records an observation, keeping an exemplar with the given labels,
+    /// e.g. a trace ID, as the latest example for its bucket.
+    pub fn observe_with_exemplar(&self, value: f64, exemplar_labels: &[(&str, &str)]) {
+        if let Some(index) = self.record(value) {
+            *self.exemplars[index].lock().unwrap() = Some(Exemplar::new(exemplar_labels, value));
+        }
+    }
+
+    /// Gets the time the histogram was created.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Records an observation, returning the index of its bucket.
+    fn record(&self, value: f64) -> Option<usize> {
+        if value.is_nan() {
             return None;
         }
-        obs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        let index = (percentile / 100.0 * obs.len() as f64).ceil() as usize - 1;
-        obs.get(index).cloned()
+        let index = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value);
+        self.sum_of_squares.fetch_add(value * value);
+        self.min.fetch_min(value);
+        self.max.fetch_max(value);
+        Some(index)
+    }
+
+    /// Gets the bucket upper bounds, excluding the implicit `+Inf` bucket.
+    pub fn bounds(&self) -> &[f64] {
+        &self.bounds
+    }
+
+    /// Gets the cumulative bucket counts as `(upper bound, count)` pairs,
+    /// ending with the `+Inf` bucket.
+    pub fn get_buckets(&self) -> Vec<(f64, u64)> {
+        let mut cumulative = 0;
+        self.bounds
+            .iter()
+            .copied()
+            .chain(std::iter::once(f64::INFINITY))
+            .zip(&self.buckets)
+            .map(|(bound, bucket)| {
+                cumulative += bucket.load(Ordering::Relaxed);
+                (bound, cumulative)
+            })
+            .collect()
+    }
+
+    /// Gets the number of observations.
+    pub fn get_count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Gets the sum of all observations.
+    pub fn get_sum(&self) -> f64 {
+        self.sum.load()
+    }
+
+    /// Gets the estimated percentile value, interpolating linearly within
+    /// the bucket that contains it.
+    pub fn get_percentile(&self, percentile: f64) -> Option<f64> {
+        self.snapshot().percentile(percentile)
+    }
+
+    /// Gets a point-in-time copy of the buckets and statistics.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets = self.get_buckets();
+        HistogramSnapshot {
+            count: buckets.last().map_or(0, |(_, count)| *count),
+            buckets,
+            sum: self.sum.load(),
+            min: self.min.load(),
+            max: self.max.load(),
+            exemplars: self
+                .exemplars
+                .iter()
+                .map(|exemplar| exemplar.lock().unwrap().clone())
+                .collect(),
+        }
     }
 
     /// Gets a summary of the histogram.
     pub fn get_summary(&self) -> HistogramSummary {
-        let obs = self.observations.lock().unwrap();
-        HistogramSummary::from_observations(&obs)
+        let count = self.get_count() as usize;
+        if count == 0 {
+            return HistogramSummary::from_observations(&[]);
+        }
+        let sum = self.sum.load();
+        let mean = sum / count as f64;
+        let variance = (self.sum_of_squares.load() / count as f64 - mean * mean).max(0.0);
+        HistogramSummary {
+            count,
+            sum,
+            min: self.min.load(),
+            max: self.max.load(),
+            mean,
+            std_dev: variance.sqrt(),
+        }
     }
 }
 
@@ -56,6 +176,56 @@ impl Metric for Histogram {
     }
 }
 
+impl ObserveDuration for Histogram {
+    fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+}
+
+/// A point-in-time copy of a histogram.
+#[derive(Clone, Debug)]
+pub struct HistogramSnapshot {
+    /// Cumulative `(upper bound, count)` pairs, ending with `+Inf`.
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    /// The latest exemplar of each bucket, parallel to `buckets`.
+    pub exemplars: Vec<Option<Exemplar>>,
+}
+
+impl HistogramSnapshot {
+    /// Gets the estimated percentile value, interpolating linearly within
+    /// the bucket that contains it.
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (percentile / 100.0).clamp(0.0, 1.0) * self.count as f64;
+        if rank <= 0.0 {
+            return Some(self.min);
+        }
+
+        let buckets = &self.buckets;
+        let index = buckets
+            .iter()
+            .position(|(_, cumulative)| *cumulative as f64 >= rank)
+            .unwrap_or(buckets.len() - 1);
+        let below = if index == 0 { 0 } else { buckets[index - 1].1 };
+        let in_bucket = buckets[index].1 - below;
+
+        let lower = if index == 0 {
+            self.min
+        } else {
+            buckets[index - 1].0.max(self.min)
+        };
+        let upper = buckets[index].0.min(self.max);
+        let fraction = (rank - below as f64) / in_bucket as f64;
+        Some(lower + (upper - lower) * fraction)
+    }
+}
+
 /// A summary of histogram data.
 pub struct HistogramSummary {
     pub count: usize,
@@ -89,3 +259,82 @@ impl HistogramSummary {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(bounds: &[f64], observations: &[f64]) -> Histogram {
+        let histogram = Histogram::with_buckets("h", HashMap::new(), bounds.to_vec());
+        for value in observations {
+            histogram.observe(*value);
+        }
+        histogram
+    }
+
+    #[test]
+    fn counts_bounds_as_inclusive_upper_bounds() {
+        let histogram = histogram(&[1.0, 2.0], &[0.5, 1.0, 1.5, 2.0, 2.5]);
+        assert_eq!(
+            histogram.get_buckets(),
+            [(1.0, 2), (2.0, 4), (f64::INFINITY, 5)]
+        );
+        assert_eq!(histogram.get_count(), 5);
+        assert_eq!(histogram.get_sum(), 7.5);
+    }
+
+    #[test]
+    fn counts_values_above_the_last_bound_in_inf() {
+        let histogram = histogram(&[1.0], &[10.0, f64::INFINITY]);
+        assert_eq!(histogram.get_buckets(), [(1.0, 0), (f64::INFINITY, 2)]);
+        assert_eq!(histogram.get_count(), 2);
+        assert_eq!(histogram.get_summary().max, f64::INFINITY);
+    }
+
+    #[test]
+    fn ignores_nan_observations() {
+        let histogram = histogram(&[1.0], &[f64::NAN, 0.5]);
+        assert_eq!(histogram.get_count(), 1);
+        assert_eq!(histogram.get_sum(), 0.5);
+        assert_eq!(histogram.get_summary().min, 0.5);
+    }
+
+    #[test]
+    fn sorts_and_deduplicates_bounds() {
+        let histogram = histogram(&[2.0, f64::INFINITY, 1.0, 2.0, f64::NAN], &[]);
+        assert_eq!(histogram.bounds(), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn interpolates_percentiles_within_buckets() {
+        let observed = histogram(&[1.0, 2.0, 4.0], &[0.5, 1.0, 1.5, 3.0]);
+        // The first bucket starts at the minimum and the last ends at the
+        // maximum.
+        assert_eq!(observed.get_percentile(0.0), Some(0.5));
+        assert_eq!(observed.get_percentile(25.0), Some(0.75));
+        assert_eq!(observed.get_percentile(50.0), Some(1.0));
+        assert_eq!(observed.get_percentile(62.5), Some(1.5));
+        assert_eq!(observed.get_percentile(75.0), Some(2.0));
+        assert_eq!(observed.get_percentile(100.0), Some(3.0));
+        assert_eq!(observed.get_percentile(150.0), Some(3.0));
+
+        // Above the last bound, the `+Inf` bucket ends at the maximum.
+        let overflow = histogram(&[1.0], &[0.5, 10.0]);
+        assert_eq!(overflow.get_percentile(100.0), Some(10.0));
+    }
+
+    #[test]
+    fn has_no_percentiles_while_empty() {
+        let histogram = histogram(&[1.0], &[]);
+        assert_eq!(histogram.get_percentile(50.0), None);
+        assert_eq!(histogram.get_summary().count, 0);
+    }
+
+    #[test]
+    fn summarizes_observations() {
+        let summary = histogram(&[1.0], &[1.0, 3.0]).get_summary();
+        assert_eq!((summary.count, summary.sum), (2, 4.0));
+        assert_eq!((summary.min, summary.max), (1.0, 3.0));
+        assert_eq!((summary.mean, summary.std_dev), (2.0, 1.0));
+    }
+}
@@ -0,0 +1,32 @@
+// src/metrics/info.rs
+
+use std::collections::HashMap;
+
+use super::Metric;
+
+/// An info metric, exposing static information such as a build version
+/// through its labels. Its value is always 1.
+pub struct Info {
+    name: String,
+    labels: HashMap<String, String>,
+}
+
+impl Info {
+    /// Creates a new info metric.
+    pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
+        Info {
+            name: name.to_string(),
+            labels,
+        }
+    }
+}
+
+impl Metric for Info {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+}
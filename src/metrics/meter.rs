@@ -1,46 +1,115 @@
 // src/metrics/meter.rs
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use super::Metric;
 
+/// How often the moving averages are updated.
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
 /// A meter metric to track rates.
+///
+/// Besides the mean rate since creation, a meter keeps exponentially
+/// weighted moving averages over one, five and fifteen minutes. The averages
+/// are brought up to date whenever a rate is read.
 pub struct Meter {
     name: String,
     pub labels: HashMap<String, String>,
-    count: Mutex<u64>,
+    count: AtomicU64,
     start_time: Instant,
+    created: SystemTime,
+    averages: Mutex<MovingAverages>,
 }
 
 impl Meter {
     /// Creates a new meter.
     pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
+        let start_time = Instant::now();
         Meter {
             name: name.to_string(),
             labels,
-            count: Mutex::new(0),
-            start_time: Instant::now(),
+            count: AtomicU64::new(0),
+            start_time,
+            created: SystemTime::now(),
+            averages: Mutex::new(MovingAverages::new(start_time)),
         }
     }
 
     /// Marks an event occurrence.
     pub fn mark(&self) {
-        let mut count = self.count.lock().unwrap();
-        *count += 1;
+        self.mark_n(1);
+    }
+
+    /// Marks `n` event occurrences.
+    pub fn mark_n(&self, n: u64) {
+        self.count.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Gets the number of events marked.
+    pub fn get_count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Gets the time the meter was created.
+    pub fn created(&self) -> SystemTime {
+        self.created
     }
 
     /// Gets the rate of events per second.
     pub fn get_rate(&self) -> f64 {
-        let count = self.count.lock().unwrap();
+        let count = self.get_count();
         let elapsed = self.start_time.elapsed().as_secs_f64();
         if elapsed > 0.0 {
-            *count as f64 / elapsed
+            count as f64 / elapsed
         } else {
             0.0
         }
     }
+
+    /// Gets the one-minute moving average rate of events per second.
+    pub fn get_one_minute_rate(&self) -> f64 {
+        self.tick().m1.rate
+    }
+
+    /// Gets the five-minute moving average rate of events per second.
+    pub fn get_five_minute_rate(&self) -> f64 {
+        self.tick().m5.rate
+    }
+
+    /// Gets the fifteen-minute moving average rate of events per second.
+    pub fn get_fifteen_minute_rate(&self) -> f64 {
+        self.tick().m15.rate
+    }
+
+    /// Gets a consistent copy of the count and all rates.
+    pub fn snapshot(&self) -> MeterSnapshot {
+        let averages = self.tick();
+        MeterSnapshot {
+            count: self.get_count(),
+            mean_rate: self.get_rate(),
+            one_minute_rate: averages.m1.rate,
+            five_minute_rate: averages.m5.rate,
+            fifteen_minute_rate: averages.m15.rate,
+        }
+    }
+
+    fn tick(&self) -> std::sync::MutexGuard<'_, MovingAverages> {
+        let mut averages = self.averages.lock().unwrap();
+        let ticks = (averages.last_tick.elapsed().as_nanos() / TICK_INTERVAL.as_nanos()) as u32;
+        if ticks > 0 {
+            let count = self.get_count();
+            let uncounted = count - averages.last_count;
+            averages.last_count = count;
+            averages.last_tick += TICK_INTERVAL * ticks;
+            averages.m1.tick(uncounted, ticks);
+            averages.m5.tick(uncounted, ticks);
+            averages.m15.tick(uncounted, ticks);
+        }
+        averages
+    }
 }
 
 impl Metric for Meter {
@@ -52,3 +121,144 @@ impl Metric for Meter {
         &self.labels
     }
 }
+
+/// A point-in-time copy of a meter's count and rates, in events per second.
+#[derive(Clone, Debug)]
+pub struct MeterSnapshot {
+    pub count: u64,
+    pub mean_rate: f64,
+    pub one_minute_rate: f64,
+    pub five_minute_rate: f64,
+    pub fifteen_minute_rate: f64,
+}
+
+impl MeterSnapshot {
+    /// Gets the rates keyed by their window: `mean`, `1m`, `5m` and `15m`.
+    pub fn rates(&self) -> [(&'static str, f64); 4] {
+        [
+            ("mean", self.mean_rate),
+            ("1m", self.one_minute_rate),
+            ("5m", self.five_minute_rate),
+            ("15m", self.fifteen_minute_rate),
+        ]
+    }
+}
+
+struct MovingAverages {
+    last_tick: Instant,
+    last_count: u64,
+    m1: Ewma,
+    m5: Ewma,
+    m15: Ewma,
+}
+
+impl MovingAverages {
+    fn new(start_time: Instant) -> Self {
+        MovingAverages {
+            last_tick: start_time,
+            last_count: 0,
+            m1: Ewma::new(Duration::from_secs(60)),
+            m5: Ewma::new(Duration::from_secs(5 * 60)),
+            m15: Ewma::new(Duration::from_secs(15 * 60)),
+        }
+    }
+}
+
+/// An exponentially weighted moving average of a per-second rate.
+struct Ewma {
+    alpha: f64,
+    rate: f64,
+    initialized: bool,
+}
+
+impl Ewma {
+    fn new(window: Duration) -> Self {
+        Ewma {
+            alpha: 1.0 - (-TICK_INTERVAL.as_secs_f64() / window.as_secs_f64()).exp(),
+            rate: 0.0,
+            initialized: false,
+        }
+    }
+
+    /// Applies `ticks` intervals over which `count` events were spread
+    /// evenly.
+    fn tick(&mut self, count: u64, ticks: u32) {
+        let instant_rate = count as f64 / (TICK_INTERVAL.as_secs_f64() * ticks as f64);
+        if self.initialized {
+            let decay = (1.0 - self.alpha).powi(ticks as i32);
+            self.rate = instant_rate + (self.rate - instant_rate) * decay;
+        } else {
+            self.rate = instant_rate;
+            self.initialized = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn starts_averages_at_the_first_rate() {
+        let mut ewma = Ewma::new(Duration::from_secs(60));
+        // 50 events over 2 ticks of 5 seconds.
+        ewma.tick(50, 2);
+        assert_close(ewma.rate, 5.0);
+    }
+
+    #[test]
+    fn decays_averages_over_their_window() {
+        let mut averages = MovingAverages::new(Instant::now());
+        for ewma in [&mut averages.m1, &mut averages.m5, &mut averages.m15] {
+            ewma.tick(50, 1);
+            // A minute without events.
+            ewma.tick(0, 12);
+        }
+        assert_close(averages.m1.rate, 10.0 * (-1.0f64).exp());
+        assert_close(averages.m5.rate, 10.0 * (-0.2f64).exp());
+        assert_close(averages.m15.rate, 10.0 * (-1.0f64 / 15.0).exp());
+    }
+
+    #[test]
+    fn applies_missed_ticks_at_once() {
+        let mut one_by_one = Ewma::new(Duration::from_secs(300));
+        let mut at_once = Ewma::new(Duration::from_secs(300));
+        one_by_one.tick(10, 1);
+        at_once.tick(10, 1);
+        for _ in 0..6 {
+            one_by_one.tick(20, 1);
+        }
+        at_once.tick(120, 6);
+        assert_close(at_once.rate, one_by_one.rate);
+    }
+
+    #[test]
+    fn ticks_lazily_when_rates_are_read() {
+        let meter = Meter::new("m", HashMap::new());
+        meter.mark_n(60);
+        // Less than a tick has passed, so the averages are not started.
+        assert_eq!(meter.get_one_minute_rate(), 0.0);
+
+        // Three ticks have passed since the meter was created.
+        meter.averages.lock().unwrap().last_tick -= TICK_INTERVAL * 3;
+        let snapshot = meter.snapshot();
+        assert_eq!(snapshot.count, 60);
+        assert_close(snapshot.one_minute_rate, 4.0);
+        assert_close(snapshot.five_minute_rate, 4.0);
+        assert_close(snapshot.fifteen_minute_rate, 4.0);
+
+        // The events were counted in that tick.
+        meter.averages.lock().unwrap().last_tick -= TICK_INTERVAL;
+        let alpha = 1.0 - (-5.0f64 / 60.0).exp();
+        assert_close(meter.get_one_minute_rate(), 4.0 * (1.0 - alpha));
+    }
+}
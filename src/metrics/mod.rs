@@ -2,15 +2,31 @@
 
 pub mod async_timer;
 pub mod counter;
+pub mod descriptor;
+pub mod exemplar;
+pub mod family;
 pub mod gauge;
+pub mod hdr_histogram;
 pub mod histogram;
+pub mod info;
 pub mod meter;
+pub mod native_histogram;
+pub mod state_set;
+pub mod summary;
 pub mod timer;
 
 pub use counter::Counter;
+pub use descriptor::{Descriptor, Unit};
+pub use exemplar::Exemplar;
+pub use family::MetricFamily;
 pub use gauge::Gauge;
+pub use hdr_histogram::{HdrConfig, HdrHistogram};
 pub use histogram::Histogram;
+pub use info::Info;
 pub use meter::Meter;
+pub use native_histogram::{NativeHistogram, NativeHistogramConfig};
+pub use state_set::StateSet;
+pub use summary::{Summary, SummaryConfig};
 pub use timer::Timer;
 
 /// Trait representing a metric.
@@ -18,3 +34,10 @@ pub trait Metric {
     fn name(&self) -> &str;
     fn labels(&self) -> &std::collections::HashMap<String, String>;
 }
+
+/// A metric durations can be recorded in, so that timers and middlewares
+/// can record into any of them.
+pub trait ObserveDuration: Send + Sync {
+    /// Records a duration, in seconds for metrics of plain values.
+    fn observe_duration(&self, duration: std::time::Duration);
+}
@@ -0,0 +1,404 @@
+// src/metrics/native_histogram.rs
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::histogram::HistogramSnapshot;
+use super::{Metric, ObserveDuration};
+
+/// The finest schema of native histograms, splitting each power of two into
+/// 256 buckets.
+pub const MAX_SCHEMA: i32 = 8;
+
+/// The coarsest schema of native histograms, with a bucket per factor of
+/// 2^16.
+pub const MIN_SCHEMA: i32 = -4;
+
+/// The bucket layout of a native histogram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NativeHistogramConfig {
+    /// The initial resolution: each power of two is split into
+    /// `2^schema` buckets. Clamped to [`MIN_SCHEMA`]..=[`MAX_SCHEMA`].
+    pub schema: i32,
+    /// Observations with an absolute value up to the threshold are counted
+    /// in the zero bucket.
+    pub zero_threshold: f64,
+    /// The number of populated buckets above which the schema is reduced,
+    /// halving the resolution.
+    pub max_buckets: usize,
+}
+
+impl Default for NativeHistogramConfig {
+    /// Schema 3, i.e. buckets about 9% wide, a zero threshold of 2^-128 and
+    /// at most 160 buckets, like the Prometheus client libraries.
+    fn default() -> Self {
+        NativeHistogramConfig {
+            schema: 3,
+            zero_threshold: 2.938735877055719e-39,
+            max_buckets: 160,
+        }
+    }
+}
+
+/// A native histogram, also known as a sparse exponential histogram.
+///
+/// Instead of fixed bounds, buckets follow from the schema: bucket `i`
+/// holds the observations in `(base^(i-1), base^i]`, where
+/// `base = 2^(2^-schema)`, and only populated buckets are stored.
+/// Observations close to zero are counted in a zero bucket, and negative
+/// observations in mirrored buckets. When more buckets than configured are
+/// populated, the schema is reduced until they fit, merging neighbouring
+/// buckets.
+///
+/// Exporters without native histogram support render the populated buckets
+/// as a classic histogram.
+pub struct NativeHistogram {
+    name: String,
+    labels: HashMap<String, String>,
+    zero_threshold: f64,
+    max_buckets: usize,
+    state: Mutex<State>,
+    created: SystemTime,
+}
+
+struct State {
+    schema: i32,
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    zero_count: u64,
+    positive: BTreeMap<i32, u64>,
+    negative: BTreeMap<i32, u64>,
+}
+
+impl NativeHistogram {
+    /// Creates a new native histogram with the default configuration.
+    pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
+        Self::with_config(name, labels, NativeHistogramConfig::default())
+    }
+
+    /// Creates a new native histogram with the given configuration.
+    pub fn with_config(
+        name: &str,
+        labels: HashMap<String, String>,
+        config: NativeHistogramConfig,
+    ) -> Self {
+        NativeHistogram {
+            name: name.to_string(),
+            labels,
+            zero_threshold: config.zero_threshold.abs(),
+            max_buckets: config.max_buckets.max(1),
+            state: Mutex::new(State {
+                schema: config.schema.clamp(MIN_SCHEMA, MAX_SCHEMA),
+                count: 0,
+                sum: 0.0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                zero_count: 0,
+                positive: BTreeMap::new(),
+                negative: BTreeMap::new(),
+            }),
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Records an observation. NaN observations are ignored.
+    pub fn observe(&self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        state.sum += value;
+        state.min = state.min.min(value);
+        state.max = state.max.max(value);
+
+        if value.abs() <= self.zero_threshold {
+            state.zero_count += 1;
+            return;
+        }
+        let index = bucket_index(value.abs(), state.schema);
+        let buckets = if value > 0.0 {
+            &mut state.positive
+        } else {
+            &mut state.negative
+        };
+        *buckets.entry(index).or_insert(0) += 1;
+
+        while state.positive.len() + state.negative.len() > self.max_buckets
+            && state.schema > MIN_SCHEMA
+        {
+            state.schema -= 1;
+            state.positive = downscale(&state.positive, 1);
+            state.negative = downscale(&state.negative, 1);
+        }
+    }
+
+    /// Gets the current schema.
+    pub fn schema(&self) -> i32 {
+        self.state.lock().unwrap().schema
+    }
+
+    /// Gets the number of observations.
+    pub fn get_count(&self) -> u64 {
+        self.state.lock().unwrap().count
+    }
+
+    /// Gets the sum of all observations.
+    pub fn get_sum(&self) -> f64 {
+        self.state.lock().unwrap().sum
+    }
+
+    /// Gets the estimated percentile value, interpolating linearly within
+    /// the bucket that contains it.
+    pub fn get_percentile(&self, percentile: f64) -> Option<f64> {
+        self.snapshot().percentile(percentile)
+    }
+
+    /// Gets the time the histogram was created.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Takes a consistent point-in-time copy of the histogram.
+    pub fn snapshot(&self) -> NativeHistogramSnapshot {
+        let state = self.state.lock().unwrap();
+        NativeHistogramSnapshot {
+            schema: state.schema,
+            zero_threshold: self.zero_threshold,
+            zero_count: state.zero_count,
+            positive: state.positive.iter().map(|(i, c)| (*i, *c)).collect(),
+            negative: state.negative.iter().map(|(i, c)| (*i, *c)).collect(),
+            count: state.count,
+            sum: state.sum,
+            min: state.min,
+            max: state.max,
+        }
+    }
+}
+
+impl Metric for NativeHistogram {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+}
+
+impl ObserveDuration for NativeHistogram {
+    fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+}
+
+/// A point-in-time copy of a native histogram.
+#[derive(Clone, Debug)]
+pub struct NativeHistogramSnapshot {
+    pub schema: i32,
+    pub zero_threshold: f64,
+    pub zero_count: u64,
+    /// Populated positive buckets as `(index, count)` pairs, ordered by
+    /// index.
+    pub positive: Vec<(i32, u64)>,
+    /// Populated negative buckets as `(index, count)` pairs, ordered by
+    /// index; bucket `i` holds the observations in `[-base^i, -base^(i-1))`.
+    pub negative: Vec<(i32, u64)>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl NativeHistogramSnapshot {
+    /// Gets the upper bound of the positive bucket with the given index.
+    pub fn upper_bound(&self, index: i32) -> f64 {
+        upper_bound(index, self.schema)
+    }
+
+    /// Merges neighbouring buckets into those of a coarser schema. A schema
+    /// that is not coarser leaves the snapshot unchanged.
+    pub fn downscale(&self, schema: i32) -> NativeHistogramSnapshot {
+        let by = (self.schema - schema).max(0) as u32;
+        let merge = |buckets: &[(i32, u64)]| {
+            downscale(&buckets.iter().copied().collect(), by)
+                .into_iter()
+                .collect()
+        };
+        NativeHistogramSnapshot {
+            schema: self.schema - by as i32,
+            positive: merge(&self.positive),
+            negative: merge(&self.negative),
+            ..self.clone()
+        }
+    }
+
+    /// Converts the populated buckets into a classic histogram: the negative
+    /// buckets, the zero bucket if populated and the positive buckets,
+    /// bounded by their upper bounds.
+    pub fn to_classic(&self) -> HistogramSnapshot {
+        let negative = self
+            .negative
+            .iter()
+            .rev()
+            .map(|(index, count)| (-upper_bound(index - 1, self.schema), *count));
+        let zero = (self.zero_count > 0).then_some((self.zero_threshold, self.zero_count));
+        let positive = self
+            .positive
+            .iter()
+            .map(|(index, count)| (upper_bound(*index, self.schema), *count));
+
+        let mut buckets = Vec::new();
+        let mut cumulative = 0;
+        for (bound, count) in negative.chain(zero).chain(positive) {
+            cumulative += count;
+            // Counts of buckets with infinite bounds end up in `+Inf`.
+            if bound.is_finite() {
+                buckets.push((bound, cumulative));
+            }
+        }
+        buckets.push((f64::INFINITY, self.count));
+
+        HistogramSnapshot {
+            exemplars: vec![None; buckets.len()],
+            buckets,
+            count: self.count,
+            sum: self.sum,
+            min: self.min,
+            max: self.max,
+        }
+    }
+
+    /// Gets the estimated percentile value, interpolating linearly within
+    /// the bucket that contains it.
+    pub fn percentile(&self, percentile: f64) -> Option<f64> {
+        self.to_classic().percentile(percentile)
+    }
+}
+
+/// Gets the upper bound `2^(index * 2^-schema)` of a bucket.
+fn upper_bound(index: i32, schema: i32) -> f64 {
+    (index as f64 * (-schema as f64).exp2()).exp2()
+}
+
+/// Gets the index of the bucket of a positive value.
+fn bucket_index(value: f64, schema: i32) -> i32 {
+    if value.is_infinite() {
+        return i32::MAX;
+    }
+    // The logarithm is only an estimate near bucket bounds, so it is
+    // corrected against the bounds themselves.
+    let mut index = (value.log2() * (schema as f64).exp2()).ceil() as i32;
+    while value > upper_bound(index, schema) {
+        index += 1;
+    }
+    while value <= upper_bound(index - 1, schema) {
+        index -= 1;
+    }
+    index
+}
+
+/// Maps buckets to a schema coarser by `by`, where bucket `i` becomes
+/// bucket `ceil(i / 2^by)`.
+fn downscale(buckets: &BTreeMap<i32, u64>, by: u32) -> BTreeMap<i32, u64> {
+    let factor = 1i64 << by;
+    let mut merged = BTreeMap::new();
+    for (index, count) in buckets {
+        let index = (*index as i64 + factor - 1).div_euclid(factor) as i32;
+        *merged.entry(index).or_insert(0) += count;
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexes_buckets_by_their_upper_bound() {
+        // Schema 0 buckets are powers of two: bucket 1 is (1, 2].
+        assert_eq!(bucket_index(1.0, 0), 0);
+        assert_eq!(bucket_index(1.5, 0), 1);
+        assert_eq!(bucket_index(2.0, 0), 1);
+        assert_eq!(bucket_index(2.5, 0), 2);
+        assert_eq!(bucket_index(0.5, 0), -1);
+        // Schema 3 splits each power of two into 8 buckets.
+        assert_eq!(bucket_index(2.0, 3), 8);
+        assert_eq!(bucket_index(2.0f64.powf(1.0 / 8.0), 3), 1);
+        assert_eq!(bucket_index(1.0001, 3), 1);
+        // Schema -1 buckets are powers of four.
+        assert_eq!(bucket_index(4.0, -1), 1);
+        assert_eq!(bucket_index(5.0, -1), 2);
+        assert_eq!(bucket_index(f64::INFINITY, 3), i32::MAX);
+
+        for schema in MIN_SCHEMA..=MAX_SCHEMA {
+            for index in -20..20 {
+                let bound = upper_bound(index, schema);
+                assert_eq!(bucket_index(bound, schema), index);
+                assert_eq!(bucket_index(bound * 1.000_000_1, schema), index + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn downscales_by_merging_neighbouring_buckets() {
+        let buckets: BTreeMap<i32, u64> = [(-3, 1), (-2, 1), (0, 1), (1, 2), (2, 3), (3, 4)].into();
+        // Bucket `i` becomes `ceil(i / 2)`: (-2, 0] and (0, 2] are merged.
+        let merged: Vec<(i32, u64)> = downscale(&buckets, 1).into_iter().collect();
+        assert_eq!(merged, [(-1, 2), (0, 1), (1, 5), (2, 4)]);
+        let merged: Vec<(i32, u64)> = downscale(&buckets, 2).into_iter().collect();
+        assert_eq!(merged, [(0, 3), (1, 9)]);
+    }
+
+    #[test]
+    fn reduces_the_schema_when_buckets_overflow() {
+        let config = NativeHistogramConfig {
+            schema: 0,
+            zero_threshold: 0.0,
+            max_buckets: 3,
+        };
+        let histogram = NativeHistogram::with_config("h", HashMap::new(), config);
+        for value in [1.5, 3.0, 12.0] {
+            histogram.observe(value);
+        }
+        assert_eq!(histogram.schema(), 0);
+        assert_eq!(histogram.snapshot().positive, [(1, 1), (2, 1), (4, 1)]);
+
+        // A fourth bucket halves the resolution.
+        histogram.observe(-1.0);
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.schema, -1);
+        assert_eq!(snapshot.positive, [(1, 2), (2, 1)]);
+        assert_eq!(snapshot.negative, [(0, 1)]);
+        assert_eq!(snapshot.count, 4);
+
+        assert_eq!(snapshot.downscale(-2).positive, [(1, 3)]);
+        // A finer schema leaves the snapshot unchanged.
+        assert_eq!(snapshot.downscale(0).schema, -1);
+    }
+
+    #[test]
+    fn converts_to_a_classic_histogram() {
+        let histogram = NativeHistogram::with_config(
+            "h",
+            HashMap::new(),
+            NativeHistogramConfig {
+                schema: 0,
+                zero_threshold: 0.5,
+                max_buckets: 160,
+            },
+        );
+        for value in [-3.0, 0.25, 1.5, 1.75, f64::INFINITY] {
+            histogram.observe(value);
+        }
+
+        let classic = histogram.snapshot().to_classic();
+        assert_eq!(
+            classic.buckets,
+            [(-2.0, 1), (0.5, 2), (2.0, 4), (f64::INFINITY, 5)]
+        );
+    }
+}
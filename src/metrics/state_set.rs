@@ -0,0 +1,69 @@
+// src/metrics/state_set.rs
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::Metric;
+
+/// A state set metric: a fixed series of named states, each either enabled
+/// or disabled.
+pub struct StateSet {
+    name: String,
+    labels: HashMap<String, String>,
+    states: Vec<(String, AtomicBool)>,
+}
+
+impl StateSet {
+    /// Creates a new state set with all states disabled.
+    pub fn new(name: &str, labels: HashMap<String, String>, states: &[&str]) -> Self {
+        StateSet {
+            name: name.to_string(),
+            labels,
+            states: states
+                .iter()
+                .map(|state| (state.to_string(), AtomicBool::new(false)))
+                .collect(),
+        }
+    }
+
+    /// Enables or disables a state. Unknown states are ignored.
+    pub fn set(&self, state: &str, enabled: bool) {
+        if let Some((_, value)) = self.states.iter().find(|(name, _)| name == state) {
+            value.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    /// Enables the given state and disables all others, for state sets
+    /// that behave like an enum.
+    pub fn set_exclusive(&self, state: &str) {
+        for (name, value) in &self.states {
+            value.store(name == state, Ordering::Relaxed);
+        }
+    }
+
+    /// Gets whether a state is enabled.
+    pub fn get(&self, state: &str) -> Option<bool> {
+        self.states
+            .iter()
+            .find(|(name, _)| name == state)
+            .map(|(_, value)| value.load(Ordering::Relaxed))
+    }
+
+    /// Gets all states and whether they are enabled, in declaration order.
+    pub fn get_states(&self) -> Vec<(String, bool)> {
+        self.states
+            .iter()
+            .map(|(name, value)| (name.clone(), value.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+impl Metric for StateSet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+}
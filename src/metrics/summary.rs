@@ -0,0 +1,465 @@
+// src/metrics/summary.rs
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{Metric, ObserveDuration};
+
+/// The number of observations buffered before they are merged into the
+/// streams.
+const BUFFER_SIZE: usize = 500;
+
+/// The quantiles and time window of a summary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SummaryConfig {
+    /// The tracked quantiles as `(quantile, allowed error)` pairs, e.g.
+    /// `(0.99, 0.001)` for the 99th percentile with a rank error of 0.1%.
+    pub quantiles: Vec<(f64, f64)>,
+    /// How long observations count towards the quantiles.
+    pub max_age: Duration,
+    /// Into how many buckets the window is split. Observations expire a
+    /// bucket at a time, every `max_age / age_buckets`.
+    pub age_buckets: u32,
+}
+
+impl Default for SummaryConfig {
+    /// The 50th, 90th and 99th percentiles over the last 10 minutes, in 5
+    /// age buckets.
+    fn default() -> Self {
+        SummaryConfig {
+            quantiles: vec![(0.5, 0.05), (0.9, 0.01), (0.99, 0.001)],
+            max_age: Duration::from_secs(600),
+            age_buckets: 5,
+        }
+    }
+}
+
+/// A summary metric, tracking quantiles of recent observations.
+///
+/// Quantiles are estimated with the CKMS targeted quantiles algorithm, so
+/// memory use depends on the allowed errors rather than the number of
+/// observations. Only observations of the last `max_age` are taken into
+/// account: each of the age buckets holds a stream started at a different
+/// time, and the oldest is queried and then started over as the window
+/// slides. The count and sum cover all observations since creation.
+pub struct Summary {
+    name: String,
+    labels: HashMap<String, String>,
+    targets: Vec<(f64, f64)>,
+    bucket_duration: Duration,
+    state: Mutex<State>,
+    created: SystemTime,
+}
+
+struct State {
+    count: u64,
+    sum: f64,
+    buffer: Vec<f64>,
+    streams: Vec<Stream>,
+    head: usize,
+    head_expires: Instant,
+}
+
+impl Summary {
+    /// Creates a new summary with the default configuration.
+    pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
+        Self::with_config(name, labels, SummaryConfig::default())
+    }
+
+    /// Creates a new summary with the given configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a quantile is not between 0 and 1, an allowed error is not
+    /// between 0 and 1 exclusive, or the window or number of age buckets is
+    /// zero.
+    pub fn with_config(name: &str, labels: HashMap<String, String>, config: SummaryConfig) -> Self {
+        assert!(
+            config
+                .quantiles
+                .iter()
+                .all(|(quantile, error)| (0.0..=1.0).contains(quantile)
+                    && *error > 0.0
+                    && *error < 1.0),
+            "quantiles must be between 0 and 1, errors between 0 and 1 exclusive"
+        );
+        assert!(
+            !config.max_age.is_zero() && config.age_buckets > 0,
+            "the window and the number of age buckets must not be zero"
+        );
+
+        let mut targets = config.quantiles;
+        targets.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        targets.dedup_by(|a, b| a.0 == b.0);
+        let bucket_duration = config.max_age / config.age_buckets;
+        Summary {
+            name: name.to_string(),
+            labels,
+            targets,
+            bucket_duration,
+            state: Mutex::new(State {
+                count: 0,
+                sum: 0.0,
+                buffer: Vec::with_capacity(BUFFER_SIZE),
+                streams: (0..config.age_buckets).map(|_| Stream::new()).collect(),
+                head: 0,
+                head_expires: Instant::now() + bucket_duration,
+            }),
+            created: SystemTime::now(),
+        }
+    }
+
+    /// Records an observation. NaN observations are ignored.
+    pub fn observe(&self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.count += 1;
+        state.sum += value;
+        // Observations are merged before the streams rotate, so they expire
+        // with the bucket they were made in.
+        if state.buffer.len() >= BUFFER_SIZE || now >= state.head_expires {
+            self.flush(&mut state, now);
+        }
+        state.buffer.push(value);
+    }
+
+    /// Records a duration, in seconds.
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    /// Starts a timing operation, recorded in seconds when stopped.
+    pub fn start_timer(&self) -> SummaryTimerHandle<'_> {
+        SummaryTimerHandle {
+            start_time: Instant::now(),
+            summary: self,
+        }
+    }
+
+    /// Gets the number of observations.
+    pub fn get_count(&self) -> u64 {
+        self.state.lock().unwrap().count
+    }
+
+    /// Gets the sum of all observations.
+    pub fn get_sum(&self) -> f64 {
+        self.state.lock().unwrap().sum
+    }
+
+    /// Gets the estimated value of a quantile between 0 and 1 over the
+    /// window, or `None` if there were no observations in it.
+    pub fn get_quantile(&self, quantile: f64) -> Option<f64> {
+        let mut state = self.state.lock().unwrap();
+        self.flush(&mut state, Instant::now());
+        state.streams[state.head].query(&self.targets, quantile.clamp(0.0, 1.0))
+    }
+
+    /// Gets the time the summary was created.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// Takes a consistent point-in-time copy of the summary. The value of a
+    /// quantile is NaN while there were no observations in the window.
+    pub fn snapshot(&self) -> SummarySnapshot {
+        let mut state = self.state.lock().unwrap();
+        self.flush(&mut state, Instant::now());
+        let head = &state.streams[state.head];
+        SummarySnapshot {
+            quantiles: self
+                .targets
+                .iter()
+                .map(|(quantile, _)| {
+                    let value = head.query(&self.targets, *quantile);
+                    (*quantile, value.unwrap_or(f64::NAN))
+                })
+                .collect(),
+            count: state.count,
+            sum: state.sum,
+        }
+    }
+
+    /// Merges the buffered observations into every stream, then starts the
+    /// expired streams over.
+    fn flush(&self, state: &mut State, now: Instant) {
+        if !state.buffer.is_empty() {
+            let mut buffer = std::mem::take(&mut state.buffer);
+            buffer.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for stream in &mut state.streams {
+                stream.insert(&self.targets, &buffer);
+            }
+            buffer.clear();
+            state.buffer = buffer;
+        }
+        while now >= state.head_expires {
+            let head = state.head;
+            state.streams[head] = Stream::new();
+            state.head = (head + 1) % state.streams.len();
+            state.head_expires += self.bucket_duration;
+        }
+    }
+}
+
+impl Metric for Summary {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn labels(&self) -> &HashMap<String, String> {
+        &self.labels
+    }
+}
+
+impl ObserveDuration for Summary {
+    fn observe_duration(&self, duration: Duration) {
+        Summary::observe_duration(self, duration);
+    }
+}
+
+/// A handle to a timing operation of a summary.
+pub struct SummaryTimerHandle<'a> {
+    start_time: Instant,
+    summary: &'a Summary,
+}
+
+impl SummaryTimerHandle<'_> {
+    /// Stops the timing operation and records the duration.
+    pub fn stop(self) {
+        self.summary.observe_duration(self.start_time.elapsed());
+    }
+}
+
+/// A point-in-time copy of a summary.
+#[derive(Clone, Debug)]
+pub struct SummarySnapshot {
+    /// `(quantile, value)` pairs, ordered by quantile.
+    pub quantiles: Vec<(f64, f64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+/// A CKMS stream: a sorted list of samples, each standing for `width`
+/// observations whose rank is known up to `delta`.
+struct Stream {
+    samples: Vec<Sample>,
+    n: f64,
+}
+
+#[derive(Clone, Copy)]
+struct Sample {
+    value: f64,
+    width: f64,
+    delta: f64,
+}
+
+impl Stream {
+    fn new() -> Self {
+        Stream {
+            samples: Vec::new(),
+            n: 0.0,
+        }
+    }
+
+    /// Merges sorted observations into the samples and compresses them.
+    fn insert(&mut self, targets: &[(f64, f64)], sorted: &[f64]) {
+        let mut merged = Vec::with_capacity(self.samples.len() + sorted.len());
+        let mut existing = self.samples.drain(..).peekable();
+        let mut rank = 0.0;
+        for &value in sorted {
+            while let Some(sample) = existing.next_if(|sample| sample.value <= value) {
+                rank += sample.width;
+                merged.push(sample);
+            }
+            // The rank of a new minimum or maximum is known exactly.
+            let delta = if merged.is_empty() || existing.peek().is_none() {
+                0.0
+            } else {
+                (invariant(targets, rank, self.n).floor() - 1.0).max(0.0)
+            };
+            merged.push(Sample {
+                value,
+                width: 1.0,
+                delta,
+            });
+            self.n += 1.0;
+            rank += 1.0;
+        }
+        merged.extend(existing);
+        self.samples = merged;
+        self.compress(targets);
+    }
+
+    /// Merges neighbouring samples as long as the rank error stays within
+    /// the invariant.
+    fn compress(&mut self, targets: &[(f64, f64)]) {
+        let Some(&last) = self.samples.last() else {
+            return;
+        };
+        let mut compressed = Vec::with_capacity(self.samples.len());
+        let mut current = last;
+        let mut rank = self.n - 1.0 - current.width;
+        for sample in self.samples[..self.samples.len() - 1].iter().rev() {
+            if sample.width + current.width + current.delta <= invariant(targets, rank, self.n) {
+                current.width += sample.width;
+            } else {
+                compressed.push(current);
+                current = *sample;
+            }
+            rank -= sample.width;
+        }
+        compressed.push(current);
+        compressed.reverse();
+        self.samples = compressed;
+    }
+
+    fn query(&self, targets: &[(f64, f64)], quantile: f64) -> Option<f64> {
+        let (first, rest) = self.samples.split_first()?;
+        let mut rank = (quantile * self.n).ceil();
+        rank += (invariant(targets, rank, self.n) / 2.0).ceil();
+
+        let mut previous = first;
+        let mut below = 0.0;
+        for sample in rest {
+            below += previous.width;
+            if below + sample.width + sample.delta > rank {
+                break;
+            }
+            previous = sample;
+        }
+        Some(previous.value)
+    }
+}
+
+/// Gets the allowed rank error at rank `rank` of `n` observations: the
+/// tightest of the errors allowed by each targeted quantile.
+fn invariant(targets: &[(f64, f64)], rank: f64, n: f64) -> f64 {
+    targets
+        .iter()
+        .map(|(quantile, error)| {
+            if quantile * n <= rank {
+                2.0 * error * rank / quantile
+            } else {
+                2.0 * error * (n - rank) / (1.0 - quantile)
+            }
+        })
+        .fold(f64::MAX, f64::min)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Observes 1..=n in a scrambled order, so that the rank of a value is
+    /// the value itself.
+    fn observe_scrambled(summary: &Summary, n: u64) {
+        // 7919 is prime, so multiplying by it permutes the residues.
+        for i in 0..n {
+            summary.observe((i * 7919 % n + 1) as f64);
+        }
+    }
+
+    #[test]
+    fn keeps_quantiles_within_their_rank_error() {
+        let summary = Summary::new("s", HashMap::new());
+        let n = 100_000;
+        observe_scrambled(&summary, n);
+
+        for (quantile, error) in SummaryConfig::default().quantiles {
+            let value = summary.get_quantile(quantile).unwrap();
+            let rank_error = (value / n as f64 - quantile).abs();
+            assert!(
+                rank_error <= error,
+                "quantile {} is {}, a rank error of {}",
+                quantile,
+                value,
+                rank_error
+            );
+        }
+        assert_eq!(summary.get_count(), n);
+        assert_eq!(summary.get_sum(), (n * (n + 1) / 2) as f64);
+
+        // The streams hold far fewer samples than observations.
+        let state = summary.state.lock().unwrap();
+        assert!(state
+            .streams
+            .iter()
+            .all(|stream| stream.samples.len() < 1_000));
+    }
+
+    #[test]
+    fn tracks_tight_targets() {
+        let config = SummaryConfig {
+            quantiles: vec![(0.999, 0.0001), (0.1, 0.01)],
+            ..SummaryConfig::default()
+        };
+        let summary = Summary::with_config("s", HashMap::new(), config);
+        let n = 50_000;
+        observe_scrambled(&summary, n);
+
+        let p999 = summary.get_quantile(0.999).unwrap();
+        assert!((p999 / n as f64 - 0.999).abs() <= 0.0001, "{}", p999);
+        let p10 = summary.get_quantile(0.1).unwrap();
+        assert!((p10 / n as f64 - 0.1).abs() <= 0.01, "{}", p10);
+    }
+
+    #[test]
+    fn expires_observations_with_the_window() {
+        let config = SummaryConfig {
+            quantiles: vec![(0.5, 0.01)],
+            max_age: Duration::from_secs(20),
+            age_buckets: 2,
+        };
+        let summary = Summary::with_config("s", HashMap::new(), config);
+        let mut state = summary.state.lock().unwrap();
+        let start = state.head_expires - Duration::from_secs(10);
+        let at = |secs| start + Duration::from_secs(secs);
+        let median = |state: &State| state.streams[state.head].query(&summary.targets, 0.5);
+
+        state.buffer.push(1.0);
+        summary.flush(&mut state, at(15));
+        // Half a window on, the observation still counts.
+        assert_eq!(median(&state), Some(1.0));
+
+        state.buffer.extend([5.0, 5.0]);
+        summary.flush(&mut state, at(16));
+        assert_eq!(median(&state), Some(5.0));
+
+        // A window after the first observation, only the later ones count.
+        summary.flush(&mut state, at(25));
+        let head = &state.streams[state.head];
+        assert_eq!(head.n, 2.0);
+        assert_eq!(median(&state), Some(5.0));
+
+        summary.flush(&mut state, at(35));
+        assert_eq!(median(&state), None);
+    }
+
+    #[test]
+    fn snapshots_nan_quantiles_while_empty() {
+        let summary = Summary::new("s", HashMap::new());
+        let snapshot = summary.snapshot();
+        assert_eq!(snapshot.count, 0);
+        assert_eq!(snapshot.quantiles.len(), 3);
+        assert!(snapshot.quantiles.iter().all(|(_, value)| value.is_nan()));
+
+        summary.observe(f64::NAN);
+        summary.observe(2.0);
+        let snapshot = summary.snapshot();
+        assert_eq!((snapshot.count, snapshot.sum), (1, 2.0));
+        assert_eq!(snapshot.quantiles[0], (0.5, 2.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "quantiles must be between 0 and 1")]
+    fn rejects_invalid_errors() {
+        let config = SummaryConfig {
+            quantiles: vec![(0.5, 0.0)],
+            ..SummaryConfig::default()
+        };
+        Summary::with_config("s", HashMap::new(), config);
+    }
+}
@@ -1,30 +1,42 @@
-
-
 use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
-use super::Metric;
+use super::histogram::HistogramSnapshot;
+use super::{Histogram, Metric, ObserveDuration};
 
 /// A timer metric to measure durations.
+///
+/// Durations are recorded in seconds into a bucketed histogram. For precise
+/// percentiles, record durations in an [`HdrHistogram`](super::HdrHistogram)
+/// instead, with [`HdrHistogram::start_timer`](super::HdrHistogram::start_timer)
+/// or an [`AsyncTimer`](super::async_timer::AsyncTimer).
 pub struct Timer {
     name: String,
     labels: HashMap<String, String>,
-    observations: Mutex<Vec<Duration>>,
+    histogram: Histogram,
 }
 
 impl Timer {
-    /// Creates a new timer.
+    /// Creates a new timer with the default buckets.
     pub fn new(name: &str, labels: HashMap<String, String>) -> Self {
         Timer {
             name: name.to_string(),
+            histogram: Histogram::new(name, labels.clone()),
+            labels,
+        }
+    }
+
+    /// Creates a new timer with the given bucket upper bounds, in seconds.
+    pub fn with_buckets(name: &str, labels: HashMap<String, String>, buckets: Vec<f64>) -> Self {
+        Timer {
+            name: name.to_string(),
+            histogram: Histogram::with_buckets(name, labels.clone(), buckets),
             labels,
-            observations: Mutex::new(Vec::new()),
         }
     }
 
     /// Starts a timing operation.
-    pub fn start(&self) -> TimerHandle {
+    pub fn start(&self) -> TimerHandle<'_> {
         TimerHandle {
             start_time: Instant::now(),
             timer: self,
@@ -33,14 +45,51 @@ impl Timer {
 
     /// Observes a duration.
     pub fn observe_duration(&self, duration: Duration) {
-        let mut obs = self.observations.lock().unwrap();
-        obs.push(duration);
+        self.histogram.observe(duration.as_secs_f64());
+    }
+
+    /// Observes a duration, keeping an exemplar with the given labels, e.g. a
+    /// trace ID.
+    pub fn observe_duration_with_exemplar(
+        &self,
+        duration: Duration,
+        exemplar_labels: &[(&str, &str)],
+    ) {
+        self.histogram
+            .observe_with_exemplar(duration.as_secs_f64(), exemplar_labels);
+    }
+
+    /// Gets the time the timer was created.
+    pub fn created(&self) -> SystemTime {
+        self.histogram.created()
+    }
+
+    /// Gets the estimated percentile duration.
+    pub fn get_percentile(&self, percentile: f64) -> Option<Duration> {
+        self.histogram
+            .get_percentile(percentile)
+            .map(Duration::from_secs_f64)
+    }
+
+    /// Gets a point-in-time copy of the buckets and statistics, in seconds.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        self.histogram.snapshot()
     }
 
     /// Gets a summary of the timer data.
     pub fn get_summary(&self) -> TimerSummary {
-        let obs = self.observations.lock().unwrap();
-        TimerSummary::from_observations(&obs)
+        let summary = self.histogram.get_summary();
+        if summary.count == 0 {
+            return TimerSummary::from_observations(&[]);
+        }
+        TimerSummary {
+            count: summary.count,
+            sum: Duration::from_secs_f64(summary.sum),
+            min: Duration::from_secs_f64(summary.min),
+            max: Duration::from_secs_f64(summary.max),
+            mean: Duration::from_secs_f64(summary.mean),
+            std_dev: Duration::from_secs_f64(summary.std_dev),
+        }
     }
 }
 
@@ -54,6 +103,12 @@ impl Metric for Timer {
     }
 }
 
+impl ObserveDuration for Timer {
+    fn observe_duration(&self, duration: Duration) {
+        Timer::observe_duration(self, duration);
+    }
+}
+
 /// A handle to a timing operation.
 pub struct TimerHandle<'a> {
     start_time: Instant,
@@ -98,7 +153,7 @@ impl TimerSummary {
                 diff * diff
             })
             .sum::<f64>()
-            / count as f64;
+            / count.max(1) as f64;
         let std_dev_secs = variance.sqrt();
         let std_dev = Duration::from_secs_f64(std_dev_secs);
 
@@ -1,18 +1,71 @@
+use crate::metrics::HdrConfig;
+use crate::middleware::http_metrics::{HttpMetrics, RequestRecord};
+use crate::middleware::path::PathLabels;
 use crate::registry::Registry;
+use actix_web::body::{BodySize, MessageBody};
 use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::CONTENT_LENGTH;
 use actix_web::Error;
 use futures::future::{ok, LocalBoxFuture, Ready};
 use futures::task::Context;
 use futures::task::Poll;
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Instant;
+
+/// Actix-web middleware recording RED metrics for every request:
+///
+/// - `http_requests_total`, a counter,
+/// - `http_request_duration_seconds`, a histogram, or a summary with
+///   [`MetricsMiddleware::with_hdr`],
+/// - `http_request_size_bytes` and `http_response_size_bytes`, histograms
+///   recorded when the body size is known,
+///
+/// all labelled by `method`, `path`, `status` and `status_class` (e.g.
+/// `5xx`), and `http_requests_in_flight`, a gauge labelled by `method` and
+/// `path`. Requests failing with an error are recorded with the status of
+/// the error's response.
+///
+/// The `path` label is the matched route pattern, e.g. `/users/{id}`; see
+/// [`PathLabels`] for requests matching no route.
 pub struct MetricsMiddleware {
-    registry: Arc<Registry>,
+    metrics: HttpMetrics,
+    path_labels: PathLabels,
 }
 
 impl MetricsMiddleware {
+    /// Creates the middleware, registering its metrics in the registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names.
     pub fn new(registry: Arc<Registry>) -> Self {
-        MetricsMiddleware { registry }
+        MetricsMiddleware {
+            metrics: HttpMetrics::new(&registry),
+            path_labels: PathLabels::new(),
+        }
+    }
+
+    /// Creates the middleware, registering its metrics in the registry, with
+    /// `http_request_duration_seconds` recorded in an
+    /// [`HdrHistogram`](crate::metrics::HdrHistogram) and exported as a
+    /// summary of recent percentiles.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names, or if the configuration is invalid.
+    pub fn with_hdr(registry: Arc<Registry>, config: HdrConfig) -> Self {
+        MetricsMiddleware {
+            metrics: HttpMetrics::with_hdr(&registry, config),
+            path_labels: PathLabels::new(),
+        }
+    }
+
+    /// Sets how the `path` label is chosen.
+    pub fn with_path_labels(mut self, path_labels: PathLabels) -> Self {
+        self.path_labels = path_labels;
+        self
     }
 }
 
@@ -20,7 +73,7 @@ impl<S, B> Transform<S, ServiceRequest> for MetricsMiddleware
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
     type Response = ServiceResponse<B>;
     type Error = Error;
@@ -31,21 +84,23 @@ where
     fn new_transform(&self, service: S) -> Self::Future {
         ok(MetricsMiddlewareService {
             service,
-            registry: self.registry.clone(),
+            metrics: self.metrics.clone(),
+            path_labels: self.path_labels.clone(),
         })
     }
 }
 
 pub struct MetricsMiddlewareService<S> {
     service: S,
-    registry: Arc<Registry>,
+    metrics: HttpMetrics,
+    path_labels: PathLabels,
 }
 
 impl<S, B> Service<ServiceRequest> for MetricsMiddlewareService<S>
 where
     S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
     S: 'static,
-    B: 'static,
+    B: MessageBody + 'static,
 {
     type Response = ServiceResponse<B>;
     type Error = Error;
@@ -56,24 +111,175 @@ where
     }
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let registry = self.registry.clone();
+        let metrics = self.metrics.clone();
         let method = req.method().to_string();
-        let path = req.path().to_string();
+        let path = self
+            .path_labels
+            .label(req.match_pattern().as_deref(), req.path());
+        let request_size = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
 
+        let in_flight = metrics.start(&method, &path);
+        let start = Instant::now();
         let fut = self.service.call(req);
 
         Box::pin(async move {
-            let res = fut.await?;
-
-            // Record metrics
-            let mut labels = HashMap::new();
-            labels.insert("method".to_string(), method);
-            labels.insert("path".to_string(), path);
+            let result = fut.await;
+            drop(in_flight);
 
-            let counter = registry.register_counter("http_requests_total", labels);
-            counter.increment();
+            let (status, response_size) = match &result {
+                Ok(res) => {
+                    let size = match res.response().body().size() {
+                        BodySize::None => Some(0),
+                        BodySize::Sized(size) => Some(size),
+                        BodySize::Stream => None,
+                    };
+                    (res.status().as_u16(), size)
+                }
+                Err(e) => (e.as_response_error().status_code().as_u16(), None),
+            };
+            metrics.record(&RequestRecord {
+                method: &method,
+                path: &path,
+                status,
+                duration: start.elapsed(),
+                request_size,
+                response_size,
+            });
 
-            Ok(res)
+            result
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::middleware::path::normalize_ids;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, ResponseError};
+    use std::fmt;
+
+    #[derive(Debug)]
+    struct Conflict;
+
+    impl fmt::Display for Conflict {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("conflict")
+        }
+    }
+
+    impl ResponseError for Conflict {
+        fn status_code(&self) -> StatusCode {
+            StatusCode::CONFLICT
+        }
+    }
+
+    fn count(registry: &Registry, labels: &[&str]) -> u64 {
+        registry
+            .counter_family(
+                "http_requests_total",
+                &["method", "path", "status", "status_class"],
+            )
+            .with_label_values(labels)
+            .get()
+    }
+
+    #[actix_web::test]
+    async fn records_requests_by_status() {
+        let registry = Arc::new(Registry::new());
+        let app = test::init_service(
+            App::new()
+                .wrap(MetricsMiddleware::new(registry.clone()))
+                .route("/orders", web::get().to(|| async { "orders" }))
+                .route(
+                    "/orders",
+                    web::post().to(|| async { Err::<&str, _>(Conflict) }),
+                ),
+        )
+        .await;
+
+        for _ in 0..2 {
+            test::call_service(&app, test::TestRequest::get().uri("/orders").to_request()).await;
+        }
+        let response =
+            test::call_service(&app, test::TestRequest::post().uri("/orders").to_request()).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        assert_eq!(count(&registry, &["GET", "/orders", "200", "2xx"]), 2);
+        // Errors are counted with the status of their response.
+        assert_eq!(count(&registry, &["POST", "/orders", "409", "4xx"]), 1);
+        let durations = registry.histogram_family(
+            "http_request_duration_seconds",
+            &["method", "path", "status", "status_class"],
+        );
+        let duration = durations.with_label_values(&["POST", "/orders", "409", "4xx"]);
+        assert_eq!(duration.get_count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn counts_requests_in_flight() {
+        let registry = Arc::new(Registry::new());
+        let in_flight = registry
+            .gauge_family("http_requests_in_flight", &["method", "path"])
+            .with_label_values(&["GET", "/"]);
+        let during = in_flight.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap(MetricsMiddleware::new(registry.clone()))
+                .route(
+                    "/",
+                    web::get().to(move || {
+                        let value = during.get();
+                        async move { value.to_string() }
+                    }),
+                ),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/").to_request();
+        let body = test::call_and_read_body(&app, request).await;
+        assert_eq!(body, "1");
+        assert_eq!(in_flight.get(), 0.0);
+    }
+
+    #[actix_web::test]
+    async fn labels_requests_by_route_template() {
+        let registry = Arc::new(Registry::new());
+        let app = test::init_service(
+            App::new()
+                .wrap(MetricsMiddleware::new(registry.clone()))
+                .route("/users/{id}", web::get().to(|| async { "user" })),
+        )
+        .await;
+
+        for uri in ["/users/1", "/users/2", "/missing/1"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        assert_eq!(count(&registry, &["GET", "/users/{id}", "200", "2xx"]), 2);
+        assert_eq!(count(&registry, &["GET", "unmatched", "404", "4xx"]), 1);
+    }
+
+    #[actix_web::test]
+    async fn normalizes_unmatched_paths() {
+        let registry = Arc::new(Registry::new());
+        let path_labels = PathLabels::new().with_normalizer(normalize_ids);
+        let app = test::init_service(
+            App::new()
+                .wrap(MetricsMiddleware::new(registry.clone()).with_path_labels(path_labels))
+                .route("/users/{id}", web::get().to(|| async { "user" })),
+        )
+        .await;
+
+        for uri in ["/users/1", "/missing/1", "/missing/2"] {
+            test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        }
+
+        assert_eq!(count(&registry, &["GET", "/users/{id}", "200", "2xx"]), 1);
+        assert_eq!(count(&registry, &["GET", "/missing/:id", "404", "4xx"]), 2);
+    }
+}
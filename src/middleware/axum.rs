@@ -1,40 +1,183 @@
 use axum::body::Body;
-use axum::extract::Extension;
+use axum::extract::{MatchedPath, State};
+use axum::http::header::CONTENT_LENGTH;
 use axum::http::Request;
 use axum::middleware::Next;
 use axum::response::Response;
-use std::collections::HashMap;
-use std::sync::Arc;
+use http_body::Body as _;
+use std::time::Instant;
 
+use crate::metrics::HdrConfig;
+use crate::middleware::http_metrics::{HttpMetrics, RequestRecord};
+use crate::middleware::path::PathLabels;
 use crate::registry::Registry;
 
-/// Axum middleware for metrics.
+/// The state of [`metrics_middleware`]: its metric families, registered
+/// once and shared by every request.
+#[derive(Clone)]
+pub struct MetricsState {
+    metrics: HttpMetrics,
+    path_labels: PathLabels,
+}
+
+impl MetricsState {
+    /// Registers the metrics of the middleware in the registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names.
+    pub fn new(registry: &Registry) -> Self {
+        MetricsState {
+            metrics: HttpMetrics::new(registry),
+            path_labels: PathLabels::new(),
+        }
+    }
+
+    /// Registers the metrics of the middleware in the registry, with
+    /// `http_request_duration_seconds` recorded in an
+    /// [`HdrHistogram`](crate::metrics::HdrHistogram) and exported as a
+    /// summary of recent percentiles.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names, or if the configuration is invalid.
+    pub fn with_hdr(registry: &Registry, config: HdrConfig) -> Self {
+        MetricsState {
+            metrics: HttpMetrics::with_hdr(registry, config),
+            path_labels: PathLabels::new(),
+        }
+    }
+
+    /// Sets how the `path` label is chosen.
+    pub fn with_path_labels(mut self, path_labels: PathLabels) -> Self {
+        self.path_labels = path_labels;
+        self
+    }
+}
+
+/// Axum middleware for metrics, for use with
+/// `axum::middleware::from_fn_with_state` and a [`MetricsState`].
+///
+/// It records the same metrics as
+/// [`MetricsLayer`](crate::middleware::tower::MetricsLayer), but only until
+/// the response is returned: the time spent streaming the body and the
+/// size of streamed bodies are not included. Prefer `MetricsLayer`.
+///
+/// The `path` label is the matched route, e.g. `/users/:id`, which is only
+/// known when the middleware is added with `Router::layer` or
+/// `Router::route_layer`. Requests matching no route are labelled as
+/// configured by the state's [`PathLabels`].
+///
+/// ```no_run
+/// use axum::{middleware::from_fn_with_state, routing::get, Router};
+/// use metrix::middleware::axum::{metrics_middleware, MetricsState};
+/// use metrix::registry::Registry;
+///
+/// let registry = Registry::new();
+/// let app: Router = Router::new()
+///     .route("/users/:id", get(|| async { "user" }))
+///     .layer(from_fn_with_state(MetricsState::new(&registry), metrics_middleware));
+/// ```
 pub async fn metrics_middleware(
+    State(state): State<MetricsState>,
     req: Request<Body>,
     next: Next,
-    Extension(registry): Extension<Arc<Registry>>,
 ) -> Response {
-    let path = req.uri().path().to_string();
-
-    let labels = {
-        let mut labels = HashMap::new();
-        labels.insert("path".to_string(), path.clone());
-        labels
-    };
-
-    // Start timer
-    let timer = registry.register_timer("http_request_duration_seconds", labels.clone());
-    let handle = timer.start();
+    let matched = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str);
+    let path = state.path_labels.label(matched, req.uri().path());
+    let method = req.method().to_string();
+    let request_size = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
 
-    // Increment request counter
-    let counter = registry.register_counter("http_requests_total", labels.clone());
-    counter.increment();
+    let in_flight = state.metrics.start(&method, &path);
+    let start = Instant::now();
 
     // Proceed to the next middleware or handler
     let response = next.run(req).await;
 
-    // Stop timer
-    handle.stop();
+    state.metrics.record(&RequestRecord {
+        method: &method,
+        path: &path,
+        status: response.status().as_u16(),
+        duration: start.elapsed(),
+        request_size,
+        response_size: response.body().size_hint().exact(),
+    });
+    drop(in_flight);
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::MetricKind;
+    use axum::middleware::from_fn_with_state;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn records_requests_by_route() {
+        let registry = Registry::new();
+        let app: Router = Router::new()
+            .route("/users/:id", get(|| async { "user" }))
+            .layer(from_fn_with_state(
+                MetricsState::new(&registry),
+                metrics_middleware,
+            ));
+
+        for uri in ["/users/1", "/users/2", "/missing"] {
+            let request = Request::get(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let requests = registry.counter_family(
+            "http_requests_total",
+            &["method", "path", "status", "status_class"],
+        );
+        let count = |path, status| {
+            requests
+                .with_label_values(&["GET", path, status, &format!("{}xx", &status[..1])])
+                .get()
+        };
+        assert_eq!(count("/users/:id", "200"), 2);
+        assert_eq!(count("unmatched", "404"), 1);
+        assert_eq!(requests.children().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn records_durations_in_hdr_histograms() {
+        let registry = Registry::new();
+        let state = MetricsState::with_hdr(&registry, HdrConfig::default());
+        let app: Router = Router::new()
+            .route("/", get(|| async { "home" }))
+            .layer(from_fn_with_state(state, metrics_middleware));
+
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap();
+
+        let durations = registry.hdr_histogram_family(
+            "http_request_duration_seconds",
+            &["method", "path", "status", "status_class"],
+        );
+        let duration = durations.with_label_values(&["GET", "/", "200", "2xx"]);
+        assert_eq!(duration.get_count(), 1);
+        // No classic histogram is registered under the same name.
+        let snapshot = registry.snapshot();
+        let families: Vec<_> = snapshot
+            .iter()
+            .filter(|family| family.name == "http_request_duration_seconds")
+            .collect();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].kind, MetricKind::Summary);
+    }
+}
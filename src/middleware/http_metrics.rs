@@ -0,0 +1,168 @@
+use crate::metrics::{
+    Counter, Gauge, HdrConfig, HdrHistogram, Histogram, MetricFamily, ObserveDuration, Unit,
+};
+use crate::registry::Registry;
+use crate::utils::buckets::exponential_buckets;
+use std::sync::Arc;
+use std::time::Duration;
+
+const REQUESTS_TOTAL: &str = "http_requests_total";
+const REQUEST_DURATION: &str = "http_request_duration_seconds";
+const REQUESTS_IN_FLIGHT: &str = "http_requests_in_flight";
+const REQUEST_SIZE: &str = "http_request_size_bytes";
+const RESPONSE_SIZE: &str = "http_response_size_bytes";
+
+const LABELS: &[&str] = &["method", "path", "status", "status_class"];
+
+/// The RED metric families recorded by the HTTP middlewares.
+#[derive(Clone)]
+pub(crate) struct HttpMetrics {
+    requests: Arc<MetricFamily<Counter>>,
+    duration: Durations,
+    in_flight: Arc<MetricFamily<Gauge>>,
+    request_size: Arc<MetricFamily<Histogram>>,
+    response_size: Arc<MetricFamily<Histogram>>,
+}
+
+/// The family request durations are recorded in.
+#[derive(Clone)]
+enum Durations {
+    Histogram(Arc<MetricFamily<Histogram>>),
+    Hdr(Arc<MetricFamily<HdrHistogram>>),
+}
+
+impl HttpMetrics {
+    /// Registers the families and their descriptions in the registry, with
+    /// request durations recorded in a histogram.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a metric with one of the names was registered with other
+    /// label names.
+    pub(crate) fn new(registry: &Registry) -> Self {
+        let duration = Durations::Histogram(registry.histogram_family(REQUEST_DURATION, LABELS));
+        Self::register(registry, duration)
+    }
+
+    /// Registers the families and their descriptions in the registry, with
+    /// request durations recorded in an HDR histogram with the given
+    /// configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a metric with one of the names was registered with other
+    /// label names, or if the configuration is invalid, see
+    /// [`HdrHistogram::with_config`].
+    pub(crate) fn with_hdr(registry: &Registry, config: HdrConfig) -> Self {
+        let duration = Durations::Hdr(registry.hdr_histogram_family_with_config(
+            REQUEST_DURATION,
+            LABELS,
+            config,
+        ));
+        Self::register(registry, duration)
+    }
+
+    fn register(registry: &Registry, duration: Durations) -> Self {
+        // 64 bytes up to 16 MiB.
+        let size_buckets = exponential_buckets(64.0, 4.0, 10);
+
+        registry.describe(REQUESTS_TOTAL, "Total number of HTTP requests.", None);
+        registry.describe(
+            REQUEST_DURATION,
+            "Duration of HTTP requests.",
+            Some(Unit::Seconds),
+        );
+        registry.describe(
+            REQUESTS_IN_FLIGHT,
+            "Number of HTTP requests being served.",
+            None,
+        );
+        registry.describe(
+            REQUEST_SIZE,
+            "Size of HTTP request bodies.",
+            Some(Unit::Bytes),
+        );
+        registry.describe(
+            RESPONSE_SIZE,
+            "Size of HTTP response bodies.",
+            Some(Unit::Bytes),
+        );
+
+        HttpMetrics {
+            requests: registry.counter_family(REQUESTS_TOTAL, LABELS),
+            duration,
+            in_flight: registry.gauge_family(REQUESTS_IN_FLIGHT, &["method", "path"]),
+            request_size: registry.histogram_family_with_buckets(
+                REQUEST_SIZE,
+                LABELS,
+                size_buckets.clone(),
+            ),
+            response_size: registry.histogram_family_with_buckets(
+                RESPONSE_SIZE,
+                LABELS,
+                size_buckets,
+            ),
+        }
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub(crate) fn start(&self, method: &str, path: &str) -> InFlight {
+        let gauge = self.in_flight.with_label_values(&[method, path]);
+        gauge.increment();
+        InFlight { gauge }
+    }
+
+    /// Records a finished request. Body sizes are only recorded when known.
+    pub(crate) fn record(&self, request: &RequestRecord<'_>) {
+        let status = request.status.to_string();
+        let status_class = format!("{}xx", request.status / 100);
+        let labels = [
+            request.method,
+            request.path,
+            status.as_str(),
+            status_class.as_str(),
+        ];
+
+        self.requests.with_label_values(&labels).increment();
+        match &self.duration {
+            Durations::Histogram(family) => family
+                .with_label_values(&labels)
+                .observe_duration(request.duration),
+            Durations::Hdr(family) => family
+                .with_label_values(&labels)
+                .observe_duration(request.duration),
+        }
+        if let Some(size) = request.request_size {
+            self.request_size
+                .with_label_values(&labels)
+                .observe(size as f64);
+        }
+        if let Some(size) = request.response_size {
+            self.response_size
+                .with_label_values(&labels)
+                .observe(size as f64);
+        }
+    }
+}
+
+/// A finished request, as recorded by [`HttpMetrics::record`].
+pub(crate) struct RequestRecord<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status: u16,
+    pub duration: Duration,
+    pub request_size: Option<u64>,
+    pub response_size: Option<u64>,
+}
+
+/// Decrements the in-flight gauge when dropped, including when the request
+/// future is cancelled.
+pub(crate) struct InFlight {
+    gauge: Arc<Gauge>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.gauge.decrement();
+    }
+}
@@ -1,2 +1,5 @@
 pub mod actix_middleware;
 pub mod axum;
+mod http_metrics;
+pub mod path;
+pub mod tower;
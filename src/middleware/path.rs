@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+/// Turns the path of a request that matched no route into a label value.
+pub type PathNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// How the middlewares choose the `path` label of a request.
+///
+/// Requests are labelled by the template of the route they matched, e.g.
+/// `/users/{id}`, so the number of series stays bounded. Requests matching
+/// no route are labelled with the fallback value `unmatched`, or by the
+/// normalizer if one is set.
+#[derive(Clone)]
+pub struct PathLabels {
+    unmatched: String,
+    normalizer: Option<PathNormalizer>,
+}
+
+impl PathLabels {
+    /// Creates the default configuration.
+    pub fn new() -> Self {
+        PathLabels {
+            unmatched: "unmatched".to_string(),
+            normalizer: None,
+        }
+    }
+
+    /// Sets the label value of requests matching no route.
+    pub fn with_unmatched(mut self, label: &str) -> Self {
+        self.unmatched = label.to_string();
+        self
+    }
+
+    /// Labels requests matching no route by their path as rewritten by the
+    /// normalizer, e.g. [`normalize_ids`], instead of the fallback value.
+    pub fn with_normalizer<F>(mut self, normalizer: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.normalizer = Some(Arc::new(normalizer));
+        self
+    }
+
+    /// Gets the label value of a request with the given matched route
+    /// template and raw path.
+    pub fn label(&self, matched: Option<&str>, path: &str) -> String {
+        match (matched, &self.normalizer) {
+            (Some(template), _) => template.to_string(),
+            (None, Some(normalizer)) => normalizer(path),
+            (None, None) => self.unmatched.clone(),
+        }
+    }
+}
+
+impl Default for PathLabels {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Replaces path segments that look like identifiers, i.e. numbers, UUIDs
+/// and hex strings of at least 16 digits, with `:id`.
+///
+/// ```
+/// use metrix::middleware::path::normalize_ids;
+///
+/// assert_eq!(
+///     normalize_ids("/users/42/orders/0b5c6b3e-7e1a-4f6b-9c1e-3d2f1a0b9c8d"),
+///     "/users/:id/orders/:id"
+/// );
+/// ```
+pub fn normalize_ids(path: &str) -> String {
+    path.split('/')
+        .map(|segment| if is_id(segment) { ":id" } else { segment })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_id(segment: &str) -> bool {
+    if segment.is_empty() {
+        return false;
+    }
+    if segment.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    let hex = segment.replace('-', "");
+    let is_uuid = segment.len() == 36 && hex.len() == 32;
+    (is_uuid || (segment.len() >= 16 && hex.len() == segment.len()))
+        && hex.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_ids() {
+        assert!(is_id("42"));
+        assert!(is_id("0b5c6b3e-7e1a-4f6b-9c1e-3d2f1a0b9c8d"));
+        assert!(is_id("0B5C6B3E-7E1A-4F6B-9C1E-3D2F1A0B9C8D"));
+        assert!(is_id("0b5c6b3e7e1a4f6b"));
+        assert!(is_id("0b5c6b3e7e1a4f6b9c1e3d2f1a0b9c8d"));
+
+        assert!(!is_id(""));
+        assert!(!is_id("users"));
+        assert!(!is_id("v2"));
+        assert!(!is_id("-42"));
+        // Hex strings shorter than 16 digits may be words, e.g. `cafe`.
+        assert!(!is_id("deadbeef"));
+        assert!(!is_id("0b5c6b3e-7e1a-4f6b-9c1e-3d2f1a0b9c8g"));
+        assert!(!is_id("0b5c6b3e7e1a4f6b-"));
+    }
+
+    #[test]
+    fn normalizes_ids_in_every_segment() {
+        assert_eq!(normalize_ids("/"), "/");
+        assert_eq!(normalize_ids("/users"), "/users");
+        assert_eq!(normalize_ids("/users/42/"), "/users/:id/");
+        assert_eq!(
+            normalize_ids("/v2/orders/7/items/0b5c6b3e7e1a4f6b"),
+            "/v2/orders/:id/items/:id"
+        );
+    }
+
+    #[test]
+    fn labels_by_template_then_fallback() {
+        let labels = PathLabels::new();
+        assert_eq!(labels.label(Some("/users/{id}"), "/users/1"), "/users/{id}");
+        assert_eq!(labels.label(None, "/users/1"), "unmatched");
+        assert_eq!(
+            labels.with_unmatched("other").label(None, "/users/1"),
+            "other"
+        );
+
+        let labels = PathLabels::new().with_normalizer(normalize_ids);
+        assert_eq!(labels.label(Some("/users/{id}"), "/users/1"), "/users/{id}");
+        assert_eq!(labels.label(None, "/users/1"), "/users/:id");
+    }
+}
@@ -0,0 +1,381 @@
+use crate::metrics::HdrConfig;
+use crate::middleware::http_metrics::{HttpMetrics, InFlight, RequestRecord};
+use crate::middleware::path::PathLabels;
+use crate::registry::Registry;
+use axum::extract::MatchedPath;
+use bytes::Buf;
+use http_body::{Body, Frame, SizeHint};
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Request, Response};
+use pin_project_lite::pin_project;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Instant;
+use tower::{Layer, Service};
+
+/// A `tower` layer recording RED metrics for every request, usable with
+/// axum, hyper and tonic.
+///
+/// It records the same metrics as the actix-web
+/// [`MetricsMiddleware`](crate::middleware::actix_middleware::MetricsMiddleware).
+/// A request is recorded once its response body has been streamed to the
+/// end or dropped, so the duration includes the time spent sending the
+/// body.
+///
+/// The `path` label is the axum `MatchedPath`, e.g. `/users/:id`, which is
+/// only known when the layer is added with `Router::layer` or
+/// `Router::route_layer`. Other requests, e.g. with hyper or tonic, are
+/// labelled as configured by [`PathLabels`].
+///
+/// ```no_run
+/// use axum::{routing::get, Router};
+/// use metrix::middleware::tower::MetricsLayer;
+/// use metrix::registry::Registry;
+/// use std::sync::Arc;
+///
+/// let registry = Arc::new(Registry::new());
+/// let app: Router = Router::new()
+///     .route("/users/:id", get(|| async { "user" }))
+///     .layer(MetricsLayer::new(registry));
+/// ```
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: HttpMetrics,
+    path_labels: PathLabels,
+}
+
+impl MetricsLayer {
+    /// Creates the layer, registering its metrics in the registry.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names.
+    pub fn new(registry: Arc<Registry>) -> Self {
+        MetricsLayer {
+            metrics: HttpMetrics::new(&registry),
+            path_labels: PathLabels::new(),
+        }
+    }
+
+    /// Creates the layer, registering its metrics in the registry, with
+    /// `http_request_duration_seconds` recorded in an
+    /// [`HdrHistogram`](crate::metrics::HdrHistogram) and exported as a
+    /// summary of recent percentiles.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names, or if the configuration is invalid.
+    pub fn with_hdr(registry: Arc<Registry>, config: HdrConfig) -> Self {
+        MetricsLayer {
+            metrics: HttpMetrics::with_hdr(&registry, config),
+            path_labels: PathLabels::new(),
+        }
+    }
+
+    /// Sets how the `path` label is chosen.
+    pub fn with_path_labels(mut self, path_labels: PathLabels) -> Self {
+        self.path_labels = path_labels;
+        self
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+            path_labels: self.path_labels.clone(),
+        }
+    }
+}
+
+/// The service built by [`MetricsLayer`].
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: HttpMetrics,
+    path_labels: PathLabels,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    ResBody: Body,
+{
+    type Response = Response<MetricsBody<ResBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let matched = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str);
+        let path = self.path_labels.label(matched, req.uri().path());
+        let method = req.method().to_string();
+        let request_size = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+
+        let state = RequestState {
+            in_flight: self.metrics.start(&method, &path),
+            metrics: self.metrics.clone(),
+            method,
+            path,
+            request_size,
+            start: Instant::now(),
+        };
+        ResponseFuture {
+            inner: self.inner.call(req),
+            state: Some(state),
+        }
+    }
+}
+
+pin_project! {
+    /// The response future of [`MetricsService`].
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        state: Option<RequestState>,
+    }
+}
+
+impl<F, ResBody, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<ResBody>, E>>,
+    ResBody: Body,
+{
+    type Output = Result<Response<MetricsBody<ResBody>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let result = ready!(this.inner.poll(cx));
+        let state = this.state.take().expect("polled after completion");
+        Poll::Ready(match result {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                Ok(response.map(|inner| MetricsBody {
+                    inner,
+                    pending: Pending {
+                        state: Some(state),
+                        status,
+                        bytes: 0,
+                    },
+                }))
+            }
+            Err(e) => {
+                // No response was produced; count it as a server error.
+                state.finish(500, None);
+                Err(e)
+            }
+        })
+    }
+}
+
+pin_project! {
+    /// A response body that records its request once it has been streamed
+    /// to the end or dropped.
+    pub struct MetricsBody<B> {
+        #[pin]
+        inner: B,
+        pending: Pending,
+    }
+}
+
+impl<B: Body> Body for MetricsBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    this.pending.bytes += data.remaining() as u64;
+                }
+            }
+            Some(Err(_)) | None => this.pending.finish(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A request whose response is being sent.
+struct RequestState {
+    metrics: HttpMetrics,
+    method: String,
+    path: String,
+    request_size: Option<u64>,
+    start: Instant,
+    in_flight: InFlight,
+}
+
+impl RequestState {
+    fn finish(self, status: u16, response_size: Option<u64>) {
+        self.metrics.record(&RequestRecord {
+            method: &self.method,
+            path: &self.path,
+            status,
+            duration: self.start.elapsed(),
+            request_size: self.request_size,
+            response_size,
+        });
+        drop(self.in_flight);
+    }
+}
+
+/// Records the request at most once, when the body ends or at the latest
+/// when it is dropped.
+struct Pending {
+    state: Option<RequestState>,
+    status: u16,
+    bytes: u64,
+}
+
+impl Pending {
+    fn finish(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.finish(self.status, Some(self.bytes));
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::channel::mpsc;
+    use http_body_util::{BodyExt, Empty, StreamBody};
+    use std::convert::Infallible;
+    use tower::{service_fn, ServiceExt};
+
+    type Sender = mpsc::UnboundedSender<Result<Frame<Bytes>, Infallible>>;
+
+    const LABELS: &[&str] = &["method", "path", "status", "status_class"];
+
+    /// Sends a request through the layer to a service streaming the frames
+    /// sent on the returned channel.
+    async fn call(
+        registry: &Arc<Registry>,
+    ) -> (
+        Sender,
+        Response<MetricsBody<impl Body<Data = Bytes, Error = Infallible>>>,
+    ) {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut receiver = Some(receiver);
+        let service = MetricsLayer::new(registry.clone()).layer(service_fn(
+            move |_: Request<Empty<Bytes>>| {
+                let body = StreamBody::new(receiver.take().unwrap());
+                async move { Ok::<_, Infallible>(Response::new(body)) }
+            },
+        ));
+        let request = Request::post("/upload")
+            .header(CONTENT_LENGTH, "3")
+            .body(Empty::new())
+            .unwrap();
+        (sender, service.oneshot(request).await.unwrap())
+    }
+
+    fn send(sender: &Sender, data: &'static str) {
+        sender
+            .unbounded_send(Ok(Frame::data(Bytes::from(data))))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn records_requests_once_the_body_ends() {
+        let registry = Arc::new(Registry::new());
+        let requests = registry.counter_family("http_requests_total", LABELS);
+        let durations = registry.histogram_family("http_request_duration_seconds", LABELS);
+        let in_flight = registry
+            .gauge_family("http_requests_in_flight", &["method", "path"])
+            .with_label_values(&["POST", "unmatched"]);
+        let labels = ["POST", "unmatched", "200", "2xx"];
+
+        let (sender, response) = call(&registry).await;
+        let mut body = response.into_body();
+        send(&sender, "hello");
+        send(&sender, " world");
+        for _ in 0..2 {
+            body.frame().await.unwrap().unwrap();
+        }
+        // The body is still being sent.
+        assert_eq!(requests.with_label_values(&labels).get(), 0);
+        assert_eq!(in_flight.get(), 1.0);
+
+        drop(sender);
+        assert!(body.frame().await.is_none());
+        assert_eq!(requests.with_label_values(&labels).get(), 1);
+        assert_eq!(durations.with_label_values(&labels).get_count(), 1);
+        assert_eq!(in_flight.get(), 0.0);
+        let response_size = registry
+            .histogram_family("http_response_size_bytes", LABELS)
+            .with_label_values(&labels);
+        assert_eq!(
+            (response_size.get_count(), response_size.get_sum()),
+            (1, 11.0)
+        );
+        let request_size = registry
+            .histogram_family("http_request_size_bytes", LABELS)
+            .with_label_values(&labels);
+        assert_eq!(request_size.get_sum(), 3.0);
+
+        // Dropping the finished body records nothing more.
+        drop(body);
+        assert_eq!(requests.with_label_values(&labels).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn records_requests_when_the_body_is_dropped() {
+        let registry = Arc::new(Registry::new());
+        let labels = ["POST", "unmatched", "200", "2xx"];
+
+        let (sender, response) = call(&registry).await;
+        let mut body = response.into_body();
+        send(&sender, "hello");
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+
+        let requests = registry.counter_family("http_requests_total", LABELS);
+        assert_eq!(requests.with_label_values(&labels).get(), 1);
+        let response_size = registry
+            .histogram_family("http_response_size_bytes", LABELS)
+            .with_label_values(&labels);
+        assert_eq!(response_size.get_sum(), 5.0);
+        let in_flight = registry
+            .gauge_family("http_requests_in_flight", &["method", "path"])
+            .with_label_values(&["POST", "unmatched"]);
+        assert_eq!(in_flight.get(), 0.0);
+    }
+}
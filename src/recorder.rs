@@ -0,0 +1,238 @@
+// src/recorder.rs
+
+use crate::metrics::{Counter, Gauge, Histogram, Unit};
+use crate::registry::Registry;
+use ::metrics::{
+    CounterFn, GaugeFn, HistogramFn, Key, KeyName, Metadata, Recorder, SetRecorderError,
+    SharedString,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A [`metrics`](::metrics) facade recorder backed by a registry, so that
+/// everything emitted through `metrics::counter!`, `gauge!` and
+/// `histogram!` is exported with the registry's own metrics.
+///
+/// Key labels become metric labels, and descriptions and units are stored
+/// as the registry's descriptors. A key whose label names differ from those
+/// the metric was first registered with is ignored.
+///
+/// ```
+/// use metrix::recorder::RegistryRecorder;
+/// use metrix::registry::Registry;
+/// use std::sync::Arc;
+///
+/// let registry = Arc::new(Registry::new());
+/// RegistryRecorder::new(registry.clone()).install().unwrap();
+///
+/// metrics::describe_counter!("jobs_processed", "Jobs processed.");
+/// metrics::counter!("jobs_processed", "queue" => "default").increment(1);
+/// ```
+pub struct RegistryRecorder {
+    registry: Arc<Registry>,
+}
+
+impl RegistryRecorder {
+    /// Creates a recorder backed by the registry.
+    pub fn new(registry: Arc<Registry>) -> Self {
+        RegistryRecorder { registry }
+    }
+
+    /// Installs the recorder as the global `metrics` recorder.
+    pub fn install(self) -> Result<(), SetRecorderError<Self>> {
+        ::metrics::set_global_recorder(self)
+    }
+
+    fn describe(&self, key: KeyName, unit: Option<::metrics::Unit>, description: SharedString) {
+        self.registry
+            .describe(key.as_str(), &description, unit.map(convert_unit));
+    }
+}
+
+impl Recorder for RegistryRecorder {
+    fn describe_counter(
+        &self,
+        key: KeyName,
+        unit: Option<::metrics::Unit>,
+        description: SharedString,
+    ) {
+        self.describe(key, unit, description);
+    }
+
+    fn describe_gauge(
+        &self,
+        key: KeyName,
+        unit: Option<::metrics::Unit>,
+        description: SharedString,
+    ) {
+        self.describe(key, unit, description);
+    }
+
+    fn describe_histogram(
+        &self,
+        key: KeyName,
+        unit: Option<::metrics::Unit>,
+        description: SharedString,
+    ) {
+        self.describe(key, unit, description);
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> ::metrics::Counter {
+        match self.registry.try_register_counter(key.name(), &labels(key)) {
+            Some(counter) => ::metrics::Counter::from_arc(counter),
+            None => ::metrics::Counter::noop(),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> ::metrics::Gauge {
+        match self.registry.try_register_gauge(key.name(), &labels(key)) {
+            Some(gauge) => ::metrics::Gauge::from_arc(gauge),
+            None => ::metrics::Gauge::noop(),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> ::metrics::Histogram {
+        match self
+            .registry
+            .try_register_histogram(key.name(), &labels(key))
+        {
+            Some(histogram) => ::metrics::Histogram::from_arc(histogram),
+            None => ::metrics::Histogram::noop(),
+        }
+    }
+}
+
+impl CounterFn for Counter {
+    fn increment(&self, value: u64) {
+        self.increment_by(value);
+    }
+
+    fn absolute(&self, value: u64) {
+        Counter::absolute(self, value);
+    }
+}
+
+impl GaugeFn for Gauge {
+    fn increment(&self, value: f64) {
+        self.add(value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.add(-value);
+    }
+
+    fn set(&self, value: f64) {
+        Gauge::set(self, value);
+    }
+}
+
+impl HistogramFn for Histogram {
+    fn record(&self, value: f64) {
+        self.observe(value);
+    }
+}
+
+fn labels(key: &Key) -> HashMap<String, String> {
+    key.labels()
+        .map(|label| (label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+fn convert_unit(unit: ::metrics::Unit) -> Unit {
+    match unit {
+        ::metrics::Unit::Count => Unit::Count,
+        ::metrics::Unit::Percent => Unit::Percent,
+        ::metrics::Unit::Seconds => Unit::Seconds,
+        ::metrics::Unit::Milliseconds => Unit::Milliseconds,
+        ::metrics::Unit::Microseconds => Unit::Microseconds,
+        ::metrics::Unit::Nanoseconds => Unit::Nanoseconds,
+        ::metrics::Unit::Bytes => Unit::Bytes,
+        other => Unit::Other(other.as_str().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporters::openmetrics::collect_metrics;
+    use crate::metrics::Descriptor;
+
+    #[test]
+    fn records_facade_metrics_in_the_registry() {
+        let registry = Arc::new(Registry::new());
+        let recorder = RegistryRecorder::new(registry.clone());
+        ::metrics::with_local_recorder(&recorder, || {
+            let counter = ::metrics::counter!("jobs_total", "queue" => "default");
+            counter.increment(2);
+            counter.increment(1);
+            ::metrics::counter!("restarts_total").absolute(7);
+
+            let gauge = ::metrics::gauge!("workers");
+            gauge.set(4.0);
+            gauge.increment(2.0);
+            gauge.decrement(1.5);
+
+            ::metrics::histogram!("job_seconds", "queue" => "default").record(0.25);
+            ::metrics::histogram!("job_seconds", "queue" => "default").record(0.75);
+        });
+
+        let jobs = registry.counter_family("jobs_total", &["queue"]);
+        assert_eq!(jobs.with_label_values(&["default"]).get(), 3);
+        let restarts = registry.register_counter("restarts_total", HashMap::new());
+        assert_eq!(restarts.get(), 7);
+        let workers = registry.register_gauge("workers", HashMap::new());
+        assert_eq!(workers.get(), 4.5);
+        let job_seconds = registry
+            .histogram_family("job_seconds", &["queue"])
+            .with_label_values(&["default"]);
+        assert_eq!((job_seconds.get_count(), job_seconds.get_sum()), (2, 1.0));
+    }
+
+    #[test]
+    fn stores_descriptions_and_units() {
+        let registry = Arc::new(Registry::new());
+        let recorder = RegistryRecorder::new(registry.clone());
+        ::metrics::with_local_recorder(&recorder, || {
+            ::metrics::describe_counter!("jobs_total", "Jobs processed.");
+            ::metrics::describe_gauge!("queue_bytes", ::metrics::Unit::Bytes, "Queued bytes.");
+            ::metrics::describe_histogram!(
+                "job_seconds",
+                ::metrics::Unit::Seconds,
+                "Job duration."
+            );
+            ::metrics::counter!("jobs_total").increment(1);
+            ::metrics::gauge!("queue_bytes").set(512.0);
+            ::metrics::histogram!("job_seconds").record(0.5);
+        });
+
+        assert_eq!(
+            registry.descriptor("queue_bytes"),
+            Some(Descriptor::new("Queued bytes.", Some(Unit::Bytes)))
+        );
+        let output = collect_metrics(&registry);
+        assert!(output.contains("# HELP jobs Jobs processed.\n"));
+        assert!(output.contains("# UNIT queue_bytes bytes\n# HELP queue_bytes Queued bytes.\n"));
+        assert!(output.contains("# UNIT job_seconds seconds\n# HELP job_seconds Job duration.\n"));
+    }
+
+    #[test]
+    fn ignores_keys_with_other_label_names() {
+        let registry = Arc::new(Registry::new());
+        let recorder = RegistryRecorder::new(registry.clone());
+        ::metrics::with_local_recorder(&recorder, || {
+            ::metrics::counter!("jobs_total", "queue" => "default").increment(1);
+            ::metrics::counter!("jobs_total", "worker" => "1").increment(5);
+            ::metrics::counter!("jobs_total").increment(5);
+            ::metrics::gauge!("workers").set(1.0);
+            ::metrics::gauge!("workers", "pool" => "a").set(5.0);
+        });
+
+        let jobs = registry.counter_family("jobs_total", &["queue"]);
+        assert_eq!(jobs.children().len(), 1);
+        assert_eq!(jobs.with_label_values(&["default"]).get(), 1);
+        assert_eq!(
+            registry.register_gauge("workers", HashMap::new()).get(),
+            1.0
+        );
+    }
+}
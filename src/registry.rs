@@ -1,78 +1,563 @@
 // src/registry.rs
 
-use crate::metrics::{Counter, Gauge, Histogram, Meter, Timer};
+use crate::metrics::{
+    Counter, Descriptor, Gauge, HdrConfig, HdrHistogram, Histogram, Info, Meter, MetricFamily,
+    NativeHistogram, NativeHistogramConfig, StateSet, Summary, SummaryConfig, Timer, Unit,
+};
+use crate::snapshot::FamilySnapshot;
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
 
+type Families<M> = RwLock<HashMap<String, Arc<MetricFamily<M>>>>;
+
 /// A registry to manage all metrics.
+///
+/// Metrics are grouped into families: each metric name has a fixed set of
+/// label names, and every distinct set of label values is its own series.
+#[derive(Default)]
 pub struct Registry {
-    pub counters: RwLock<HashMap<String, Arc<Counter>>>,
-    pub gauges: RwLock<HashMap<String, Arc<Gauge>>>,
-    histograms: RwLock<HashMap<String, Arc<Histogram>>>,
-    meters: RwLock<HashMap<String, Arc<Meter>>>,
-    timers: RwLock<HashMap<String, Arc<Timer>>>,
+    counters: Families<Counter>,
+    gauges: Families<Gauge>,
+    histograms: Families<Histogram>,
+    native_histograms: Families<NativeHistogram>,
+    summaries: Families<Summary>,
+    hdr_histograms: Families<HdrHistogram>,
+    meters: Families<Meter>,
+    timers: Families<Timer>,
+    infos: Families<Info>,
+    state_sets: Families<StateSet>,
+    descriptors: RwLock<HashMap<String, Descriptor>>,
 }
 
 impl Registry {
     /// Creates a new registry.
     pub fn new() -> Self {
-        Registry {
-            counters: RwLock::new(HashMap::new()),
-            gauges: RwLock::new(HashMap::new()),
-            histograms: RwLock::new(HashMap::new()),
-            meters: RwLock::new(HashMap::new()),
-            timers: RwLock::new(HashMap::new()),
-        }
+        Self::default()
+    }
+
+    /// Sets the help text and unit of the metric with the given name. The
+    /// description may be given before or after the metric is registered,
+    /// and applies whatever the kind of the metric.
+    pub fn describe(&self, name: &str, help: &str, unit: Option<Unit>) {
+        self.descriptors
+            .write()
+            .unwrap()
+            .insert(name.to_string(), Descriptor::new(help, unit));
+    }
+
+    /// Gets the description of the metric with the given name, if any.
+    pub fn descriptor(&self, name: &str) -> Option<Descriptor> {
+        self.descriptors.read().unwrap().get(name).cloned()
+    }
+
+    /// Registers or retrieves a counter family with the given label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a counter with the same name was registered with different
+    /// label names.
+    pub fn counter_family(&self, name: &str, label_names: &[&str]) -> Arc<MetricFamily<Counter>> {
+        get_or_create_family(&self.counters, name, label_names, Counter::new)
+    }
+
+    /// Registers or retrieves a gauge family with the given label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a gauge with the same name was registered with different
+    /// label names.
+    pub fn gauge_family(&self, name: &str, label_names: &[&str]) -> Arc<MetricFamily<Gauge>> {
+        get_or_create_family(&self.gauges, name, label_names, Gauge::new)
+    }
+
+    /// Registers or retrieves a histogram family with the given label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a histogram with the same name was registered with different
+    /// label names.
+    pub fn histogram_family(
+        &self,
+        name: &str,
+        label_names: &[&str],
+    ) -> Arc<MetricFamily<Histogram>> {
+        get_or_create_family(&self.histograms, name, label_names, Histogram::new)
+    }
+
+    /// Registers or retrieves a histogram family whose series use the given
+    /// bucket upper bounds. If the family already exists, its buckets are
+    /// kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a histogram with the same name was registered with different
+    /// label names.
+    pub fn histogram_family_with_buckets(
+        &self,
+        name: &str,
+        label_names: &[&str],
+        buckets: Vec<f64>,
+    ) -> Arc<MetricFamily<Histogram>> {
+        get_or_create_family(&self.histograms, name, label_names, move |name, labels| {
+            Histogram::with_buckets(name, labels, buckets.clone())
+        })
+    }
+
+    /// Registers or retrieves a native histogram family with the given label
+    /// names and the default configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a native histogram with the same name was registered with
+    /// different label names.
+    pub fn native_histogram_family(
+        &self,
+        name: &str,
+        label_names: &[&str],
+    ) -> Arc<MetricFamily<NativeHistogram>> {
+        get_or_create_family(
+            &self.native_histograms,
+            name,
+            label_names,
+            NativeHistogram::new,
+        )
+    }
+
+    /// Registers or retrieves a native histogram family whose series use the
+    /// given configuration. If the family already exists, its configuration
+    /// is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a native histogram with the same name was registered with
+    /// different label names.
+    pub fn native_histogram_family_with_config(
+        &self,
+        name: &str,
+        label_names: &[&str],
+        config: NativeHistogramConfig,
+    ) -> Arc<MetricFamily<NativeHistogram>> {
+        get_or_create_family(
+            &self.native_histograms,
+            name,
+            label_names,
+            move |name, labels| NativeHistogram::with_config(name, labels, config),
+        )
+    }
+
+    /// Registers or retrieves a summary family with the given label names
+    /// and the default configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a summary with the same name was registered with different
+    /// label names.
+    pub fn summary_family(&self, name: &str, label_names: &[&str]) -> Arc<MetricFamily<Summary>> {
+        get_or_create_family(&self.summaries, name, label_names, Summary::new)
+    }
+
+    /// Registers or retrieves a summary family whose series use the given
+    /// configuration. If the family already exists, its configuration is
+    /// kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a summary with the same name was registered with different
+    /// label names, or if the configuration is invalid, see
+    /// [`Summary::with_config`].
+    pub fn summary_family_with_config(
+        &self,
+        name: &str,
+        label_names: &[&str],
+        config: SummaryConfig,
+    ) -> Arc<MetricFamily<Summary>> {
+        get_or_create_family(&self.summaries, name, label_names, move |name, labels| {
+            Summary::with_config(name, labels, config.clone())
+        })
+    }
+
+    /// Registers or retrieves an HDR histogram family with the given label
+    /// names and the default configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an HDR histogram with the same name was registered with
+    /// different label names.
+    pub fn hdr_histogram_family(
+        &self,
+        name: &str,
+        label_names: &[&str],
+    ) -> Arc<MetricFamily<HdrHistogram>> {
+        get_or_create_family(&self.hdr_histograms, name, label_names, HdrHistogram::new)
+    }
+
+    /// Registers or retrieves an HDR histogram family whose series use the
+    /// given configuration. If the family already exists, its configuration
+    /// is kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an HDR histogram with the same name was registered with
+    /// different label names, or if the configuration is invalid, see
+    /// [`HdrHistogram::with_config`].
+    pub fn hdr_histogram_family_with_config(
+        &self,
+        name: &str,
+        label_names: &[&str],
+        config: HdrConfig,
+    ) -> Arc<MetricFamily<HdrHistogram>> {
+        get_or_create_family(
+            &self.hdr_histograms,
+            name,
+            label_names,
+            move |name, labels| HdrHistogram::with_config(name, labels, config.clone()),
+        )
+    }
+
+    /// Registers or retrieves a meter family with the given label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a meter with the same name was registered with different
+    /// label names.
+    pub fn meter_family(&self, name: &str, label_names: &[&str]) -> Arc<MetricFamily<Meter>> {
+        get_or_create_family(&self.meters, name, label_names, Meter::new)
+    }
+
+    /// Registers or retrieves a timer family with the given label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a timer with the same name was registered with different
+    /// label names.
+    pub fn timer_family(&self, name: &str, label_names: &[&str]) -> Arc<MetricFamily<Timer>> {
+        get_or_create_family(&self.timers, name, label_names, Timer::new)
+    }
+
+    /// Registers or retrieves a timer family whose series use the given
+    /// bucket upper bounds, in seconds. If the family already exists, its
+    /// buckets are kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a timer with the same name was registered with different
+    /// label names.
+    pub fn timer_family_with_buckets(
+        &self,
+        name: &str,
+        label_names: &[&str],
+        buckets: Vec<f64>,
+    ) -> Arc<MetricFamily<Timer>> {
+        get_or_create_family(&self.timers, name, label_names, move |name, labels| {
+            Timer::with_buckets(name, labels, buckets.clone())
+        })
+    }
+
+    /// Registers or retrieves an info family with the given label names.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an info metric with the same name was registered with
+    /// different label names.
+    pub fn info_family(&self, name: &str, label_names: &[&str]) -> Arc<MetricFamily<Info>> {
+        get_or_create_family(&self.infos, name, label_names, Info::new)
     }
 
-    /// Registers or retrieves a counter.
+    /// Registers or retrieves a state set family whose series have the given
+    /// states. If the family already exists, its states are kept.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a state set with the same name was registered with different
+    /// label names.
+    pub fn state_set_family(
+        &self,
+        name: &str,
+        label_names: &[&str],
+        states: &[&str],
+    ) -> Arc<MetricFamily<StateSet>> {
+        let states: Vec<String> = states.iter().map(|s| s.to_string()).collect();
+        get_or_create_family(&self.state_sets, name, label_names, move |name, labels| {
+            let states: Vec<&str> = states.iter().map(String::as_str).collect();
+            StateSet::new(name, labels, &states)
+        })
+    }
+
+    /// Registers or retrieves the counter series with the given labels.
     pub fn register_counter(&self, name: &str, labels: HashMap<String, String>) -> Arc<Counter> {
-        let mut counters = self.counters.write().unwrap();
-        counters
-            .entry(name.to_string())
-            .or_insert_with(|| Arc::new(Counter::new(name, labels)))
-            .clone()
+        self.counter_family(name, &label_names(&labels))
+            .with_labels(&labels)
     }
 
-    /// Registers or retrieves a gauge.
+    /// Registers or retrieves the gauge series with the given labels.
     pub fn register_gauge(&self, name: &str, labels: HashMap<String, String>) -> Arc<Gauge> {
-        let mut gauges = self.gauges.write().unwrap();
-        gauges
-            .entry(name.to_string())
-            .or_insert_with(|| Arc::new(Gauge::new(name, labels)))
-            .clone()
+        self.gauge_family(name, &label_names(&labels))
+            .with_labels(&labels)
     }
 
-    /// Registers or retrieves a histogram.
+    /// Registers or retrieves the histogram series with the given labels.
     pub fn register_histogram(
         &self,
         name: &str,
         labels: HashMap<String, String>,
     ) -> Arc<Histogram> {
-        let mut histograms = self.histograms.write().unwrap();
-        histograms
-            .entry(name.to_string())
-            .or_insert_with(|| Arc::new(Histogram::new(name, labels)))
-            .clone()
+        self.histogram_family(name, &label_names(&labels))
+            .with_labels(&labels)
+    }
+
+    /// Registers or retrieves the histogram series with the given labels,
+    /// using the given bucket upper bounds if the family is new.
+    pub fn register_histogram_with_buckets(
+        &self,
+        name: &str,
+        labels: HashMap<String, String>,
+        buckets: Vec<f64>,
+    ) -> Arc<Histogram> {
+        self.histogram_family_with_buckets(name, &label_names(&labels), buckets)
+            .with_labels(&labels)
+    }
+
+    /// Registers or retrieves the native histogram series with the given
+    /// labels.
+    pub fn register_native_histogram(
+        &self,
+        name: &str,
+        labels: HashMap<String, String>,
+    ) -> Arc<NativeHistogram> {
+        self.native_histogram_family(name, &label_names(&labels))
+            .with_labels(&labels)
+    }
+
+    /// Registers or retrieves the summary series with the given labels.
+    pub fn register_summary(&self, name: &str, labels: HashMap<String, String>) -> Arc<Summary> {
+        self.summary_family(name, &label_names(&labels))
+            .with_labels(&labels)
+    }
+
+    /// Registers or retrieves the HDR histogram series with the given labels.
+    pub fn register_hdr_histogram(
+        &self,
+        name: &str,
+        labels: HashMap<String, String>,
+    ) -> Arc<HdrHistogram> {
+        self.hdr_histogram_family(name, &label_names(&labels))
+            .with_labels(&labels)
     }
 
-    /// Registers or retrieves a meter.
+    /// Registers or retrieves the meter series with the given labels.
     pub fn register_meter(&self, name: &str, labels: HashMap<String, String>) -> Arc<Meter> {
-        let mut meters = self.meters.write().unwrap();
-        meters
-            .entry(name.to_string())
-            .or_insert_with(|| Arc::new(Meter::new(name, labels)))
-            .clone()
+        self.meter_family(name, &label_names(&labels))
+            .with_labels(&labels)
     }
 
-    /// Registers or retrieves a timer.
+    /// Registers or retrieves the timer series with the given labels.
     pub fn register_timer(&self, name: &str, labels: HashMap<String, String>) -> Arc<Timer> {
-        let mut timers = self.timers.write().unwrap();
-        timers
+        self.timer_family(name, &label_names(&labels))
+            .with_labels(&labels)
+    }
+
+    /// Registers or retrieves the counter series with the given labels, or
+    /// returns `None` if the counter has other label names.
+    pub(crate) fn try_register_counter(
+        &self,
+        name: &str,
+        labels: &HashMap<String, String>,
+    ) -> Option<Arc<Counter>> {
+        try_get_or_create_family(&self.counters, name, &label_names(labels), Counter::new)
+            .ok()
+            .map(|family| family.with_labels(labels))
+    }
+
+    /// Registers or retrieves a counter family, or returns `None` if the
+    /// counter has other label names.
+    pub(crate) fn try_counter_family(
+        &self,
+        name: &str,
+        label_names: &[&str],
+    ) -> Option<Arc<MetricFamily<Counter>>> {
+        try_get_or_create_family(&self.counters, name, label_names, Counter::new).ok()
+    }
+
+    /// Registers or retrieves the gauge series with the given labels, or
+    /// returns `None` if the gauge has other label names.
+    pub(crate) fn try_register_gauge(
+        &self,
+        name: &str,
+        labels: &HashMap<String, String>,
+    ) -> Option<Arc<Gauge>> {
+        try_get_or_create_family(&self.gauges, name, &label_names(labels), Gauge::new)
+            .ok()
+            .map(|family| family.with_labels(labels))
+    }
+
+    /// Registers or retrieves the histogram series with the given labels,
+    /// or returns `None` if the histogram has other label names.
+    pub(crate) fn try_register_histogram(
+        &self,
+        name: &str,
+        labels: &HashMap<String, String>,
+    ) -> Option<Arc<Histogram>> {
+        try_get_or_create_family(&self.histograms, name, &label_names(labels), Histogram::new)
+            .ok()
+            .map(|family| family.with_labels(labels))
+    }
+
+    /// Takes a point-in-time copy of every metric family, ordered by name.
+    pub fn snapshot(&self) -> Vec<FamilySnapshot> {
+        let mut families: Vec<FamilySnapshot> = Vec::new();
+        families.extend(self.counter_families().iter().map(|f| f.snapshot()));
+        families.extend(self.gauge_families().iter().map(|f| f.snapshot()));
+        families.extend(self.histogram_families().iter().map(|f| f.snapshot()));
+        families.extend(
+            self.native_histogram_families()
+                .iter()
+                .map(|f| f.snapshot()),
+        );
+        families.extend(self.summary_families().iter().map(|f| f.snapshot()));
+        families.extend(self.hdr_histogram_families().iter().map(|f| f.snapshot()));
+        families.extend(self.meter_families().iter().map(|f| f.snapshot()));
+        families.extend(self.timer_families().iter().map(|f| f.snapshot()));
+        families.extend(self.info_families().iter().map(|f| f.snapshot()));
+        families.extend(self.state_set_families().iter().map(|f| f.snapshot()));
+        families.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let descriptors = self.descriptors.read().unwrap();
+        for family in &mut families {
+            if let Some(descriptor) = descriptors.get(&family.name) {
+                family.help = descriptor.help.clone();
+                family.unit = descriptor.unit.clone();
+            }
+        }
+        families
+    }
+
+    /// Registers or retrieves the info series with the given labels.
+    pub fn register_info(&self, name: &str, labels: HashMap<String, String>) -> Arc<Info> {
+        self.info_family(name, &label_names(&labels))
+            .with_labels(&labels)
+    }
+
+    /// Registers or retrieves the state set series with the given labels,
+    /// with the given states if the family is new.
+    pub fn register_state_set(
+        &self,
+        name: &str,
+        labels: HashMap<String, String>,
+        states: &[&str],
+    ) -> Arc<StateSet> {
+        self.state_set_family(name, &label_names(&labels), states)
+            .with_labels(&labels)
+    }
+
+    /// Gets all counter families, ordered by name.
+    pub fn counter_families(&self) -> Vec<Arc<MetricFamily<Counter>>> {
+        sorted_families(&self.counters)
+    }
+
+    /// Gets all gauge families, ordered by name.
+    pub fn gauge_families(&self) -> Vec<Arc<MetricFamily<Gauge>>> {
+        sorted_families(&self.gauges)
+    }
+
+    /// Gets all histogram families, ordered by name.
+    pub fn histogram_families(&self) -> Vec<Arc<MetricFamily<Histogram>>> {
+        sorted_families(&self.histograms)
+    }
+
+    /// Gets all native histogram families, ordered by name.
+    pub fn native_histogram_families(&self) -> Vec<Arc<MetricFamily<NativeHistogram>>> {
+        sorted_families(&self.native_histograms)
+    }
+
+    /// Gets all summary families, ordered by name.
+    pub fn summary_families(&self) -> Vec<Arc<MetricFamily<Summary>>> {
+        sorted_families(&self.summaries)
+    }
+
+    /// Gets all HDR histogram families, ordered by name.
+    pub fn hdr_histogram_families(&self) -> Vec<Arc<MetricFamily<HdrHistogram>>> {
+        sorted_families(&self.hdr_histograms)
+    }
+
+    /// Gets all meter families, ordered by name.
+    pub fn meter_families(&self) -> Vec<Arc<MetricFamily<Meter>>> {
+        sorted_families(&self.meters)
+    }
+
+    /// Gets all timer families, ordered by name.
+    pub fn timer_families(&self) -> Vec<Arc<MetricFamily<Timer>>> {
+        sorted_families(&self.timers)
+    }
+
+    /// Gets all info families, ordered by name.
+    pub fn info_families(&self) -> Vec<Arc<MetricFamily<Info>>> {
+        sorted_families(&self.infos)
+    }
+
+    /// Gets all state set families, ordered by name.
+    pub fn state_set_families(&self) -> Vec<Arc<MetricFamily<StateSet>>> {
+        sorted_families(&self.state_sets)
+    }
+}
+
+fn get_or_create_family<M, F>(
+    families: &Families<M>,
+    name: &str,
+    label_names: &[&str],
+    new_child: F,
+) -> Arc<MetricFamily<M>>
+where
+    F: Fn(&str, HashMap<String, String>) -> M + Send + Sync + 'static,
+{
+    match try_get_or_create_family(families, name, label_names, new_child) {
+        Ok(family) => family,
+        Err(family) => panic!(
+            "metric `{}` is already registered with labels {:?}, got {:?}",
+            name,
+            family.label_names(),
+            label_names
+        ),
+    }
+}
+
+/// Gets or creates a family, returning the existing family as the error if
+/// it has other label names.
+fn try_get_or_create_family<M, F>(
+    families: &Families<M>,
+    name: &str,
+    label_names: &[&str],
+    new_child: F,
+) -> Result<Arc<MetricFamily<M>>, Arc<MetricFamily<M>>>
+where
+    F: Fn(&str, HashMap<String, String>) -> M + Send + Sync + 'static,
+{
+    let existing = families.read().unwrap().get(name).cloned();
+    let family = match existing {
+        Some(family) => family,
+        None => families
+            .write()
+            .unwrap()
             .entry(name.to_string())
-            .or_insert_with(|| Arc::new(Timer::new(name, labels)))
-            .clone()
+            .or_insert_with(|| Arc::new(MetricFamily::new(name, label_names, new_child)))
+            .clone(),
+    };
+    let requested: Vec<String> = label_names.iter().map(|l| l.to_string()).collect();
+    if family.has_label_names(&requested) {
+        Ok(family)
+    } else {
+        Err(family)
     }
+}
+
+fn sorted_families<M>(families: &Families<M>) -> Vec<Arc<MetricFamily<M>>> {
+    let families = families.read().unwrap();
+    let mut families: Vec<_> = families.values().cloned().collect();
+    families.sort_by(|a, b| a.name().cmp(b.name()));
+    families
+}
 
-    // Methods to collect and export metrics can be added here.
+fn label_names(labels: &HashMap<String, String>) -> Vec<&str> {
+    let mut names: Vec<&str> = labels.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    names
 }
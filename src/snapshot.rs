@@ -0,0 +1,246 @@
+// src/snapshot.rs
+
+use crate::metrics::histogram::HistogramSnapshot;
+use crate::metrics::meter::MeterSnapshot;
+use crate::metrics::native_histogram::NativeHistogramSnapshot;
+use crate::metrics::summary::SummarySnapshot;
+use crate::metrics::{
+    Counter, Exemplar, Gauge, HdrHistogram, Histogram, Info, Meter, Metric, MetricFamily,
+    NativeHistogram, StateSet, Summary, Timer, Unit,
+};
+use std::time::SystemTime;
+
+/// The kind of a metric family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+    NativeHistogram,
+    Summary,
+    Meter,
+    Timer,
+    Info,
+    StateSet,
+}
+
+impl MetricKind {
+    /// Gets the lowercase name of the kind, e.g. `"counter"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+            MetricKind::NativeHistogram => "native_histogram",
+            MetricKind::Summary => "summary",
+            MetricKind::Meter => "meter",
+            MetricKind::Timer => "timer",
+            MetricKind::Info => "info",
+            MetricKind::StateSet => "stateset",
+        }
+    }
+}
+
+/// A point-in-time copy of a metric family and all of its series.
+///
+/// Exporters render snapshots rather than walking the registry themselves.
+#[derive(Clone, Debug)]
+pub struct FamilySnapshot {
+    pub name: String,
+    pub help: String,
+    pub unit: Option<Unit>,
+    pub kind: MetricKind,
+    pub series: Vec<SeriesSnapshot>,
+}
+
+/// A point-in-time copy of a single series.
+#[derive(Clone, Debug)]
+pub struct SeriesSnapshot {
+    /// Label pairs, ordered by label name.
+    pub labels: Vec<(String, String)>,
+    pub value: MetricValue,
+    /// When the series was created, for metrics that track it.
+    pub created: Option<SystemTime>,
+    /// The latest exemplar of a counter.
+    pub exemplar: Option<Exemplar>,
+}
+
+/// The value of a series at snapshot time.
+#[derive(Clone, Debug)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(f64),
+    Histogram(HistogramSnapshot),
+    NativeHistogram(NativeHistogramSnapshot),
+    Summary(SummarySnapshot),
+    Meter(MeterSnapshot),
+    /// A timer's histogram, in seconds.
+    Timer(HistogramSnapshot),
+    /// An info metric, whose value is always 1.
+    Info,
+    /// Each state of a state set and whether it is enabled.
+    StateSet(Vec<(String, bool)>),
+}
+
+/// A metric that can be copied into a snapshot.
+pub trait Snapshot: Metric {
+    /// The kind of the metric.
+    const KIND: MetricKind;
+
+    /// Reads the current value of the metric.
+    fn value(&self) -> MetricValue;
+
+    /// Gets when the metric was created, if it tracks it.
+    fn created(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Gets the latest exemplar of the metric, if it keeps one.
+    fn exemplar(&self) -> Option<Exemplar> {
+        None
+    }
+}
+
+impl Snapshot for Counter {
+    const KIND: MetricKind = MetricKind::Counter;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Counter(self.get())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(Counter::created(self))
+    }
+
+    fn exemplar(&self) -> Option<Exemplar> {
+        self.get_exemplar()
+    }
+}
+
+impl Snapshot for Gauge {
+    const KIND: MetricKind = MetricKind::Gauge;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Gauge(self.get())
+    }
+}
+
+impl Snapshot for Histogram {
+    const KIND: MetricKind = MetricKind::Histogram;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Histogram(self.snapshot())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(Histogram::created(self))
+    }
+}
+
+impl Snapshot for NativeHistogram {
+    const KIND: MetricKind = MetricKind::NativeHistogram;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::NativeHistogram(self.snapshot())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(NativeHistogram::created(self))
+    }
+}
+
+impl Snapshot for Summary {
+    const KIND: MetricKind = MetricKind::Summary;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Summary(self.snapshot())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(Summary::created(self))
+    }
+}
+
+/// HDR histograms are exported as summaries of their latest interval.
+impl Snapshot for HdrHistogram {
+    const KIND: MetricKind = MetricKind::Summary;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Summary(self.snapshot())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(HdrHistogram::created(self))
+    }
+}
+
+impl Snapshot for Meter {
+    const KIND: MetricKind = MetricKind::Meter;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Meter(self.snapshot())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(Meter::created(self))
+    }
+}
+
+impl Snapshot for Timer {
+    const KIND: MetricKind = MetricKind::Timer;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Timer(self.snapshot())
+    }
+
+    fn created(&self) -> Option<SystemTime> {
+        Some(Timer::created(self))
+    }
+}
+
+impl Snapshot for Info {
+    const KIND: MetricKind = MetricKind::Info;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::Info
+    }
+}
+
+impl Snapshot for StateSet {
+    const KIND: MetricKind = MetricKind::StateSet;
+
+    fn value(&self) -> MetricValue {
+        MetricValue::StateSet(self.get_states())
+    }
+}
+
+impl<M: Snapshot> MetricFamily<M> {
+    /// Takes a point-in-time copy of the family and all of its series.
+    pub fn snapshot(&self) -> FamilySnapshot {
+        let series = self
+            .children()
+            .iter()
+            .map(|child| {
+                let mut labels: Vec<(String, String)> = child
+                    .labels()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                labels.sort();
+                SeriesSnapshot {
+                    labels,
+                    value: child.value(),
+                    created: child.created(),
+                    exemplar: child.exemplar(),
+                }
+            })
+            .collect();
+        FamilySnapshot {
+            name: self.name().to_string(),
+            help: String::new(),
+            unit: None,
+            kind: M::KIND,
+            series,
+        }
+    }
+}
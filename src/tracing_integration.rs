@@ -1,42 +1,600 @@
+use crate::metrics::{Counter, Histogram, MetricFamily, Unit};
 use crate::registry::Registry;
+use crate::utils::buckets::DEFAULT_BUCKETS;
 use std::collections::HashMap;
-use std::sync::Arc;
-use tracing::Subscriber;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Record};
+use tracing::{Event, Subscriber};
 use tracing_subscriber::layer::{Context, Layer};
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+const BUSY_SECONDS: &str = "tracing_span_busy_seconds";
+const DURATION_SECONDS: &str = "tracing_span_duration_seconds";
+const EVENTS_TOTAL: &str = "tracing_events_total";
+
+const MONOTONIC_COUNTER_PREFIX: &str = "monotonic_counter.";
+const COUNTER_PREFIX: &str = "counter.";
+const HISTOGRAM_PREFIX: &str = "histogram.";
+
+/// A `tracing` layer recording span and event metrics.
+///
+/// Besides counting `{span}_entered` and `{span}_exited`, it records, when
+/// a span closes, the time it was entered for into
+/// `tracing_span_busy_seconds` and the time from its creation into
+/// `tracing_span_duration_seconds`. Both histograms are labelled by `span`
+/// name and `target`. A span entered repeatedly, e.g. an instrumented
+/// future polled across `.await` points, is busy for the sum of the times
+/// it was entered. Spans whose counters were already registered with other
+/// label names are not counted.
+///
+/// Span metrics can be labelled by span fields, e.g. `http.route`, listed
+/// with [`MetricsLayer::with_span_fields`]. Only listed fields become labels,
+/// so high-cardinality fields such as request IDs never do; a listed field
+/// that a span does not record has an empty label value.
+///
+/// Events are counted into `tracing_events_total`, labelled by `level` and
+/// `target`, and event fields with these prefixes update metrics named
+/// after the rest of the field name:
+///
+/// - `monotonic_counter.` increments a counter by a non-negative integer,
+/// - `counter.` adds a value, which may be negative, to a gauge: like an
+///   OpenTelemetry `UpDownCounter`, it counts things that come and go,
+/// - `histogram.` records a value into a histogram.
+///
+/// The other fields of the event, except `message`, become the labels of
+/// these metrics. An update whose labels differ from those the metric was
+/// first recorded with is dropped.
+///
+/// ```
+/// # use metrix::registry::Registry;
+/// # use metrix::tracing_integration::MetricsLayer;
+/// # use std::sync::Arc;
+/// # use tracing_subscriber::prelude::*;
+/// let registry = Arc::new(Registry::new());
+/// let subscriber = tracing_subscriber::registry().with(MetricsLayer::new(registry.clone()));
+/// tracing::subscriber::with_default(subscriber, || {
+///     tracing::info!(monotonic_counter.orders_placed = 1, region = "eu");
+///     tracing::info!(histogram.order_value = 42.5, region = "eu");
+///     tracing::info!(counter.orders_open = 2, region = "eu");
+///     tracing::info!(counter.orders_open = -1, region = "eu");
+/// });
+///
+/// let open = registry.gauge_family("orders_open", &["region"]);
+/// assert_eq!(open.with_label_values(&["eu"]).get(), 1.0);
+/// ```
 pub struct MetricsLayer {
     registry: Arc<Registry>,
+    buckets: Vec<f64>,
+    span_fields: Vec<String>,
+    span_families: OnceLock<SpanFamilies>,
+    span_counters: RwLock<HashMap<&'static str, Option<SpanCounters>>>,
+    events: Arc<MetricFamily<Counter>>,
+}
+
+/// The span histograms, registered on first use so that their label names
+/// include the span fields.
+struct SpanFamilies {
+    busy: Arc<MetricFamily<Histogram>>,
+    duration: Arc<MetricFamily<Histogram>>,
+}
+
+/// The `{span}_entered` and `{span}_exited` counters of a span name.
+#[derive(Clone)]
+struct SpanCounters {
+    entered: Arc<MetricFamily<Counter>>,
+    exited: Arc<MetricFamily<Counter>>,
 }
 
 impl MetricsLayer {
+    /// Creates the layer with the default buckets.
     pub fn new(registry: Arc<Registry>) -> Self {
-        MetricsLayer { registry }
+        Self::with_buckets(registry, DEFAULT_BUCKETS.to_vec())
+    }
+
+    /// Creates the layer with the given bucket upper bounds of the span
+    /// histograms, in seconds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the metrics was already registered with other label
+    /// names; for the span metrics, when the first span closes.
+    pub fn with_buckets(registry: Arc<Registry>, buckets: Vec<f64>) -> Self {
+        registry.describe(
+            BUSY_SECONDS,
+            "Time spans were entered for.",
+            Some(Unit::Seconds),
+        );
+        registry.describe(
+            DURATION_SECONDS,
+            "Time from creation to close of spans.",
+            Some(Unit::Seconds),
+        );
+        registry.describe(EVENTS_TOTAL, "Total number of tracing events.", None);
+        MetricsLayer {
+            buckets,
+            span_fields: Vec::new(),
+            span_families: OnceLock::new(),
+            span_counters: RwLock::new(HashMap::new()),
+            events: registry.counter_family(EVENTS_TOTAL, &["level", "target"]),
+            registry,
+        }
+    }
+
+    /// Labels the span metrics by the given span fields.
+    pub fn with_span_fields(mut self, fields: &[&str]) -> Self {
+        self.span_fields = fields.iter().map(|field| field.to_string()).collect();
+        self
+    }
+
+    fn span_families(&self) -> &SpanFamilies {
+        self.span_families.get_or_init(|| {
+            let mut label_names = vec!["span", "target"];
+            label_names.extend(self.span_fields.iter().map(String::as_str));
+            SpanFamilies {
+                busy: self.registry.histogram_family_with_buckets(
+                    BUSY_SECONDS,
+                    &label_names,
+                    self.buckets.clone(),
+                ),
+                duration: self.registry.histogram_family_with_buckets(
+                    DURATION_SECONDS,
+                    &label_names,
+                    self.buckets.clone(),
+                ),
+            }
+        })
     }
+
+    /// Gets the counters of the spans with the given name, registered on
+    /// first use, or `None` if one of them has other label names.
+    fn span_counters(&self, span_name: &'static str) -> Option<SpanCounters> {
+        if let Some(counters) = self.span_counters.read().unwrap().get(span_name) {
+            return counters.clone();
+        }
+        let label_names: Vec<&str> = self.span_fields.iter().map(String::as_str).collect();
+        let family = |suffix| {
+            self.registry
+                .try_counter_family(&format!("{}{}", span_name, suffix), &label_names)
+        };
+        let counters = family("_entered")
+            .zip(family("_exited"))
+            .map(|(entered, exited)| SpanCounters { entered, exited });
+        self.span_counters
+            .write()
+            .unwrap()
+            .insert(span_name, counters.clone());
+        counters
+    }
+}
+
+/// Counts a span being entered or exited, labelled by its allow-listed
+/// fields.
+fn count<S>(span: &SpanRef<'_, S>, family: &MetricFamily<Counter>)
+where
+    S: for<'a> LookupSpan<'a>,
+{
+    let counter = match span.extensions().get::<SpanLabels>() {
+        Some(SpanLabels(labels)) => family.with_labels(labels),
+        None => family.with_label_values(&[]),
+    };
+    counter.increment();
+}
+
+/// The allow-listed fields of a span, kept in its extensions.
+struct SpanLabels(HashMap<String, String>);
+
+/// Records the allow-listed fields of a span.
+struct SpanFieldVisitor<'a> {
+    fields: &'a [String],
+    labels: &'a mut HashMap<String, String>,
+}
+
+impl SpanFieldVisitor<'_> {
+    fn record_label(&mut self, field: &Field, value: String) {
+        if self.fields.iter().any(|name| name == field.name()) {
+            self.labels.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl Visit for SpanFieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_label(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_label(field, format!("{:?}", value));
+    }
+}
+
+/// The timings of a span, kept in its extensions.
+struct Timings {
+    created: Instant,
+    busy: Duration,
+    entered: Option<Instant>,
+    /// How many times the span is currently entered, as it may be entered
+    /// again before being exited.
+    depth: usize,
 }
 
 impl<S> Layer<S> for MetricsLayer
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
 {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &tracing::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            extensions.insert(Timings {
+                created: Instant::now(),
+                busy: Duration::ZERO,
+                entered: None,
+                depth: 0,
+            });
+            if !self.span_fields.is_empty() {
+                let mut labels: HashMap<String, String> = self
+                    .span_fields
+                    .iter()
+                    .map(|field| (field.clone(), String::new()))
+                    .collect();
+                attrs.record(&mut SpanFieldVisitor {
+                    fields: &self.span_fields,
+                    labels: &mut labels,
+                });
+                extensions.insert(SpanLabels(labels));
+            }
+        }
+    }
+
+    fn on_record(&self, id: &tracing::Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(SpanLabels(labels)) = span.extensions_mut().get_mut::<SpanLabels>() {
+                values.record(&mut SpanFieldVisitor {
+                    fields: &self.span_fields,
+                    labels,
+                });
+            }
+        }
+    }
+
     fn on_enter(&self, id: &tracing::Id, ctx: Context<S>) {
         if let Some(span) = ctx.span(id) {
-            let name = span.name().to_string();
-            let labels = HashMap::new();
-            let counter = self
-                .registry
-                .register_counter(&format!("{}_entered", name), labels);
-            counter.increment();
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                if timings.depth == 0 {
+                    timings.entered = Some(Instant::now());
+                }
+                timings.depth += 1;
+            }
+
+            if let Some(counters) = self.span_counters(span.name()) {
+                count(&span, &counters.entered);
+            }
         }
     }
 
     fn on_exit(&self, id: &tracing::Id, ctx: Context<S>) {
         if let Some(span) = ctx.span(id) {
-            let name = span.name().to_string();
-            let labels = HashMap::new();
-            let counter = self
-                .registry
-                .register_counter(&format!("{}_exited", name), labels);
-            counter.increment();
+            if let Some(timings) = span.extensions_mut().get_mut::<Timings>() {
+                timings.depth = timings.depth.saturating_sub(1);
+                if timings.depth == 0 {
+                    if let Some(entered) = timings.entered.take() {
+                        timings.busy += entered.elapsed();
+                    }
+                }
+            }
+
+            if let Some(counters) = self.span_counters(span.name()) {
+                count(&span, &counters.exited);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        self.events
+            .with_label_values(&[&metadata.level().as_str().to_lowercase(), metadata.target()])
+            .increment();
+
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        for update in &visitor.updates {
+            update.apply(&self.registry, &visitor.labels);
+        }
+    }
+
+    fn on_close(&self, id: tracing::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let mut extensions = span.extensions_mut();
+            let span_labels = extensions.remove::<SpanLabels>();
+            if let Some(timings) = extensions.remove::<Timings>() {
+                let mut labels = vec![span.name(), span.metadata().target()];
+                if let Some(SpanLabels(span_labels)) = &span_labels {
+                    labels.extend(
+                        self.span_fields
+                            .iter()
+                            .map(|field| span_labels[field].as_str()),
+                    );
+                }
+                let families = self.span_families();
+                families
+                    .busy
+                    .with_label_values(&labels)
+                    .observe(timings.busy.as_secs_f64());
+                families
+                    .duration
+                    .with_label_values(&labels)
+                    .observe(timings.created.elapsed().as_secs_f64());
+            }
+        }
+    }
+}
+
+/// A metric update carried by an event field.
+enum MetricUpdate {
+    MonotonicCounter(String, u64),
+    Counter(String, f64),
+    Histogram(String, f64),
+}
+
+impl MetricUpdate {
+    fn apply(&self, registry: &Registry, labels: &HashMap<String, String>) {
+        match self {
+            MetricUpdate::MonotonicCounter(name, value) => {
+                if let Some(counter) = registry.try_register_counter(name, labels) {
+                    counter.increment_by(*value);
+                }
+            }
+            MetricUpdate::Counter(name, value) => {
+                if let Some(gauge) = registry.try_register_gauge(name, labels) {
+                    gauge.add(*value);
+                }
+            }
+            MetricUpdate::Histogram(name, value) => {
+                if let Some(histogram) = registry.try_register_histogram(name, labels) {
+                    histogram.observe(*value);
+                }
+            }
+        }
+    }
+}
+
+/// Splits the fields of an event into metric updates and labels.
+#[derive(Default)]
+struct EventVisitor {
+    updates: Vec<MetricUpdate>,
+    labels: HashMap<String, String>,
+}
+
+impl EventVisitor {
+    /// Records a numeric field, returning whether it was a metric field.
+    fn record_number(&mut self, field: &Field, value: f64) -> bool {
+        let name = field.name();
+        if let Some(metric) = name.strip_prefix(MONOTONIC_COUNTER_PREFIX) {
+            if value >= 0.0 && value.fract() == 0.0 {
+                self.updates.push(MetricUpdate::MonotonicCounter(
+                    metric.to_string(),
+                    value as u64,
+                ));
+            }
+        } else if let Some(metric) = name.strip_prefix(COUNTER_PREFIX) {
+            self.updates
+                .push(MetricUpdate::Counter(metric.to_string(), value));
+        } else if let Some(metric) = name.strip_prefix(HISTOGRAM_PREFIX) {
+            self.updates
+                .push(MetricUpdate::Histogram(metric.to_string(), value));
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn record_label(&mut self, field: &Field, value: String) {
+        let name = field.name();
+        let is_metric = [MONOTONIC_COUNTER_PREFIX, COUNTER_PREFIX, HISTOGRAM_PREFIX]
+            .iter()
+            .any(|prefix| name.starts_with(prefix));
+        if name != "message" && !is_metric {
+            self.labels.insert(name.to_string(), value);
         }
     }
 }
+
+impl Visit for EventVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if !self.record_number(field, value) {
+            self.record_label(field, value.to_string());
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if !self.record_number(field, value as f64) {
+            self.record_label(field, value.to_string());
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        // Counters take the value as is rather than rounded through f64.
+        match field.name().strip_prefix(MONOTONIC_COUNTER_PREFIX) {
+            Some(metric) => self
+                .updates
+                .push(MetricUpdate::MonotonicCounter(metric.to_string(), value)),
+            None => {
+                if !self.record_number(field, value as f64) {
+                    self.record_label(field, value.to_string());
+                }
+            }
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record_label(field, value.to_string());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_label(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_label(field, format!("{:?}", value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::histogram::HistogramSnapshot;
+    use crate::snapshot::MetricValue;
+    use tracing_subscriber::prelude::*;
+
+    /// Runs `f` with the layer as the default subscriber.
+    fn with_layer(layer: MetricsLayer, f: impl FnOnce()) {
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    /// Gets the only series of a histogram from the registry snapshot.
+    fn histogram(registry: &Registry, name: &str) -> HistogramSnapshot {
+        let family = registry
+            .snapshot()
+            .into_iter()
+            .find(|family| family.name == name)
+            .unwrap();
+        assert_eq!(family.series.len(), 1);
+        match &family.series[0].value {
+            MetricValue::Histogram(histogram) => histogram.clone(),
+            value => panic!("not a histogram: {:?}", value),
+        }
+    }
+
+    #[test]
+    fn updates_metrics_from_event_fields() {
+        let registry = Arc::new(Registry::new());
+        with_layer(MetricsLayer::new(registry.clone()), || {
+            tracing::info!(monotonic_counter.orders_placed = 2u64, region = "eu");
+            tracing::info!(monotonic_counter.orders_placed = 1i64, region = "eu");
+            // Negative and fractional increments are dropped.
+            tracing::info!(monotonic_counter.orders_placed = -1, region = "eu");
+            tracing::info!(monotonic_counter.orders_placed = 0.5, region = "eu");
+            tracing::info!(counter.orders_open = 3, region = "eu");
+            tracing::info!(counter.orders_open = -1.5, region = "eu");
+            tracing::info!(
+                histogram.order_value = 42.5,
+                region = "eu",
+                express = true,
+                "order placed"
+            );
+            // Other labels than on first use are dropped.
+            tracing::info!(monotonic_counter.orders_placed = 1, shop = "a");
+        });
+
+        let placed = registry.counter_family("orders_placed", &["region"]);
+        assert_eq!(placed.with_label_values(&["eu"]).get(), 3);
+        let open = registry.gauge_family("orders_open", &["region"]);
+        assert_eq!(open.with_label_values(&["eu"]).get(), 1.5);
+        // The message is not a label.
+        let value = registry
+            .histogram_family("order_value", &["express", "region"])
+            .with_label_values(&["true", "eu"]);
+        assert_eq!((value.get_count(), value.get_sum()), (1, 42.5));
+    }
+
+    #[test]
+    fn counts_events_by_level_and_target() {
+        let registry = Arc::new(Registry::new());
+        with_layer(MetricsLayer::new(registry.clone()), || {
+            tracing::info!("started");
+            tracing::info!(target: "orders", "placed");
+            tracing::warn!(target: "orders", "late");
+            tracing::warn!(target: "orders", "late");
+        });
+
+        let events = registry.counter_family(EVENTS_TOTAL, &["level", "target"]);
+        let count = |level, target| events.with_label_values(&[level, target]).get();
+        assert_eq!(count("info", module_path!()), 1);
+        assert_eq!(count("info", "orders"), 1);
+        assert_eq!(count("warn", "orders"), 2);
+        assert_eq!(events.children().len(), 3);
+    }
+
+    #[test]
+    fn records_busy_time_over_every_entry() {
+        let registry = Arc::new(Registry::new());
+        with_layer(MetricsLayer::new(registry.clone()), || {
+            let span = tracing::info_span!("work");
+            span.in_scope(|| std::thread::sleep(Duration::from_millis(10)));
+            std::thread::sleep(Duration::from_millis(30));
+            // Entering the span again while it is entered adds no busy
+            // time.
+            span.in_scope(|| span.in_scope(|| std::thread::sleep(Duration::from_millis(10))));
+        });
+
+        let entered = registry.register_counter("work_entered", HashMap::new());
+        let exited = registry.register_counter("work_exited", HashMap::new());
+        assert_eq!((entered.get(), exited.get()), (3, 3));
+
+        let busy = histogram(&registry, BUSY_SECONDS);
+        let duration = histogram(&registry, DURATION_SECONDS);
+        assert_eq!((busy.count, duration.count), (1, 1));
+        assert!(busy.sum >= 0.02, "{}", busy.sum);
+        // The span was not entered while sleeping between the entries.
+        assert!(
+            duration.sum - busy.sum >= 0.03,
+            "{} {}",
+            duration.sum,
+            busy.sum
+        );
+    }
+
+    #[test]
+    fn skips_counters_registered_with_other_labels() {
+        let registry = Arc::new(Registry::new());
+        registry.counter_family("work_entered", &["queue"]);
+        with_layer(MetricsLayer::new(registry.clone()), || {
+            tracing::info_span!("work").in_scope(|| {});
+        });
+
+        let entered = registry.counter_family("work_entered", &["queue"]);
+        assert!(entered.children().is_empty());
+        assert_eq!(histogram(&registry, BUSY_SECONDS).count, 1);
+    }
+
+    #[test]
+    fn labels_span_metrics_by_allow_listed_fields() {
+        let registry = Arc::new(Registry::new());
+        let layer = MetricsLayer::new(registry.clone()).with_span_fields(&["http.route", "tenant"]);
+        with_layer(layer, || {
+            let span = tracing::info_span!(
+                "request",
+                http.route = tracing::field::Empty,
+                request_id = "abc"
+            );
+            span.record("http.route", "/users/:id");
+            span.in_scope(|| {});
+        });
+
+        // The route was recorded after the span was created, the tenant
+        // never was, and the request ID is not listed.
+        let busy = registry
+            .snapshot()
+            .into_iter()
+            .find(|family| family.name == BUSY_SECONDS)
+            .unwrap();
+        let labels: Vec<(&str, &str)> = busy.series[0]
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("http.route", "/users/:id"),
+                ("span", "request"),
+                ("target", module_path!()),
+                ("tenant", ""),
+            ]
+        );
+        let entered = registry.counter_family("request_entered", &["http.route", "tenant"]);
+        assert_eq!(entered.with_label_values(&["/users/:id", ""]).get(), 1);
+    }
+}
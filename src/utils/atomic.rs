@@ -0,0 +1,48 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// An `f64` that can be updated atomically, stored as its bit pattern.
+pub struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    /// Creates a new atomic float.
+    pub fn new(value: f64) -> Self {
+        AtomicF64(AtomicU64::new(value.to_bits()))
+    }
+
+    /// Loads the current value.
+    pub fn load(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Stores a new value.
+    pub fn store(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Adds to the current value, returning the previous value.
+    pub fn fetch_add(&self, value: f64) -> f64 {
+        self.fetch_update(|current| current + value)
+    }
+
+    /// Stores the minimum of the current and given value, returning the
+    /// previous value.
+    pub fn fetch_min(&self, value: f64) -> f64 {
+        self.fetch_update(|current| current.min(value))
+    }
+
+    /// Stores the maximum of the current and given value, returning the
+    /// previous value.
+    pub fn fetch_max(&self, value: f64) -> f64 {
+        self.fetch_update(|current| current.max(value))
+    }
+
+    fn fetch_update(&self, f: impl Fn(f64) -> f64) -> f64 {
+        let previous = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(f(f64::from_bits(bits)).to_bits())
+            })
+            .unwrap_or_else(|bits| bits);
+        f64::from_bits(previous)
+    }
+}
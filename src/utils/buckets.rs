@@ -1,3 +1,10 @@
+/// The default histogram buckets, tailored to request latencies in seconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Creates `count` buckets, the first with upper bound `start` and each
+/// following one `factor` times the previous.
 pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Vec<f64> {
     let mut buckets = Vec::with_capacity(count);
     let mut current = start;
@@ -8,6 +15,8 @@ pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Vec<f64> {
     buckets
 }
 
+/// Creates `count` buckets, the first with upper bound `start` and each
+/// following one `width` wider than the previous.
 pub fn linear_buckets(start: f64, width: f64, count: usize) -> Vec<f64> {
     (0..count).map(|i| start + (i as f64) * width).collect()
 }